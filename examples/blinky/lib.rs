@@ -18,8 +18,8 @@ main!({
 
     loop {
         pin_out(13, Power::High);
-        wait_ns(1 * S_TO_NANO);
+        wait(Duration::from_secs(1));
         pin_out(13, Power::Low);
-        wait_ns(1 * S_TO_NANO);
+        wait(Duration::from_secs(1));
     }
 });
\ No newline at end of file
@@ -30,7 +30,7 @@ main!({
         debug_u32(count, b"iteration");
         debug_hex(0xff72, b"hex values");
         debug_str(b"debug ping!");
-        wait_ns(S_TO_NANO);
+        wait(Duration::from_secs(1));
 
         count += 1;
     }
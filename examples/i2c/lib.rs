@@ -25,7 +25,7 @@ teensycore::main!({
     wire.end_transmission();
 
     // Settle time for whole-page write. Per docs.
-    wait_ns(250 * MS_TO_NANO);
+    wait(Duration::from_millis(250));
 
     // Select the address we wish to read
     wire.begin_transmission(0x50, true);
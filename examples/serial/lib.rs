@@ -24,6 +24,6 @@ main!({
 
     loop {
         serial_write(SerioDevice::Default, b"ping!");
-        wait_ns(S_TO_NANO);
+        wait(Duration::from_secs(1));
     }
 });
\ No newline at end of file
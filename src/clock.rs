@@ -1,16 +1,38 @@
 //! A top level system which keeps track of time
 //! using the onboard clock peripheral.
+//!
+//! `nanos()` returns the uptime as a raw `uNano`. Newer code should
+//! prefer `Instant` and `Duration`, which keep nanoseconds in a u64.
+//! That is enough for 584 years of uptime, and avoids the 128-bit
+//! math the Cortex-M7 has to do in software.
+//!
+//! ```no_run
+//! use teensycore::clock::*;
+//!
+//! let start = Instant::now();
+//! let deadline = start + Duration::from_millis(250);
+//!
+//! while Instant::now() < deadline {}
+//! let took = start.elapsed().as_micros();
+//! ```
 
+use crate::phys::irq::CriticalSection;
 use crate::phys::periodic_timers::*;
 use crate::phys::*;
+use core::ops::{Add, AddAssign, Div, Mul, Sub, SubAssign};
 
 #[allow(non_camel_case_types)]
 pub type uNano = u128;
 
 /**
- * The default configured clock speed in HZ (132MHz)
+ * The core clock speed in HZ at boot (396MHz). Use
+ * `cpu_hz()` for the speed it is running at right now.
  */
 pub const F_CPU: u32 = 396000000;
+/**
+ * The IPG clock speed in HZ at boot (132MHz), which the
+ * periodic timers count. Use `pit_hz()` for the current speed.
+ */
 pub const CLOCK_CPU: u32 = 132000000;
 
 pub fn clock_init() {
@@ -48,28 +70,583 @@ pub fn clock_init() {
     // to a default timeout that takes like a minute.
     pit_restart(&PeriodicTimerSource::Timer1);
     pit_restart(&PeriodicTimerSource::Timer0);
+
+    // Start counting core clock cycles
+    dwt::dwt_enable();
 }
 
+#[cfg(feature = "testing")]
+#[thread_local]
+static mut SIMULATED_NANOS: uNano = 0;
+
+#[cfg(not(feature = "testing"))]
 #[no_mangle]
 /// This method returns the current uptime of the system
 /// in nanoseconds.
 pub fn nanos() -> uNano {
-    // The periodic timer clock is configured to be 132MHz which
-    // is 7.5757575 nanoseconds per tick.
-    //
-    // In order to achieve perfect timing, we need some division here.
-    // Because of how large the number is, we cannot use floats
-    // otherwise we'll lose precisino and the value will just be wrong.
-    //
-    // Through the embarassing process of trial and error, I determined
-    // that 14000 / 1848 = 7.575757575. But there's a catch. Doing
-    // large-number division after a mnultiplication operation is
-    // inherently unstable, because the value could overflow.
-    // This is why we are using a third-party division library
-    // to do software-level division instead of relying on assembly.
-    //
-    // The end result is a perfectly accurate clock, as verified through
-    // an external source (a separate arduino).
-    let uptime_ticks = pit_read_lifetime() as uNano;
-    return ((uptime_ticks * 14000) / 1848) as uNano;
+    // The periodic timers count the IPG clock, which is 132MHz
+    // at boot but changes along with the core clock. The time
+    // base keeps track of every speed it has run at, and does
+    // the conversion with 64-bit math.
+    return pit_time().nanos_at(pit_read_lifetime()) as uNano;
+}
+
+/// When testing on the host there is no periodic timer, so
+/// uptime is simulated. It only moves when a test moves it.
+#[cfg(feature = "testing")]
+pub fn nanos() -> uNano {
+    return unsafe { SIMULATED_NANOS };
+}
+
+/// Move the simulated clock forward.
+#[cfg(feature = "testing")]
+pub fn clock_advance(nanos: uNano) {
+    unsafe {
+        SIMULATED_NANOS += nanos;
+    }
+}
+
+/// Set the simulated clock to an exact uptime.
+#[cfg(feature = "testing")]
+pub fn clock_set(nanos: uNano) {
+    unsafe {
+        SIMULATED_NANOS = nanos;
+    }
+}
+
+/// A span of time, in nanoseconds. Arithmetic saturates
+/// instead of overflowing or wrapping around.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Duration(u64);
+
+impl Duration {
+    pub const ZERO: Duration = Duration(0);
+    pub const MAX: Duration = Duration(u64::MAX);
+
+    pub const fn from_nanos(nanos: u64) -> Self {
+        return Duration(nanos);
+    }
+
+    pub const fn from_micros(micros: u64) -> Self {
+        return Duration(micros.saturating_mul(1_000));
+    }
+
+    pub const fn from_millis(millis: u64) -> Self {
+        return Duration(millis.saturating_mul(1_000_000));
+    }
+
+    pub const fn from_secs(secs: u64) -> Self {
+        return Duration(secs.saturating_mul(1_000_000_000));
+    }
+
+    pub const fn as_nanos(&self) -> u64 {
+        return self.0;
+    }
+
+    pub const fn as_micros(&self) -> u64 {
+        return self.0 / 1_000;
+    }
+
+    pub const fn as_millis(&self) -> u64 {
+        return self.0 / 1_000_000;
+    }
+
+    pub const fn as_secs(&self) -> u64 {
+        return self.0 / 1_000_000_000;
+    }
+
+    pub const fn is_zero(&self) -> bool {
+        return self.0 == 0;
+    }
+
+    pub const fn checked_add(self, other: Duration) -> Option<Duration> {
+        return match self.0.checked_add(other.0) {
+            Some(nanos) => Some(Duration(nanos)),
+            None => None,
+        };
+    }
+
+    pub const fn checked_sub(self, other: Duration) -> Option<Duration> {
+        return match self.0.checked_sub(other.0) {
+            Some(nanos) => Some(Duration(nanos)),
+            None => None,
+        };
+    }
+
+    pub const fn saturating_add(self, other: Duration) -> Duration {
+        return Duration(self.0.saturating_add(other.0));
+    }
+
+    pub const fn saturating_sub(self, other: Duration) -> Duration {
+        return Duration(self.0.saturating_sub(other.0));
+    }
+}
+
+/// Raw nanoseconds, such as `MS_TO_NANO * 5`, convert
+/// straight into a `Duration`, so older code keeps working.
+impl From<uNano> for Duration {
+    fn from(nanos: uNano) -> Self {
+        if nanos > u64::MAX as uNano {
+            return Duration::MAX;
+        }
+
+        return Duration(nanos as u64);
+    }
+}
+
+impl From<Duration> for uNano {
+    fn from(duration: Duration) -> Self {
+        return duration.0 as uNano;
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, other: Duration) -> Duration {
+        return self.saturating_add(other);
+    }
+}
+
+impl AddAssign for Duration {
+    fn add_assign(&mut self, other: Duration) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, other: Duration) -> Duration {
+        return self.saturating_sub(other);
+    }
+}
+
+impl SubAssign for Duration {
+    fn sub_assign(&mut self, other: Duration) {
+        *self = *self - other;
+    }
+}
+
+impl Mul<u32> for Duration {
+    type Output = Duration;
+
+    fn mul(self, factor: u32) -> Duration {
+        return Duration(self.0.saturating_mul(factor as u64));
+    }
+}
+
+impl Div<u32> for Duration {
+    type Output = Duration;
+
+    fn div(self, divisor: u32) -> Duration {
+        return Duration(self.0 / divisor as u64);
+    }
+}
+
+/// A point in time, measured from when the system booted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The current uptime.
+    pub fn now() -> Self {
+        return Instant(uptime_nanos());
+    }
+
+    /// The instant `nanos` nanoseconds after boot.
+    pub const fn from_nanos(nanos: u64) -> Self {
+        return Instant(nanos);
+    }
+
+    /// Nanoseconds since boot.
+    pub const fn as_nanos(&self) -> u64 {
+        return self.0;
+    }
+
+    /// How much time has passed since this instant.
+    pub fn elapsed(&self) -> Duration {
+        return Instant::now().duration_since(*self);
+    }
+
+    /// How much time passed between `earlier` and this
+    /// instant, or zero if `earlier` is actually later.
+    pub const fn duration_since(&self, earlier: Instant) -> Duration {
+        return Duration(self.0.saturating_sub(earlier.0));
+    }
+
+    pub const fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        return match self.0.checked_add(duration.0) {
+            Some(nanos) => Some(Instant(nanos)),
+            None => None,
+        };
+    }
+
+    pub const fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        return match self.0.checked_sub(duration.0) {
+            Some(nanos) => Some(Instant(nanos)),
+            None => None,
+        };
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        return Instant(self.0.saturating_add(duration.0));
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        return Instant(self.0.saturating_sub(duration.0));
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        return self.duration_since(earlier);
+    }
+}
+
+/// The same as `nanos()`, but without going through a u128.
+#[cfg(not(feature = "testing"))]
+fn uptime_nanos() -> u64 {
+    return pit_time().nanos_at(pit_read_lifetime());
+}
+
+#[cfg(feature = "testing")]
+fn uptime_nanos() -> u64 {
+    return nanos() as u64;
+}
+
+/// Turns a count of clock ticks into nanoseconds, even
+/// when the clock doing the ticking changes speed.
+#[derive(Copy, Clone)]
+struct TimeBase {
+    hz: u32,
+    base_ticks: u64,
+    base_nanos: u64,
+}
+
+impl TimeBase {
+    const fn new(hz: u32) -> Self {
+        return TimeBase {
+            hz: hz,
+            base_ticks: 0,
+            base_nanos: 0,
+        };
+    }
+
+    fn nanos_at(&self, ticks: u64) -> u64 {
+        let elapsed = ticks - self.base_ticks;
+        let hz = self.hz as u64;
+        return self.base_nanos
+            + (elapsed / hz) * 1_000_000_000
+            + (elapsed % hz) * 1_000_000_000 / hz;
+    }
+
+    /// From `ticks` on, the clock runs at `hz`.
+    fn set_hz(&mut self, hz: u32, ticks: u64) {
+        self.base_nanos = self.nanos_at(ticks);
+        self.base_ticks = ticks;
+        self.hz = hz;
+    }
+}
+
+/// Extends the 32-bit DWT cycle counter to 64 bits.
+struct CycleClock {
+    cycles: u64,
+    last_raw: u32,
+    last_uptime: u64,
+    time: TimeBase,
+}
+
+impl CycleClock {
+    const fn new() -> Self {
+        return CycleClock {
+            cycles: 0,
+            last_raw: 0,
+            last_uptime: 0,
+            time: TimeBase::new(F_CPU),
+        };
+    }
+
+    /// Fold a new reading of the raw counter into the 64-bit
+    /// count. The raw counter only gives the low 32 bits of
+    /// what has passed since the last reading, so the uptime
+    /// is used to work out how many times it wrapped around.
+    fn extend(&mut self, raw: u32, uptime: u64) -> u64 {
+        let low = raw.wrapping_sub(self.last_raw) as u64;
+        let expected = nanos_to_cycles(uptime.saturating_sub(self.last_uptime), self.time.hz);
+        let wraps = match expected.checked_sub(low) {
+            None => 0,
+            Some(missing) => (missing + (1 << 31)) >> 32,
+        };
+
+        self.cycles += low + (wraps << 32);
+        self.last_raw = raw;
+        self.last_uptime = uptime;
+        return self.cycles;
+    }
+}
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut CYCLE_CLOCK: CycleClock = CycleClock::new();
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut PIT_TIME: TimeBase = TimeBase::new(CLOCK_CPU);
+
+fn cycle_clock() -> &'static mut CycleClock {
+    return unsafe { &mut *core::ptr::addr_of_mut!(CYCLE_CLOCK) };
+}
+
+fn pit_time() -> &'static mut TimeBase {
+    return unsafe { &mut *core::ptr::addr_of_mut!(PIT_TIME) };
+}
+
+/// How many cycles it takes to wait `nanos`, rounded up.
+fn nanos_to_cycles(nanos: u64, hz: u32) -> u64 {
+    let hz = hz as u64;
+    return (nanos / 1_000_000_000) * hz
+        + ((nanos % 1_000_000_000) * hz + 999_999_999) / 1_000_000_000;
+}
+
+/// The speed of the core clock, in HZ.
+pub fn cpu_hz() -> u32 {
+    return cycle_clock().time.hz;
+}
+
+/// The speed of the IPG clock which the periodic timers count, in HZ.
+pub fn pit_hz() -> u32 {
+    return pit_time().hz;
+}
+
+/// Tell the clock that the core now runs at a different speed.
+/// Whatever changes the core clock has to call this, so that
+/// `micros()` and `delay_ns()` stay right.
+pub fn clock_set_cpu_hz(hz: u32) {
+    let _cs = CriticalSection::new();
+    let clock = cycle_clock();
+    let now = clock.extend(raw_cycles(), uptime_nanos());
+    clock.time.set_hz(hz, now);
+}
+
+/// Tell the clock that the periodic timers now count at a
+/// different speed, so that `nanos()` carries on from where
+/// it was instead of jumping.
+pub fn clock_set_pit_hz(hz: u32) {
+    let _cs = CriticalSection::new();
+    let ticks = pit_ticks();
+    pit_time().set_hz(hz, ticks);
+}
+
+/// Tell the clock that the core is about to sleep, so that
+/// every wrap of the cycle counter so far is counted.
+pub fn clock_sleep() {
+    cycles();
+}
+
+/// Tell the clock that the core has just woken up. The cycle
+/// counter stops while the core sleeps, so `micros()` catches up
+/// with `nanos()` again. `skipped` is any time which passed with
+/// the periodic timers stopped as well, in the deepest sleep.
+pub fn clock_wake(skipped: u64) {
+    let _cs = CriticalSection::new();
+    skip_uptime(skipped);
+
+    // The uptime kept going while the cycle counter didn't,
+    // so it says nothing about how often the counter wrapped.
+    let clock = cycle_clock();
+    let uptime = uptime_nanos();
+    clock.last_uptime = uptime;
+    let now = clock.extend(raw_cycles(), uptime);
+    let nanos = crate::math::max(clock.time.nanos_at(now), uptime_nanos());
+    clock.time.base_ticks = now;
+    clock.time.base_nanos = nanos;
+}
+
+/// How many core clock cycles have passed since `clock_init()`.
+///
+/// The hardware counter is only 32 bits and wraps around about
+/// every 10 seconds at 396MHz. The periodic timers behind
+/// `nanos()` fill in the wraps, so this can be called as
+/// rarely as you like.
+pub fn cycles() -> u64 {
+    let _cs = CriticalSection::new();
+    return cycle_clock().extend(raw_cycles(), uptime_nanos());
+}
+
+/// Microseconds of uptime, according to the cycle counter.
+pub fn micros() -> u64 {
+    let _cs = CriticalSection::new();
+    let clock = cycle_clock();
+    let now = clock.extend(raw_cycles(), uptime_nanos());
+    return clock.time.nanos_at(now) / 1000;
+}
+
+/// Spin until `count` core clock cycles have passed.
+#[cfg(not(feature = "testing"))]
+#[inline(always)]
+pub fn delay_cycles(count: u32) {
+    dwt::dwt_enable();
+    let start = dwt::dwt_cycles();
+    while dwt::dwt_cycles().wrapping_sub(start) < count {}
+}
+
+#[cfg(feature = "testing")]
+pub fn delay_cycles(count: u32) {
+    let hz = cpu_hz() as u64;
+    clock_advance(((count as u64 * 1_000_000_000 + hz - 1) / hz) as uNano);
+}
+
+/// Spin for at least `nanos` nanoseconds. The wait is counted
+/// in core clock cycles, so it is exact to within a couple of
+/// cycles no matter the clock speed or optimization level.
+/// Interrupts which fire in the middle make it longer.
+pub fn delay_ns(nanos: u64) {
+    let mut remaining = nanos_to_cycles(nanos, cpu_hz());
+    while remaining > 0 {
+        let chunk = crate::math::min(remaining, u32::MAX as u64);
+        delay_cycles(chunk as u32);
+        remaining -= chunk;
+    }
+}
+
+#[cfg(not(feature = "testing"))]
+fn raw_cycles() -> u32 {
+    return dwt::dwt_cycles();
+}
+
+#[cfg(feature = "testing")]
+fn raw_cycles() -> u32 {
+    let hz = cycle_clock().time.hz as u64;
+    return (uptime_nanos() * hz / 1_000_000_000) as u32;
+}
+
+#[cfg(not(feature = "testing"))]
+fn skip_uptime(nanos: u64) {
+    pit_time().base_nanos += nanos;
+}
+
+#[cfg(feature = "testing")]
+fn skip_uptime(nanos: u64) {
+    clock_advance(nanos as uNano);
+}
+
+#[cfg(not(feature = "testing"))]
+fn pit_ticks() -> u64 {
+    return pit_read_lifetime();
+}
+
+/// The simulated clock doesn't tick, so nothing
+/// counted before the change needs carrying over.
+#[cfg(feature = "testing")]
+fn pit_ticks() -> u64 {
+    return pit_time().base_ticks;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MS_TO_NANO, S_TO_NANO};
+
+    #[test]
+    fn test_duration() {
+        let duration = Duration::from_millis(1500);
+        assert_eq!(duration.as_secs(), 1);
+        assert_eq!(duration.as_micros(), 1_500_000);
+        assert_eq!(duration, Duration::from(MS_TO_NANO * 1500));
+        assert_eq!(uNano::from(duration), MS_TO_NANO * 1500);
+
+        assert_eq!(duration - Duration::from_secs(2), Duration::ZERO);
+        assert_eq!(Duration::MAX + duration, Duration::MAX);
+        assert_eq!(Duration::MAX.checked_add(duration), None);
+        assert_eq!(
+            duration.checked_sub(Duration::from_secs(1)),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(duration * 2 / 3, Duration::from_secs(1));
+        assert_eq!(Duration::from(uNano::MAX), Duration::MAX);
+        assert_eq!(Duration::from_secs(u64::MAX), Duration::MAX);
+    }
+
+    #[test]
+    fn test_instant() {
+        clock_set(MS_TO_NANO * 10);
+        let start = Instant::now();
+        assert_eq!(start.as_nanos(), 10_000_000);
+
+        clock_advance(MS_TO_NANO * 5);
+        assert_eq!(start.elapsed(), Duration::from_millis(5));
+        assert_eq!(Instant::now() - start, Duration::from_millis(5));
+
+        // Going backwards saturates at zero
+        assert_eq!(start - Instant::now(), Duration::ZERO);
+        assert_eq!(start - Duration::from_secs(1), Instant::from_nanos(0));
+        assert_eq!(start.checked_sub(Duration::from_secs(1)), None);
+        assert_eq!(
+            start.checked_add(Duration::from_millis(5)),
+            Some(Instant::now())
+        );
+        assert!(start + Duration::from_millis(6) > Instant::now());
+    }
+
+    #[test]
+    fn test_cycle_clock() {
+        let mut clock = CycleClock::new();
+        assert_eq!(clock.extend(1000, 0), 1000);
+
+        // The raw counter wrapping around keeps counting up
+        assert_eq!(clock.extend(u32::MAX, 0), u32::MAX as u64);
+        assert_eq!(clock.extend(5, 0), u32::MAX as u64 + 6);
+
+        // The uptime says how many more times it wrapped
+        // around while nobody was looking
+        let uptime = (3 << 32) * 1_000_000_000 / F_CPU as u64;
+        assert_eq!(clock.extend(5, uptime), (4 << 32) + 5);
+
+        // 396 cycles is one microsecond at 396MHz
+        assert_eq!(clock.time.nanos_at(396_000), 1_000_000);
+
+        // Halving the speed doubles how long each cycle takes
+        clock.time.set_hz(198_000_000, 396_000);
+        assert_eq!(clock.time.nanos_at(396_000 + 198), 1_001_000);
+
+        // The periodic timers tick 132 times a microsecond at boot
+        assert_eq!(TimeBase::new(CLOCK_CPU).nanos_at(132), 1000);
+    }
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(nanos_to_cycles(1000, 396_000_000), 396);
+        assert_eq!(nanos_to_cycles(1, 396_000_000), 1);
+        assert_eq!(nanos_to_cycles(3_000_000_000, 600_000_000), 1_800_000_000);
+
+        clock_set(0);
+        let start = micros();
+        delay_ns(1_500_000);
+        assert_eq!(nanos(), 1_500_000);
+        assert_eq!(micros() - start, 1500);
+
+        // Time spent with every counter stopped is added on
+        clock_wake(2_000_000);
+        assert_eq!(nanos(), 3_500_000);
+        assert_eq!(micros() - start, 3500);
+
+        // Long enough for the raw counter to wrap several times
+        clock_advance(30 * S_TO_NANO);
+        assert_eq!(micros() - start, 30_003_500);
+    }
 }
@@ -1,11 +1,11 @@
-use crate::clock::uNano;
+use crate::clock::{uNano, Duration};
 use crate::mem::*;
 use crate::phys::pins::*;
 use crate::serio::*;
 use crate::system::vector::{Stack, Vector};
 use crate::usb_serial::*;
 use crate::*;
-use crate::{math::*, MS_TO_NANO};
+use crate::math::*;
 
 const UART_SERIAL: bool = true;
 const USB_SERIAL: bool = true;
@@ -28,6 +28,13 @@ pub enum Speed {
     Normal = (crate::MS_TO_NANO * 700 as uNano) as isize,
 }
 
+impl Speed {
+    /// How long the LED stays in each state.
+    pub fn duration(self) -> Duration {
+        return Duration::from_nanos(self as isize as u64);
+    }
+}
+
 pub static mut BLINK_CONFIG: BlinkConfig = BlinkConfig {
     speed: Speed::Normal,
     remaining_count: 0,
@@ -68,17 +75,19 @@ pub fn blink_accumulate() {
 pub fn blink_hardware(count: u8) {
     for _ in 0..count {
         blink_led_on();
-        wait_ns(MS_TO_NANO * 250);
+        wait(Duration::from_millis(250));
         blink_led_off();
-        wait_ns(MS_TO_NANO * 150);
+        wait(Duration::from_millis(150));
     }
 }
 
-pub fn blink_custom(on_time: uNano, off_time: uNano) {
+/// Flash LED 13 once, holding it on for `on_time` and then off
+/// for `off_time`. Takes a `Duration` or raw nanoseconds.
+pub fn blink_custom<On: Into<Duration>, Off: Into<Duration>>(on_time: On, off_time: Off) {
     blink_led_on();
-    wait_ns(on_time);
+    wait(on_time.into());
     blink_led_off();
-    wait_ns(off_time);
+    wait(off_time.into());
 }
 
 pub fn print_u32(val: u32) {
@@ -0,0 +1,690 @@
+//! A cooperative executor for `async` code.
+//!
+//! Gates are a great fit for simple, periodic work but anything
+//! with several steps that each wait on something different
+//! quickly turns into a long chain of `when` conditions. The
+//! executor lets the same flow be written top to bottom:
+//!
+//! ```no_run
+//! use teensycore::executor::*;
+//! use teensycore::serio::*;
+//! use teensycore::*;
+//!
+//! spawn(async {
+//!     loop {
+//!         let mut line = serial_read_async(SerioDevice::Default).await;
+//!         line.drop();
+//!         sleep_ns(5 * MS_TO_NANO).await;
+//!     }
+//! });
+//!
+//! loop {
+//!     // Gates and the executor can share the main loop
+//!     executor_poll();
+//! }
+//! ```
+//!
+//! Just like gates, nothing is preemptive. A task runs until
+//! it reaches an `.await` which isn't ready yet, and is only
+//! polled again once something wakes it up. Timers wake tasks
+//! from `executor_poll()`, and interrupt handlers wake them
+//! through an `IrqWaker`:
+//!
+//! ```no_run
+//! use teensycore::executor::*;
+//! use teensycore::phys::irq::*;
+//!
+//! static TIMER_FIRED: IrqWaker = IrqWaker::new();
+//!
+//! irq_attach(Irq::PeriodicTimer, handle_timer_irq);
+//! spawn(async {
+//!     TIMER_FIRED.wait().await;
+//! });
+//!
+//! fn handle_timer_irq() {
+//!     TIMER_FIRED.wake();
+//! }
+//! ```
+//!
+//! Under the `testing` feature the executor runs on the host
+//! against the simulated clock in `clock`.
+use crate::clock::*;
+use crate::mem::{Mempage, GLOBAL_ALLOC_SCOPE};
+use crate::phys::irq::{disable_interrupts, enable_interrupts};
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::mem::{align_of, size_of};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+/// The most tasks which can be spawned at once.
+pub const MAX_TASKS: usize = 16;
+
+/// How many tasks an `IrqWakers` keeps track of at once.
+pub const MAX_WAITERS: usize = 4;
+
+/// The future passed to `block_on` gets the slot after every task.
+const ROOT_TASK: usize = MAX_TASKS;
+
+/// Identifies a spawned task.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TaskId(usize);
+
+struct Task {
+    future: Option<*mut dyn Future<Output = ()>>,
+    wake_at: Option<uNano>,
+}
+
+impl Task {
+    const fn new() -> Self {
+        return Task {
+            future: None,
+            wake_at: None,
+        };
+    }
+}
+
+// One bit per task, set whenever it should be polled again.
+// This is the only state touched from interrupt handlers.
+#[cfg_attr(feature = "testing", thread_local)]
+static READY: AtomicU32 = AtomicU32::new(0);
+#[cfg_attr(feature = "testing", thread_local)]
+static mut TASKS: [Task; MAX_TASKS + 1] = [const { Task::new() }; MAX_TASKS + 1];
+#[cfg_attr(feature = "testing", thread_local)]
+static mut CURRENT_TASK: Option<usize> = None;
+
+static WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(waker_clone, waker_wake, waker_wake, waker_drop);
+
+/// Start running a future in the background. Returns None
+/// if every task slot is already taken.
+///
+/// The future lives on the heap until it completes, so it
+/// is not released by an enclosing `using!` block.
+pub fn spawn<F: Future<Output = ()> + 'static>(future: F) -> Option<TaskId> {
+    unsafe {
+        for index in 0..MAX_TASKS {
+            if TASKS[index].future.is_none() {
+                let ptr = Mempage::add_page_in::<F>(size_of::<F>(), align_of::<F>(), GLOBAL_ALLOC_SCOPE);
+                ptr.write(future);
+
+                TASKS[index] = Task {
+                    future: Some(ptr as *mut dyn Future<Output = ()>),
+                    wake_at: None,
+                };
+
+                wake_task(index);
+                return Some(TaskId(index));
+            }
+        }
+    }
+
+    return None;
+}
+
+/// Returns true if a task has not yet run to completion.
+pub fn is_running(task: TaskId) -> bool {
+    return unsafe { TASKS[task.0].future.is_some() };
+}
+
+/// Stop a task, dropping its future where it stands.
+pub fn cancel(task: TaskId) {
+    finish_task(task.0);
+}
+
+/// Returns how many tasks have not yet run to completion.
+pub fn task_count() -> usize {
+    let mut count = 0;
+    for index in 0..MAX_TASKS {
+        if unsafe { TASKS[index].future.is_some() } {
+            count += 1;
+        }
+    }
+    return count;
+}
+
+/// Poll every task which has been woken up since last time,
+/// including any whose timers have elapsed. This never blocks,
+/// so it can be called from the main loop alongside gates.
+///
+/// Returns how many tasks were polled.
+pub fn executor_poll() -> usize {
+    wake_timers();
+
+    // Claim every ready task except the root, which only block_on polls
+    let ready = READY.fetch_and(1 << ROOT_TASK, Ordering::AcqRel) & !(1 << ROOT_TASK);
+    let mut polled = 0;
+
+    for index in 0..MAX_TASKS {
+        if ready & (1 << index) == 0 {
+            continue;
+        }
+
+        let future = match unsafe { TASKS[index].future } {
+            None => {
+                continue;
+            }
+            Some(future) => future,
+        };
+
+        polled += 1;
+        let previous = enter_task(index);
+        let waker = task_waker(index);
+        let mut context = Context::from_waker(&waker);
+
+        // Spawned futures are never moved once they are on the heap
+        let result = unsafe { Pin::new_unchecked(&mut *future) }.poll(&mut context);
+        leave_task(previous);
+
+        match result {
+            Poll::Ready(()) => {
+                finish_task(index);
+            }
+            Poll::Pending => {}
+        }
+    }
+
+    return polled;
+}
+
+/// Run every spawned task, forever.
+pub fn executor_run() -> ! {
+    loop {
+        executor_poll();
+    }
+}
+
+/// Returns the earliest time a sleeping task wants to be
+/// woken up, if any task is sleeping at all.
+pub fn next_wake() -> Option<uNano> {
+    let mut earliest: Option<uNano> = None;
+    for index in 0..=MAX_TASKS {
+        match unsafe { TASKS[index].wake_at } {
+            None => {}
+            Some(time) => {
+                if earliest.is_none() || time < earliest.unwrap() {
+                    earliest = Some(time);
+                }
+            }
+        }
+    }
+    return earliest;
+}
+
+/// Returns true if no task is waiting to be polled.
+pub fn executor_idle() -> bool {
+    return READY.load(Ordering::Acquire) & !(1 << ROOT_TASK) == 0;
+}
+
+/// Run a future to completion, polling every spawned
+/// task while it waits.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = future;
+    let waker = task_waker(ROOT_TASK);
+    let mut context = Context::from_waker(&waker);
+    wake_task(ROOT_TASK);
+
+    loop {
+        wake_timers();
+
+        let root = 1 << ROOT_TASK;
+        if READY.fetch_and(!root, Ordering::AcqRel) & root != 0 {
+            let previous = enter_task(ROOT_TASK);
+
+            // The future is shadowed, so it can never be moved again
+            let result = unsafe { Pin::new_unchecked(&mut future) }.poll(&mut context);
+            leave_task(previous);
+
+            match result {
+                Poll::Ready(output) => {
+                    unsafe {
+                        TASKS[ROOT_TASK].wake_at = None;
+                    }
+                    return output;
+                }
+                Poll::Pending => {}
+            }
+        }
+
+        executor_poll();
+    }
+}
+
+/// A future which completes once `clock::nanos()` passes a deadline.
+pub struct Sleep {
+    deadline: uNano,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if nanos() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        match unsafe { CURRENT_TASK } {
+            Some(index) => {
+                // Keep the earliest deadline if a task
+                // is waiting on several at once.
+                unsafe {
+                    match TASKS[index].wake_at {
+                        Some(time) if time <= self.deadline => {}
+                        _ => {
+                            TASKS[index].wake_at = Some(self.deadline);
+                        }
+                    }
+                }
+            }
+            None => {
+                // Polled from somewhere else, so fall back on busy polling
+                cx.waker().wake_by_ref();
+            }
+        }
+
+        return Poll::Pending;
+    }
+}
+
+/// Wait for some amount of nanoseconds.
+pub fn sleep_ns(duration: uNano) -> Sleep {
+    return sleep_until(nanos() + duration);
+}
+
+/// Wait until `clock::nanos()` reaches a particular uptime.
+pub fn sleep_until(deadline: uNano) -> Sleep {
+    return Sleep { deadline: deadline };
+}
+
+/// A future which lets every other ready task run once.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        return Poll::Pending;
+    }
+}
+
+/// Give every other ready task a turn before continuing.
+pub fn yield_task() -> YieldNow {
+    return YieldNow { yielded: false };
+}
+
+/// A waker which can be triggered from an interrupt handler.
+///
+/// Declare one as a `static`, call `wake()` from the handler
+/// attached with `irq_attach` and `.await` on `wait()` from a
+/// task. A wake which happens while nobody is waiting is
+/// remembered, so it can never be missed.
+pub struct IrqWaker {
+    pending: AtomicBool,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+unsafe impl Sync for IrqWaker {}
+
+impl IrqWaker {
+    pub const fn new() -> Self {
+        return IrqWaker {
+            pending: AtomicBool::new(false),
+            waker: UnsafeCell::new(None),
+        };
+    }
+
+    /// Flag the event and wake whoever is waiting for it.
+    /// Safe to call from an interrupt handler.
+    pub fn wake(&self) {
+        self.pending.store(true, Ordering::Release);
+        match unsafe { &*self.waker.get() } {
+            None => {}
+            Some(waker) => {
+                waker.wake_by_ref();
+            }
+        }
+    }
+
+    /// Wake whichever task last registered, without
+    /// flagging the event itself.
+    pub fn notify(&self) {
+        match unsafe { &*self.waker.get() } {
+            None => {}
+            Some(waker) => {
+                waker.wake_by_ref();
+            }
+        }
+    }
+
+    /// Make sure the task behind `waker` is woken by the next
+    /// call to `wake()`. Only the most recent waker is kept.
+    pub fn register(&self, waker: &Waker) {
+        // The handler can't be allowed to see a half written waker
+        disable_interrupts();
+        unsafe {
+            let slot = &mut *self.waker.get();
+            match slot {
+                Some(current) if current.will_wake(waker) => {}
+                _ => {
+                    *slot = Some(waker.clone());
+                }
+            }
+        }
+        enable_interrupts();
+    }
+
+    /// Consume the event, returning true if it had happened.
+    pub fn take(&self) -> bool {
+        return self.pending.swap(false, Ordering::AcqRel);
+    }
+
+    /// Returns a future which completes the next time `wake()`
+    /// is called, or straight away if it already has been.
+    pub fn wait(&self) -> IrqWait<'_> {
+        return IrqWait { waker: self };
+    }
+}
+
+/// A future which waits on an `IrqWaker`.
+pub struct IrqWait<'a> {
+    waker: &'a IrqWaker,
+}
+
+impl<'a> Future for IrqWait<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.waker.take() {
+            return Poll::Ready(());
+        }
+
+        self.waker.register(cx.waker());
+
+        // The interrupt may have fired while registering
+        if self.waker.take() {
+            return Poll::Ready(());
+        }
+
+        return Poll::Pending;
+    }
+}
+
+/// Like `IrqWaker`, but for something several tasks can wait
+/// on at once, such as a semaphore.
+///
+/// `wake_all()` wakes every task which has registered, and each
+/// one takes its turn at whatever it was waiting for. Those which
+/// miss out register again. If more than `MAX_WAITERS` tasks wait
+/// at once, the extra ones are polled again on every pass of the
+/// executor until there is room, instead of missing their wakeup.
+pub struct IrqWakers {
+    wakers: UnsafeCell<[Option<Waker>; MAX_WAITERS]>,
+}
+
+unsafe impl Sync for IrqWakers {}
+
+impl IrqWakers {
+    pub const fn new() -> Self {
+        return IrqWakers {
+            wakers: UnsafeCell::new([const { None }; MAX_WAITERS]),
+        };
+    }
+
+    /// Make sure the task behind `waker` is woken by
+    /// the next call to `wake_all()`.
+    pub fn register(&self, waker: &Waker) {
+        disable_interrupts();
+        unsafe {
+            let slots = &mut *self.wakers.get();
+            let known = slots.iter().any(|slot| match slot {
+                Some(current) => current.will_wake(waker),
+                None => false,
+            });
+
+            if !known {
+                match slots.iter().position(|slot| slot.is_none()) {
+                    Some(index) => {
+                        slots[index] = Some(waker.clone());
+                    }
+                    None => {
+                        // No room, so check again straight away
+                        waker.wake_by_ref();
+                    }
+                }
+            }
+        }
+        enable_interrupts();
+    }
+
+    /// Wake every task which is waiting. Safe to
+    /// call from an interrupt handler.
+    pub fn wake_all(&self) {
+        disable_interrupts();
+        unsafe {
+            for slot in (*self.wakers.get()).iter_mut() {
+                match slot.take() {
+                    None => {}
+                    Some(waker) => waker.wake(),
+                }
+            }
+        }
+        enable_interrupts();
+    }
+}
+
+fn wake_task(index: usize) {
+    READY.fetch_or(1 << index, Ordering::AcqRel);
+}
+
+/// Wake every task whose sleep has elapsed.
+fn wake_timers() {
+    let now = nanos();
+    for index in 0..=MAX_TASKS {
+        unsafe {
+            match TASKS[index].wake_at {
+                Some(time) if time <= now => {
+                    TASKS[index].wake_at = None;
+                    wake_task(index);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn enter_task(index: usize) -> Option<usize> {
+    unsafe {
+        let previous = CURRENT_TASK;
+        CURRENT_TASK = Some(index);
+        return previous;
+    }
+}
+
+fn leave_task(previous: Option<usize>) {
+    unsafe {
+        CURRENT_TASK = previous;
+    }
+}
+
+/// Drop a task's future and hand its slot back.
+fn finish_task(index: usize) {
+    unsafe {
+        match TASKS[index].future {
+            None => {}
+            Some(future) => {
+                TASKS[index] = Task::new();
+                core::ptr::drop_in_place(future);
+                Mempage::free(future as *mut u8 as usize);
+            }
+        }
+    }
+}
+
+fn task_waker(index: usize) -> Waker {
+    return unsafe { Waker::from_raw(RawWaker::new(index as *const (), &WAKER_VTABLE)) };
+}
+
+// Task wakers are nothing more than the index of the task,
+// so there is nothing to allocate or release.
+unsafe fn waker_clone(data: *const ()) -> RawWaker {
+    return RawWaker::new(data, &WAKER_VTABLE);
+}
+
+unsafe fn waker_wake(data: *const ()) {
+    wake_task(data as usize);
+}
+
+unsafe fn waker_drop(_data: *const ()) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MS_TO_NANO;
+
+    static mut LOG: [u32; 8] = [0; 8];
+
+    #[test]
+    fn test_spawn_runs_to_completion() {
+        let task = spawn(async {}).unwrap();
+        assert!(is_running(task));
+        assert_eq!(task_count(), 1);
+
+        assert_eq!(executor_poll(), 1);
+        assert!(!is_running(task));
+        assert_eq!(task_count(), 0);
+        assert_eq!(crate::mem::ref_count(), 0);
+    }
+
+    #[test]
+    fn test_sleep() {
+        clock_set(0);
+        let task = spawn(async {
+            sleep_ns(5 * MS_TO_NANO).await;
+            sleep_ns(5 * MS_TO_NANO).await;
+        })
+        .unwrap();
+
+        executor_poll();
+        assert_eq!(next_wake(), Some(5 * MS_TO_NANO));
+
+        // Nothing is polled until the timer elapses
+        clock_advance(4 * MS_TO_NANO);
+        assert_eq!(executor_poll(), 0);
+
+        clock_advance(MS_TO_NANO);
+        assert_eq!(executor_poll(), 1);
+        assert_eq!(next_wake(), Some(10 * MS_TO_NANO));
+        assert!(is_running(task));
+
+        clock_set(10 * MS_TO_NANO);
+        executor_poll();
+        assert!(!is_running(task));
+        assert_eq!(next_wake(), None);
+    }
+
+    #[test]
+    fn test_irq_waker() {
+        static EVENT: IrqWaker = IrqWaker::new();
+        let task = spawn(async {
+            EVENT.wait().await;
+        })
+        .unwrap();
+
+        executor_poll();
+        assert!(executor_idle());
+        assert!(is_running(task));
+
+        // Pretend to be the interrupt handler
+        EVENT.wake();
+        assert!(!executor_idle());
+        executor_poll();
+        assert!(!is_running(task));
+
+        // A wake nobody waited on is remembered
+        EVENT.wake();
+        assert!(block_on(async { EVENT.wait().await; true }));
+    }
+
+    #[test]
+    fn test_irq_wakers() {
+        static EVENT: IrqWaker = IrqWaker::new();
+        static WAITERS: IrqWakers = IrqWakers::new();
+
+        // Every task waits on the same thing
+        let mut tasks = [None; MAX_WAITERS + 1];
+        for task in tasks.iter_mut() {
+            *task = spawn(core::future::poll_fn(|cx| {
+                if EVENT.take() {
+                    return Poll::Ready(());
+                }
+                WAITERS.register(cx.waker());
+                return Poll::Pending;
+            }));
+        }
+
+        // One too many, so the last keeps getting polled
+        assert_eq!(executor_poll(), MAX_WAITERS + 1);
+        assert_eq!(executor_poll(), 1);
+
+        // Only one of them gets the event, and the rest wait again
+        EVENT.wake();
+        WAITERS.wake_all();
+        assert_eq!(executor_poll(), MAX_WAITERS + 1);
+        let running = tasks.iter().filter(|task| is_running(task.unwrap())).count();
+        assert_eq!(running, MAX_WAITERS);
+
+        for task in tasks.iter() {
+            cancel(task.unwrap());
+        }
+    }
+
+    #[test]
+    fn test_yield_interleaves() {
+        async fn worker(id: u32) {
+            for step in 0..2 {
+                unsafe {
+                    let log = &mut *core::ptr::addr_of_mut!(LOG);
+                    let slot = log.iter().position(|entry| *entry == 0).unwrap();
+                    log[slot] = id * 10 + step;
+                }
+                yield_task().await;
+            }
+        }
+
+        // Both tasks are polled in the same pass, so they take turns
+        let value = block_on(async {
+            spawn(worker(1));
+            spawn(worker(2));
+            yield_task().await;
+            yield_task().await;
+            yield_task().await;
+            42
+        });
+
+        assert_eq!(value, 42);
+        let log = unsafe { LOG };
+        assert_eq!(log[..4], [10, 20, 11, 21]);
+    }
+
+    #[test]
+    fn test_cancel() {
+        clock_set(0);
+        let task = spawn(async {
+            sleep_ns(MS_TO_NANO).await;
+        })
+        .unwrap();
+
+        executor_poll();
+        cancel(task);
+        assert!(!is_running(task));
+        assert_eq!(crate::mem::ref_count(), 0);
+    }
+}
@@ -7,10 +7,26 @@ If the condition is not met, the gate will yield
 until a later point.
 */
 use crate::clock::*;
+use crate::mem::{write_num, Mempage, GLOBAL_ALLOC_SCOPE};
+use crate::system::map::BTreeMap;
 use crate::system::vector::*;
+use core::mem::{align_of, size_of};
 
 type CondFn = fn(&mut Gate) -> bool;
 type ExecFn = fn();
+type FailFn = fn(&mut Gate);
+
+/// Anything which a gate stage can wait on with `when_ready`.
+pub trait Signal {
+    fn is_ready(&self) -> bool;
+
+    /// Called when a stage is waiting on the signal. Signals
+    /// which are used up by waiting, like a semaphore, take
+    /// their share here.
+    fn try_acquire(&self) -> bool {
+        return self.is_ready();
+    }
+}
 
 #[derive(Copy, Clone)]
 pub struct Gate {
@@ -22,21 +38,90 @@ pub struct Gate {
     pub tail: usize,
     pub sealed: bool,
     pub compiled: bool,
+    pub stages: Vector::<StageInfo>,
+    pub entered_at: uNano,
+    pub jump: Option<usize>,
+}
+
+/// Everything about a stage besides its condition,
+/// function and timing.
+#[derive(Copy, Clone)]
+pub struct StageInfo {
+    pub stats: StageStats,
+    /// For `when_or_timeout` stages, how long the condition
+    /// gets before `on_timeout` runs. Zero waits forever.
+    pub timeout: uNano,
+    pub on_timeout: FailFn,
+    /// Set with `named()`, so the stage can be jumped to.
+    pub name: &'static [u8],
+    /// What a `when_ready` stage waits on.
+    pub signal: Option<&'static dyn Signal>,
+}
+
+impl StageInfo {
+    pub const fn new() -> Self {
+        return StageInfo {
+            stats: StageStats::new(),
+            timeout: 0,
+            on_timeout: fail_noop,
+            name: b"",
+            signal: None,
+        };
+    }
+}
+
+/// How a single stage of a gate has been behaving.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StageStats {
+    /// How many times the stage has run.
+    pub runs: u32,
+    /// The total time spent running the stage, including
+    /// the condition which let it run.
+    pub total_nanos: uNano,
+    /// The longest the stage has ever taken to run.
+    pub worst_nanos: uNano,
+    /// For `when_nano` stages, the furthest past its
+    /// target time the stage has ever run.
+    pub worst_late_nanos: uNano,
+    /// For `when_or_timeout` stages, how many times
+    /// the condition gave up waiting.
+    pub timeouts: u32,
+}
+
+impl StageStats {
+    pub const fn new() -> Self {
+        return StageStats {
+            runs: 0,
+            total_nanos: 0,
+            worst_nanos: 0,
+            worst_late_nanos: 0,
+            timeouts: 0,
+        };
+    }
+
+    /// The average time the stage takes to run.
+    pub fn average_nanos(&self) -> uNano {
+        if self.runs == 0 {
+            return 0;
+        }
+
+        return self.total_nanos / self.runs as uNano;
+    }
 }
 
 #[macro_export]
 macro_rules! gate_open {
     ( $( $x:expr ),* ) => {
         {
-            let id = code_hash();
+            let id = $crate::code_hash();
             let current_node = unsafe { GATES.get(&id) };
-            let result: &mut Gate;
+            let result: &mut $crate::gate::Gate;
             
             match current_node {
                 None => {
                     // Let's create a new gate.
-                    let new_gate = crate::mem::alloc::<Gate>();
-                    unsafe { *new_gate = Gate::new(); }
+                    let new_gate = $crate::mem::alloc::<$crate::gate::Gate>();
+                    unsafe { *new_gate = $crate::gate::Gate::new(); }
 
                     // This new gate is what we'l return
                     result = unsafe { &mut (*new_gate) };
@@ -45,7 +130,7 @@ macro_rules! gate_open {
                     unsafe { GATES.insert(id, new_gate as u32) };
                 },
                 Some(gate) => {
-                    result = unsafe { ((*gate) as *mut Gate).as_mut().unwrap() };
+                    result = unsafe { ((*gate) as *mut $crate::gate::Gate).as_mut().unwrap() };
                 }
             }
 
@@ -54,6 +139,41 @@ macro_rules! gate_open {
     };
 }
 
+/// Open a gate which owns some state and runs closures,
+/// identified by an explicit ID rather than where it is
+/// called from. The state expression is only evaluated
+/// the first time the gate is opened.
+///
+/// ```no_run
+/// use teensycore::prelude::*;
+/// # static mut GATES: BTreeMap<u32, u32> = BTreeMap { root: None };
+///
+/// gate_state!(0x1ED, 0u32)
+///     .when(|count| *count < 10, |count| *count += 1)
+///     .when_nano(Duration::from_millis(500), |_| blink_led_on())
+///     .compile();
+/// ```
+#[macro_export]
+macro_rules! gate_state {
+    ($id: expr, $state: expr) => {{
+        let id: u32 = $id;
+        let current_node = unsafe { GATES.get(&id) };
+        let result: &mut $crate::gate::StateGate<_>;
+
+        match current_node {
+            None => {
+                let new_gate = $crate::gate::StateGate::allocate($state);
+                result = unsafe { &mut (*new_gate) };
+                unsafe { GATES.insert(id, new_gate as u32) };
+            }
+            Some(gate) => {
+                result = unsafe { ((*gate) as *mut $crate::gate::StateGate<_>).as_mut().unwrap() };
+            }
+        }
+
+        result
+    }};
+}
 
 impl Gate {
     pub fn new() -> Gate {
@@ -66,6 +186,9 @@ impl Gate {
             tail: 0usize,
             sealed: false,
             compiled: false,
+            stages: Vector::new(),
+            entered_at: 0,
+            jump: None,
         };
     }
 
@@ -74,25 +197,154 @@ impl Gate {
             return self;
         }
 
-        self.target_times.push(0);
-        self.durations.push(0);
-        self.conditions.push(cond);
-        self.functions.push(then);
-        self.tail += 1;
+        self.stage(cond, then, 0);
         return self;
     }
 
-    pub fn when_nano(&mut self, duration_nanos: uNano, then: ExecFn) -> &mut Self {
+    /// Run `then` once `duration` has passed since the previous
+    /// stage. Takes a `Duration` or raw nanoseconds.
+    pub fn when_nano<D: Into<Duration>>(&mut self, duration: D, then: ExecFn) -> &mut Self {
         if self.compiled {
             return self;
         }
 
+        self.stage(when_cond, then, uNano::from(duration.into()));
+        return self;
+    }
+
+    /// Run `then` once `cond` returns true. If `cond` is still
+    /// false `timeout` after the stage began, `on_timeout`
+    /// runs instead. It can `goto()` a named stage or `restart()`
+    /// the gate, and if it does neither the gate moves on to the
+    /// next stage without running `then`.
+    ///
+    /// ```no_run
+    /// use teensycore::gate::*;
+    /// use teensycore::prelude::*;
+    /// # static mut GATES: BTreeMap<u32, u32> = BTreeMap { root: None };
+    /// # fn sensor_ready(_: &mut Gate) -> bool { true }
+    /// # fn read_sensor() {}
+    /// # fn power_up() {}
+    ///
+    /// gate_open!()
+    ///     .when(|_| true, power_up)
+    ///     .named(b"power")
+    ///     .when_or_timeout(sensor_ready, Duration::from_millis(50), read_sensor, |gate| {
+    ///         gate.goto(b"power");
+    ///     })
+    ///     .compile();
+    /// ```
+    pub fn when_or_timeout<D: Into<Duration>>(&mut self, cond: CondFn, timeout: D, then: ExecFn, on_timeout: FailFn) -> &mut Self {
+        if self.compiled {
+            return self;
+        }
+
+        self.stage(cond, then, 0);
+        let mut info = self.stages.get(self.tail - 1).unwrap();
+        info.timeout = uNano::from(timeout.into());
+        info.on_timeout = on_timeout;
+        self.stages.put(self.tail - 1, info);
+        return self;
+    }
+
+    /// Run `then` once a signal, such as a channel
+    /// having something in it, is ready.
+    pub fn when_ready(&mut self, signal: &'static dyn Signal, then: ExecFn) -> &mut Self {
+        if self.compiled {
+            return self;
+        }
+
+        self.stage(signal_cond, then, 0);
+        let mut info = self.stages.get(self.tail - 1).unwrap();
+        info.signal = Some(signal);
+        self.stages.put(self.tail - 1, info);
+        return self;
+    }
+
+    /// Give the most recently added stage a name,
+    /// so that it can be jumped to with `goto()`.
+    pub fn named(&mut self, name: &'static [u8]) -> &mut Self {
+        if self.compiled || self.tail == 0 {
+            return self;
+        }
+
+        let mut info = self.stages.get(self.tail - 1).unwrap();
+        info.name = name;
+        self.stages.put(self.tail - 1, info);
+        return self;
+    }
+
+    fn stage(&mut self, cond: CondFn, then: ExecFn, duration_nanos: uNano) {
         self.target_times.push(0);
         self.durations.push(duration_nanos);
-        self.conditions.push(when_cond);
+        self.conditions.push(cond);
         self.functions.push(then);
+        self.stages.push(StageInfo::new());
         self.tail += 1;
-        return self;
+    }
+
+    /// Continue from the stage with this name, instead of
+    /// wherever the gate would have gone next. Returns false
+    /// if there is no such stage.
+    ///
+    /// The jump happens once the current condition or timeout
+    /// handler returns. The stage starts over, so `when_nano`
+    /// delays and timeouts count from the jump.
+    pub fn goto(&mut self, name: &[u8]) -> bool {
+        for index in 0..self.tail {
+            if self.stages.get(index).unwrap().name == name {
+                self.jump = Some(index);
+                return true;
+            }
+        }
+
+        return false;
+    }
+
+    /// Continue from the first stage. This also brings
+    /// a sealed gate which has finished back to life for
+    /// one more run.
+    pub fn restart(&mut self) {
+        self.jump = Some(0);
+    }
+
+    /// Returns how a particular stage has been behaving.
+    pub fn stage_stats(&self, index: usize) -> Option<StageStats> {
+        return self.stages.get(index).map(|info| info.stats);
+    }
+
+    /// The uptime from which the gate next has something to do,
+    /// or None if it has nothing left to do at all.
+    ///
+    /// Only `when_nano` stages know when they will be ready. Any
+    /// other stage has to be polled to find out, so it counts as
+    /// ready right away.
+    pub fn next_wake(&self) -> Option<uNano> {
+        if !self.compiled || self.tail == 0 {
+            return None;
+        } else if self.jump.is_some() {
+            return Some(self.entered_at);
+        } else if self.current_index == self.tail {
+            // Only a sealed gate which has finished stops here
+            return None;
+        }
+
+        let index = self.current_index;
+        if self.durations.get(index).unwrap() == 0 {
+            return Some(self.entered_at);
+        }
+
+        // `when_nano` stages run once the clock is past the target
+        return Some(self.target_times.get(index).unwrap() + 1);
+    }
+
+    /// Start counting every stage from zero again.
+    pub fn clear_stats(&mut self) {
+        for index in 0..self.stages.size() {
+            let mut info = self.stages.get(index).unwrap();
+            info.stats = StageStats::new();
+            self.stages.put(index, info);
+        }
     }
 
     /// If called, this gate will only ever execute one time.
@@ -125,17 +377,24 @@ impl Gate {
             self.process();
         } else {
             self.compiled = true;
+            self.entered_at = nanos();
         }
 
         return *self;
     }
 
+    /// Throw away every stage, so the gate is built again
+    /// on the next call to `compile()`. Any pending jump is
+    /// dropped, and a sealed gate stays sealed.
     pub fn reset(&mut self) {
         self.current_index = 0;
+        self.tail = 0;
         self.conditions.clear();
         self.functions.clear();
         self.target_times.clear();
         self.durations.clear();
+        self.stages.clear();
+        self.jump = None;
         self.compiled = false;
     }
 
@@ -143,6 +402,7 @@ impl Gate {
     // gate condition and, if true, execute
     // the underlying block.
     pub fn process(&mut self) {
+        self.take_jump();
 
         // Check if it is valid to process
         if self.current_index == self.tail && self.sealed {
@@ -152,22 +412,691 @@ impl Gate {
         let cond = self.conditions.get(self.current_index).unwrap();
         let then = self.functions.get(self.current_index).unwrap();
 
+        // Read the clock before the condition, because the
+        // condition of a state gate does the actual work.
+        let start = nanos();
         if cond(self) {
             then();
-            self.current_index += 1;
+            self.record(start);
+            self.advance();
+        } else if self.timed_out(start) {
+            let mut info = self.stages.get(self.current_index).unwrap();
+            info.stats.timeouts = info.stats.timeouts.wrapping_add(1);
+            self.stages.put(self.current_index, info);
+
+            (info.on_timeout)(self);
 
-            if self.current_index == self.tail && self.sealed {
+            // The handler may have reset the gate entirely
+            if !self.compiled {
                 return;
-            } else if self.current_index == self.tail {
-                self.current_index = 0;
-            } 
+            } else if self.jump.is_none() {
+                self.advance();
+            }
+        }
+
+        self.take_jump();
+    }
+}
+
+impl Gate {
+    fn advance(&mut self) {
+        self.current_index += 1;
+
+        if self.current_index == self.tail && self.sealed {
+            return;
+        } else if self.current_index == self.tail {
+            self.current_index = 0;
+        }
+
+        self.enter(self.current_index);
+    }
 
-            self.target_times.put(self.current_index, nanos() + self.durations.get(self.current_index).unwrap());
+    fn enter(&mut self, index: usize) {
+        self.current_index = index;
+        self.entered_at = nanos();
+        self.target_times.put(index, self.entered_at + self.durations.get(index).unwrap());
+    }
+
+    fn take_jump(&mut self) {
+        match self.jump.take() {
+            None => {}
+            Some(index) => {
+                if index < self.tail {
+                    self.enter(index);
+                }
+            }
+        }
+    }
+
+    fn timed_out(&self, now: uNano) -> bool {
+        let timeout = self.stages.get(self.current_index).unwrap().timeout;
+        return timeout > 0 && now > self.entered_at + timeout;
+    }
+
+    fn record(&mut self, start: uNano) {
+        let index = self.current_index;
+        let elapsed = nanos() - start;
+        let mut info = self.stages.get(index).unwrap();
+        let stats = &mut info.stats;
+
+        stats.runs = stats.runs.wrapping_add(1);
+        stats.total_nanos += elapsed;
+        stats.worst_nanos = crate::math::max(stats.worst_nanos, elapsed);
+
+        // Only timed stages have a target worth comparing against
+        let target = self.target_times.get(index).unwrap();
+        if self.durations.get(index).unwrap() > 0 && start > target {
+            stats.worst_late_nanos = crate::math::max(stats.worst_late_nanos, start - target);
         }
 
+        self.stages.put(index, info);
+    }
+}
+
+/// Print one line for every stage of a gate.
+pub fn gate_report<F: FnMut(&[u8])>(id: u32, gate: &Gate, mut write: F) {
+    for index in 0..gate.stages.size() {
+        let stats = gate.stages.get(index).unwrap().stats;
+        write(b"gate 0x");
+        write_num(&mut write, id as u64, 16);
+        write(b" stage=");
+        write_num(&mut write, index as u64, 10);
+        write(b" runs=");
+        write_num(&mut write, stats.runs as u64, 10);
+        write(b" avg_ns=");
+        write_num(&mut write, stats.average_nanos() as u64, 10);
+        write(b" worst_ns=");
+        write_num(&mut write, stats.worst_nanos as u64, 10);
+        write(b" late_ns=");
+        write_num(&mut write, stats.worst_late_nanos as u64, 10);
+        write(b" timeouts=");
+        write_num(&mut write, stats.timeouts as u64, 10);
+        write(b"\n");
     }
 }
 
+/// Walk every gate in the registry and print a table of how
+/// each stage has been behaving over serial and usb serial.
+/// This is the first place to look when the main loop is slow.
+///
+/// ```no_run
+/// use teensycore::gate::*;
+/// use teensycore::prelude::*;
+/// # static mut GATES: BTreeMap<u32, u32> = BTreeMap { root: None };
+///
+/// gate_dump(unsafe { &*core::ptr::addr_of!(GATES) });
+/// ```
+pub fn gate_dump(gates: &BTreeMap<u32, u32>) {
+    gates.for_each(|id, gate| {
+        let gate = unsafe { &*(*gate as *const Gate) };
+        gate_report(*id, gate, |bytes| crate::debug::print(bytes));
+    });
+}
+
+/// The soonest any gate in the registry next has something to
+/// do, or None if none of them do. An idle loop can sleep until
+/// then, as long as interrupts which make a gate ready wake it.
+pub fn gate_next_wake(gates: &BTreeMap<u32, u32>) -> Option<uNano> {
+    let mut result: Option<uNano> = None;
+    gates.for_each(|_id, gate| {
+        let gate = unsafe { &*(*gate as *const Gate) };
+        result = match (result, gate.next_wake()) {
+            (None, wake) => wake,
+            (Some(soonest), None) => Some(soonest),
+            (Some(soonest), Some(wake)) => Some(crate::math::min(soonest, wake)),
+        };
+    });
+
+    return result;
+}
+
+/// How long until any gate in the registry next has something
+/// to do. Zero if one is ready now, and None if none ever will be.
+///
+/// ```no_run
+/// use teensycore::gate::*;
+/// use teensycore::prelude::*;
+/// # static mut GATES: BTreeMap<u32, u32> = BTreeMap { root: None };
+///
+/// match gate_time_until_next(unsafe { &*core::ptr::addr_of!(GATES) }) {
+///     None => {}
+///     Some(wait) => debug_u64(wait.as_micros(), b"us until the next gate"),
+/// }
+/// ```
+pub fn gate_time_until_next(gates: &BTreeMap<u32, u32>) -> Option<Duration> {
+    let wake = gate_next_wake(gates)?;
+    return Some(Duration::from_nanos(wake.saturating_sub(nanos()) as u64));
+}
+
+fn fail_noop(_gate: &mut Gate) {}
+
+fn signal_cond(gate: &mut Gate) -> bool {
+    return match gate.stages.get(gate.current_index).unwrap().signal {
+        None => true,
+        Some(signal) => signal.try_acquire(),
+    };
+}
+
 fn when_cond(gate: &mut Gate) -> bool {
     return nanos() > gate.target_times.get(gate.current_index).unwrap();
-}
\ No newline at end of file
+}
+
+type StateCondFn<S> = *mut dyn FnMut(&mut S) -> bool;
+type StateExecFn<S> = *mut dyn FnMut(&mut S);
+
+/// A gate which owns a value of state, and whose stages are
+/// closures over that state instead of bare functions. This
+/// makes it possible for a driver to run its own gated state
+/// machine without reaching for `static mut` globals.
+///
+/// The underlying `Gate` comes first, so a `StateGate` can sit
+/// in the `GATES` map alongside every other gate.
+#[repr(C)]
+pub struct StateGate<S> {
+    pub gate: Gate,
+    pub state: S,
+    conditions: Vector<StateCondFn<S>>,
+    functions: Vector<StateExecFn<S>>,
+}
+
+impl<S: 'static> StateGate<S> {
+    pub fn new(state: S) -> Self {
+        return StateGate {
+            gate: Gate::new(),
+            state: state,
+            conditions: Vector::new(),
+            functions: Vector::new(),
+        };
+    }
+
+    /// Create a new gate on the heap, ready to be
+    /// inserted into the `GATES` map.
+    pub fn allocate(state: S) -> *mut Self {
+        let ptr = crate::mem::alloc::<Self>();
+        unsafe { ptr.write(StateGate::new(state)) };
+        return ptr;
+    }
+
+    /// Run `then` once `cond` returns true.
+    pub fn when<C, F>(&mut self, cond: C, then: F) -> &mut Self
+    where
+        C: FnMut(&mut S) -> bool + 'static,
+        F: FnMut(&mut S) + 'static,
+    {
+        if self.gate.compiled {
+            return self;
+        }
+
+        self.push(cond, then);
+        self.gate.stage(state_cond::<S>, state_noop, 0);
+        return self;
+    }
+
+    /// Run `then` once `duration` has passed since the
+    /// previous stage. Takes a `Duration` or raw nanoseconds.
+    pub fn when_nano<D, F>(&mut self, duration: D, then: F) -> &mut Self
+    where
+        D: Into<Duration>,
+        F: FnMut(&mut S) + 'static,
+    {
+        if self.gate.compiled {
+            return self;
+        }
+
+        self.push(|_: &mut S| true, then);
+        self.gate.stage(state_timed_cond::<S>, state_noop, uNano::from(duration.into()));
+        return self;
+    }
+
+    /// If called, this gate will only ever execute one time.
+    pub fn once<F: FnOnce(&mut S)>(&mut self, func: F) -> &mut Self {
+        if self.gate.compiled {
+            return self;
+        }
+
+        func(&mut self.state);
+        return self;
+    }
+
+    /// If a gate is sealed, it will only execute to completion once.
+    /// After that, it will remain idle forever.
+    pub fn sealed(&mut self) -> &mut Self {
+        self.gate.sealed();
+        return self;
+    }
+
+    /// Finish building the gate on the first call, and process
+    /// it on every call after that. Returns the state so the
+    /// caller can see how things are progressing.
+    pub fn compile(&mut self) -> &mut S {
+        self.gate.compile();
+        return &mut self.state;
+    }
+
+    /// Release every stage so the gate can be built again.
+    /// The state is left as it is.
+    pub fn reset(&mut self) {
+        self.release_stages();
+    }
+
+    fn push<C, F>(&mut self, cond: C, then: F)
+    where
+        C: FnMut(&mut S) -> bool + 'static,
+        F: FnMut(&mut S) + 'static,
+    {
+        self.conditions.push(box_closure(cond) as StateCondFn<S>);
+        self.functions.push(box_closure(then) as StateExecFn<S>);
+    }
+
+    /// Run the current stage's closures, returning true if the
+    /// condition passed.
+    fn run_stage(&mut self) -> bool {
+        let index = self.gate.current_index;
+        let cond = self.conditions.get(index).unwrap();
+        let then = self.functions.get(index).unwrap();
+
+        unsafe {
+            if (*cond)(&mut self.state) {
+                (*then)(&mut self.state);
+                return true;
+            }
+        }
+
+        return false;
+    }
+}
+
+impl<S> StateGate<S> {
+    /// Drop a gate made with `allocate()`, along with its
+    /// state, and give its memory back to the heap.
+    pub fn deallocate(ptr: *mut Self) {
+        unsafe {
+            core::ptr::drop_in_place(ptr);
+        }
+        crate::mem::free(ptr);
+    }
+
+    fn release_stages(&mut self) {
+        for cond in self.conditions.into_iter() {
+            drop_closure(cond);
+        }
+
+        for then in self.functions.into_iter() {
+            drop_closure(then);
+        }
+
+        self.conditions.free();
+        self.functions.free();
+        self.gate.reset();
+    }
+}
+
+/// The closures live on the heap, so they have to be
+/// dropped and released along with the gate.
+impl<S> Drop for StateGate<S> {
+    fn drop(&mut self) {
+        self.release_stages();
+    }
+}
+
+/// Move a closure onto the heap, where it stays until the
+/// gate which owns it is reset or dropped.
+fn box_closure<T>(closure: T) -> *mut T {
+    let ptr = Mempage::add_page_in::<T>(size_of::<T>(), align_of::<T>(), GLOBAL_ALLOC_SCOPE);
+    unsafe { ptr.write(closure) };
+    return ptr;
+}
+
+fn drop_closure<T: ?Sized>(closure: *mut T) {
+    unsafe {
+        core::ptr::drop_in_place(closure);
+    }
+    Mempage::free(closure as *mut u8 as usize);
+}
+
+/// Recover the `StateGate` which a `Gate` sits at the front of.
+fn state_gate<S>(gate: &mut Gate) -> &mut StateGate<S> {
+    return unsafe { &mut *(gate as *mut Gate as *mut StateGate<S>) };
+}
+
+// The stages of a state gate's inner `Gate` all point at these.
+// The condition runs the closures itself, since the function
+// which a `Gate` runs afterwards has no way to reach the state.
+fn state_cond<S: 'static>(gate: &mut Gate) -> bool {
+    return state_gate::<S>(gate).run_stage();
+}
+
+fn state_timed_cond<S: 'static>(gate: &mut Gate) -> bool {
+    return when_cond(gate) && state_gate::<S>(gate).run_stage();
+}
+
+fn state_noop() {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MS_TO_NANO;
+
+    struct Blinker {
+        toggles: u32,
+        on: bool,
+    }
+
+    #[test]
+    fn test_state_gate() {
+        let mut gate = StateGate::new(Blinker { toggles: 0, on: false });
+        let limit = 3;
+
+        for _ in 0..10 {
+            gate.when(move |blinker| blinker.toggles < limit, |blinker| {
+                blinker.on = !blinker.on;
+                blinker.toggles += 1;
+            })
+            .compile();
+        }
+
+        // The first compile only builds the gate
+        assert_eq!(gate.state.toggles, 3);
+        assert_eq!(gate.state.on, true);
+    }
+
+    /// Counts how many times it has been dropped.
+    struct Tally(*mut u32);
+
+    impl Tally {
+        fn live(&self) -> bool {
+            return unsafe { *self.0 } == 0;
+        }
+    }
+
+    impl Drop for Tally {
+        fn drop(&mut self) {
+            unsafe { *self.0 += 1 };
+        }
+    }
+
+    #[test]
+    fn test_state_gate_drop() {
+        let mut drops = 0u32;
+        let before = crate::mem::ref_count();
+        let tally = Tally(&mut drops);
+
+        {
+            let mut gate = StateGate::new(0u32);
+            gate.when(move |_| tally.live(), |count| *count += 1).compile();
+            assert!(crate::mem::ref_count() > before);
+        }
+
+        // The closure and everything it captured went with the gate
+        assert_eq!(drops, 1);
+        assert_eq!(crate::mem::ref_count(), before);
+
+        let gate = StateGate::allocate(Tally(&mut drops));
+        unsafe { (*gate).when_nano(MS_TO_NANO, |_| {}).compile() };
+        StateGate::deallocate(gate);
+        assert_eq!(drops, 2);
+        assert_eq!(crate::mem::ref_count(), before);
+    }
+
+    #[test]
+    fn test_state_gate_timing() {
+        clock_set(0);
+        let mut gate = StateGate::new(0u32);
+        let run = |gate: &mut StateGate<u32>| {
+            gate.when(|_| true, |count| *count += 1)
+                .when_nano(MS_TO_NANO * 5, |count| *count += 10)
+                .sealed()
+                .compile();
+        };
+
+        run(&mut gate);
+        run(&mut gate);
+        assert_eq!(gate.state, 1);
+
+        clock_advance(MS_TO_NANO * 4);
+        run(&mut gate);
+        assert_eq!(gate.state, 1);
+
+        clock_advance(MS_TO_NANO * 2);
+        run(&mut gate);
+        assert_eq!(gate.state, 11);
+
+        // Sealed, so it never runs again
+        clock_advance(MS_TO_NANO * 10);
+        run(&mut gate);
+        run(&mut gate);
+        assert_eq!(gate.state, 11);
+    }
+
+    fn nop() {}
+
+    fn slow() {
+        clock_advance(MS_TO_NANO);
+    }
+
+    #[test]
+    fn test_stage_stats() {
+        clock_set(0);
+        let mut gate = Gate::new();
+        let run = |gate: &mut Gate| {
+            gate.when(|_| true, slow).when_nano(Duration::from_millis(5), nop).compile();
+        };
+
+        run(&mut gate);
+        run(&mut gate);
+        run(&mut gate);
+        assert_eq!(gate.stage_stats(0).unwrap().runs, 1);
+        assert_eq!(gate.stage_stats(0).unwrap().worst_nanos, MS_TO_NANO);
+        assert_eq!(gate.stage_stats(1).unwrap().runs, 0);
+
+        // The timed stage was due 6ms after the first one ran
+        clock_advance(MS_TO_NANO * 7);
+        run(&mut gate);
+        let stats = gate.stage_stats(1).unwrap();
+        assert_eq!(stats.runs, 1);
+        assert_eq!(stats.worst_late_nanos, MS_TO_NANO * 2);
+        assert_eq!(gate.stage_stats(0).unwrap().worst_late_nanos, 0);
+
+        run(&mut gate);
+        run(&mut gate);
+        assert_eq!(gate.stage_stats(0).unwrap().runs, 2);
+        assert_eq!(gate.stage_stats(0).unwrap().average_nanos(), MS_TO_NANO);
+
+        gate.clear_stats();
+        assert_eq!(gate.stage_stats(0), Some(StageStats::new()));
+    }
+
+    #[test]
+    fn test_gate_report() {
+        let mut gate = Gate::new();
+        gate.when(|_| true, nop).compile();
+        gate.compile();
+
+        let mut output = crate::system::vector::Vector::<u8>::new();
+        gate_report(0x1ED, &gate, |bytes| {
+            for byte in bytes {
+                output.push(*byte);
+            }
+        });
+
+        let expected = b"gate 0x1ED stage=0 runs=1 avg_ns=0 worst_ns=0 late_ns=0 timeouts=0\n";
+        assert_eq!(output.size(), expected.len());
+        for index in 0..expected.len() {
+            assert_eq!(output.get(index), Some(expected[index]));
+        }
+    }
+
+    #[cfg_attr(feature = "testing", thread_local)]
+    static mut TICKS: u32 = 0;
+
+    fn tick() {
+        unsafe { TICKS += 1 };
+    }
+
+    fn ticks() -> u32 {
+        return unsafe { TICKS };
+    }
+
+    fn never(_gate: &mut Gate) -> bool {
+        return false;
+    }
+
+    fn go_to_start(gate: &mut Gate) {
+        assert!(gate.goto(b"start"));
+    }
+
+    #[test]
+    fn test_timeout_moves_on() {
+        clock_set(0);
+        let mut gate = Gate::new();
+        let run = |gate: &mut Gate| {
+            gate.when_or_timeout(never, MS_TO_NANO * 5, tick, fail_noop)
+                .when(|_| true, tick)
+                .compile();
+        };
+
+        run(&mut gate);
+        clock_advance(MS_TO_NANO * 4);
+        run(&mut gate);
+        assert_eq!(gate.current_index, 0);
+
+        // Timing out skips `then` and carries on
+        clock_advance(MS_TO_NANO * 2);
+        run(&mut gate);
+        assert_eq!(gate.current_index, 1);
+        assert_eq!(gate.stage_stats(0).unwrap().timeouts, 1);
+        run(&mut gate);
+        assert_eq!(ticks(), 1);
+
+        // The timeout counts from when the stage began again
+        run(&mut gate);
+        assert_eq!(gate.current_index, 0);
+    }
+
+    #[test]
+    fn test_timeout_goto() {
+        clock_set(0);
+        let mut gate = Gate::new();
+        let run = |gate: &mut Gate| {
+            gate.when(|_| true, tick)
+                .named(b"start")
+                .when(|_| true, nop)
+                .when_or_timeout(never, MS_TO_NANO, nop, go_to_start)
+                .when(|_| true, nop)
+                .sealed()
+                .compile();
+        };
+
+        run(&mut gate);
+        run(&mut gate);
+        run(&mut gate);
+        run(&mut gate);
+        assert_eq!(gate.current_index, 2);
+
+        clock_advance(MS_TO_NANO * 2);
+        run(&mut gate);
+        assert_eq!(gate.current_index, 0);
+        run(&mut gate);
+        assert_eq!(ticks(), 2);
+        assert_eq!(gate.goto(b"missing"), false);
+    }
+
+    #[test]
+    fn test_restart_sealed() {
+        let mut gate = Gate::new();
+        let run = |gate: &mut Gate| {
+            gate.when(|_| true, tick).sealed().compile();
+        };
+
+        run(&mut gate);
+        run(&mut gate);
+        run(&mut gate);
+        assert_eq!(ticks(), 1);
+
+        // A finished sealed gate runs once more after a restart
+        gate.restart();
+        run(&mut gate);
+        run(&mut gate);
+        assert_eq!(ticks(), 2);
+    }
+
+    #[test]
+    fn test_reset_rebuilds() {
+        let mut gate = Gate::new();
+        gate.when(|_| true, tick).named(b"first").when(|_| true, nop).compile();
+        gate.goto(b"first");
+        gate.reset();
+        assert_eq!(gate.tail, 0);
+        assert_eq!(gate.jump, None);
+
+        gate.when(|_| true, tick).compile();
+        assert_eq!(gate.tail, 1);
+        gate.compile();
+        gate.compile();
+        assert_eq!(ticks(), 2);
+    }
+
+    struct Flag(core::sync::atomic::AtomicBool);
+
+    impl Signal for Flag {
+        fn is_ready(&self) -> bool {
+            return self.0.load(core::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_when_ready() {
+        static FLAG: Flag = Flag(core::sync::atomic::AtomicBool::new(false));
+        let mut gate = Gate::new();
+        let run = |gate: &mut Gate| {
+            gate.when_ready(&FLAG, tick).compile();
+        };
+
+        run(&mut gate);
+        run(&mut gate);
+        assert_eq!(ticks(), 0);
+
+        FLAG.0.store(true, core::sync::atomic::Ordering::Relaxed);
+        run(&mut gate);
+        assert_eq!(ticks(), 1);
+    }
+
+    #[test]
+    fn test_next_wake() {
+        clock_set(0);
+        let mut gate = Gate::new();
+        let run = |gate: &mut Gate| {
+            gate.when(|_| true, nop).when_nano(MS_TO_NANO * 5, nop).sealed().compile();
+        };
+
+        assert_eq!(gate.next_wake(), None);
+
+        // A polled condition could be ready at any time
+        run(&mut gate);
+        assert_eq!(gate.next_wake(), Some(0));
+
+        clock_set(MS_TO_NANO);
+        run(&mut gate);
+        assert_eq!(gate.next_wake(), Some(MS_TO_NANO * 6 + 1));
+
+        // Finished and sealed, so there is nothing left to wait for
+        clock_set(MS_TO_NANO * 7);
+        run(&mut gate);
+        assert_eq!(gate.next_wake(), None);
+
+        // A pending jump is ready right away
+        gate.restart();
+        assert!(gate.next_wake().unwrap() <= nanos());
+    }
+
+    #[test]
+    fn test_state_gate_reset() {
+        let mut gate = StateGate::new(0u32);
+        gate.when(|_| true, |count| *count += 1).compile();
+        assert!(crate::mem::ref_count() > 0);
+
+        gate.reset();
+        assert_eq!(crate::mem::ref_count(), 0);
+        assert_eq!(gate.gate.compiled, false);
+    }
+}
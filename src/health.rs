@@ -0,0 +1,336 @@
+//! Task health checks.
+//!
+//! Feeding a watchdog from the main loop only proves that the main
+//! loop is running. A gate can be stuck on a stage forever, or a task
+//! can stop being polled, and the watchdog will never know.
+//!
+//! Instead, every gate or task which matters registers with this
+//! module and checks in whenever it makes progress. The watchdog is
+//! only fed while every participant has checked in recently enough,
+//! so a single stuck participant resets the device.
+//!
+//! ```no_run
+//! use teensycore::gate::*;
+//! use teensycore::health::*;
+//! use teensycore::phys::watchdog::*;
+//! use teensycore::prelude::*;
+//! # static mut GATES: BTreeMap<u32, u32> = BTreeMap { root: None };
+//!
+//! const MOTOR: u32 = 1;
+//!
+//! health_register(MOTOR, 200);
+//! health_start(Watchdog::Wdog1, 2000).unwrap();
+//!
+//! gate_open!()
+//!     .when_nano(Duration::from_millis(50), motor_step)
+//!     .compile();
+//!
+//! fn motor_step() {
+//!     health_check_in(MOTOR);
+//!     // ...
+//! }
+//! ```
+//!
+//! `health_start` services the checks from a software timer, so
+//! `timers_init()` needs to have been called. Anything which wants
+//! to drive the watchdog itself can call `health_feed()` instead.
+use crate::clock::*;
+use crate::phys::irq::CriticalSection;
+use crate::phys::watchdog::*;
+use crate::timers;
+use crate::MS_TO_NANO;
+
+/// The most gates or tasks which can take part at once.
+pub const MAX_PARTICIPANTS: usize = 16;
+
+#[derive(Copy, Clone)]
+struct Participant {
+    id: u32,
+    max_interval: uNano,
+    last_check_in: uNano,
+}
+
+impl Participant {
+    const fn new() -> Self {
+        return Participant {
+            id: 0,
+            max_interval: 0,
+            last_check_in: 0,
+        };
+    }
+
+    fn is_stale(&self, now: uNano) -> bool {
+        return now.saturating_sub(self.last_check_in) > self.max_interval;
+    }
+}
+
+/// Keeps track of when each participant last checked in.
+pub struct HealthMonitor {
+    participants: [Participant; MAX_PARTICIPANTS],
+    count: usize,
+}
+
+impl HealthMonitor {
+    pub const fn new() -> Self {
+        return HealthMonitor {
+            participants: [Participant::new(); MAX_PARTICIPANTS],
+            count: 0,
+        };
+    }
+
+    fn find(&self, id: u32) -> Option<usize> {
+        for index in 0..self.count {
+            if self.participants[index].id == id {
+                return Some(index);
+            }
+        }
+
+        return None;
+    }
+
+    /// Start watching `id`, which has to check in at least every
+    /// `max_interval` nanoseconds from `now` on. Registering an id
+    /// again changes its interval. Returns false if there is no room.
+    pub fn register(&mut self, id: u32, max_interval: uNano, now: uNano) -> bool {
+        let index = match self.find(id) {
+            Some(index) => index,
+            None => {
+                if self.count == MAX_PARTICIPANTS {
+                    return false;
+                }
+
+                self.count += 1;
+                self.count - 1
+            }
+        };
+
+        self.participants[index] = Participant {
+            id: id,
+            max_interval: max_interval,
+            last_check_in: now,
+        };
+
+        return true;
+    }
+
+    /// Stop watching `id`. Returns false if it was not registered.
+    pub fn unregister(&mut self, id: u32) -> bool {
+        match self.find(id) {
+            None => {
+                return false;
+            }
+            Some(index) => {
+                self.participants[index] = self.participants[self.count - 1];
+                self.count -= 1;
+                return true;
+            }
+        }
+    }
+
+    /// Record that `id` made progress. Returns false
+    /// if it was never registered.
+    pub fn check_in(&mut self, id: u32, now: uNano) -> bool {
+        match self.find(id) {
+            None => {
+                return false;
+            }
+            Some(index) => {
+                self.participants[index].last_check_in = now;
+                return true;
+            }
+        }
+    }
+
+    /// The first participant which has gone too
+    /// long without checking in, if there is one.
+    pub fn stale(&self, now: uNano) -> Option<u32> {
+        for index in 0..self.count {
+            if self.participants[index].is_stale(now) {
+                return Some(self.participants[index].id);
+            }
+        }
+
+        return None;
+    }
+
+    pub fn len(&self) -> usize {
+        return self.count;
+    }
+}
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut MONITOR: HealthMonitor = HealthMonitor::new();
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut WATCHDOG: Option<Watchdog> = None;
+
+fn monitor() -> &'static mut HealthMonitor {
+    return unsafe { &mut *core::ptr::addr_of_mut!(MONITOR) };
+}
+
+/// Start watching `id`, which then has to call `health_check_in`
+/// at least every `max_interval_ms` milliseconds.
+pub fn health_register(id: u32, max_interval_ms: u32) -> bool {
+    let _cs = CriticalSection::new();
+    return monitor().register(id, max_interval_ms as uNano * MS_TO_NANO, nanos());
+}
+
+/// Stop watching `id`, for example when a task finishes.
+pub fn health_unregister(id: u32) -> bool {
+    let _cs = CriticalSection::new();
+    return monitor().unregister(id);
+}
+
+/// Record that `id` made progress. Safe to call from
+/// gate stages, tasks and interrupt handlers alike.
+pub fn health_check_in(id: u32) -> bool {
+    let _cs = CriticalSection::new();
+    return monitor().check_in(id, nanos());
+}
+
+/// The first participant which has gone too long without
+/// checking in. Handy to log from a pre-timeout interrupt.
+pub fn health_stale() -> Option<u32> {
+    let _cs = CriticalSection::new();
+    return monitor().stale(nanos());
+}
+
+/// Feed the watchdog started by `health_start`, but only if every
+/// participant is healthy. Returns whether everything was healthy.
+pub fn health_feed() -> bool {
+    if health_stale().is_some() {
+        return false;
+    }
+
+    match unsafe { WATCHDOG } {
+        None => {}
+        Some(dog) => watchdog_feed(dog),
+    }
+
+    return true;
+}
+
+/// Start `dog` with a `timeout_ms` timeout, and check on every
+/// participant four times per timeout, feeding the watchdog
+/// whenever they are all healthy.
+///
+/// The watchdog is left alone if the checks can't be scheduled,
+/// since nothing would ever feed it.
+pub fn health_start(dog: Watchdog, timeout_ms: u32) -> Result<(), WatchdogError> {
+    let period_ms = timeout_ms / 4;
+    if period_ms == 0 {
+        return Err(WatchdogError::TimeoutOutOfRange);
+    } else if !timers::timers_ready() {
+        return Err(WatchdogError::TimerUnavailable);
+    }
+
+    let timer = match timers::every(period_ms, health_service) {
+        None => {
+            return Err(WatchdogError::TimerUnavailable);
+        }
+        Some(handle) => handle,
+    };
+
+    let config = WatchdogConfig {
+        timeout_ms: timeout_ms,
+        window_ms: None,
+        pre_timeout_ms: None,
+    };
+
+    match watchdog_configure(dog, config) {
+        Ok(()) => {}
+        Err(err) => {
+            timers::cancel(timer);
+            return Err(err);
+        }
+    }
+
+    unsafe {
+        WATCHDOG = Some(dog);
+    }
+
+    return Ok(());
+}
+
+fn health_service() {
+    health_feed();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_monitor() {
+        let mut monitor = HealthMonitor::new();
+        assert!(monitor.register(1, 100, 0));
+        assert!(monitor.register(2, 300, 0));
+        assert_eq!(monitor.stale(100), None);
+
+        // The first participant goes quiet
+        assert!(monitor.check_in(2, 150));
+        assert_eq!(monitor.stale(150), Some(1));
+
+        assert!(monitor.check_in(1, 200));
+        assert_eq!(monitor.stale(300), None);
+        assert!(monitor.check_in(1, 400));
+        assert_eq!(monitor.stale(451), Some(2));
+
+        assert!(monitor.unregister(2));
+        assert!(!monitor.check_in(2, 460));
+        assert_eq!(monitor.stale(300), None);
+        assert_eq!(monitor.len(), 1);
+    }
+
+    #[test]
+    fn test_monitor_full() {
+        let mut monitor = HealthMonitor::new();
+        for id in 0..MAX_PARTICIPANTS as u32 {
+            assert!(monitor.register(id, 100, 0));
+        }
+
+        assert!(!monitor.register(99, 100, 0));
+
+        // Registering again only changes the interval
+        assert!(monitor.register(3, 1000, 0));
+        assert_eq!(monitor.len(), MAX_PARTICIPANTS);
+    }
+
+    #[test]
+    fn test_health_feed() {
+        clock_set(0);
+        health_register(7, 10);
+        assert!(health_feed());
+
+        clock_advance(11 * MS_TO_NANO);
+        assert_eq!(health_stale(), Some(7));
+        assert!(!health_feed());
+
+        health_check_in(7);
+        assert!(health_feed());
+    }
+
+    #[test]
+    fn test_health_start_checks_timer() {
+        // Too short to check on anything
+        assert_eq!(
+            health_start(Watchdog::Wdog1, 3),
+            Err(WatchdogError::TimeoutOutOfRange)
+        );
+
+        // Nothing would run the checks
+        assert_eq!(
+            health_start(Watchdog::Wdog1, 2000),
+            Err(WatchdogError::TimerUnavailable)
+        );
+
+        // No room left for the checks
+        timers::timers_init();
+        while timers::every(1000, health_service).is_some() {}
+        assert_eq!(
+            health_start(Watchdog::Wdog1, 2000),
+            Err(WatchdogError::TimerUnavailable)
+        );
+        assert_eq!(unsafe { WATCHDOG }, None);
+    }
+}
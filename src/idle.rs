@@ -0,0 +1,215 @@
+//! Sleeping in the main loop when there is nothing to do.
+//!
+//! Left alone, the main loop spins as fast as it can, checking
+//! gates which aren't ready yet. `idle!()` works out when the
+//! next gate or software timer is due and sleeps until then, or
+//! until an interrupt comes along, whichever is first.
+//!
+//! ```no_run
+//! use teensycore::*;
+//! use teensycore::prelude::*;
+//! use teensycore::timers::timers_init;
+//! # static mut GATES: BTreeMap<u32, u32> = BTreeMap { root: None };
+//! # fn blink() {}
+//!
+//! timers_init();
+//!
+//! loop {
+//!     gate_open!()
+//!         .when_nano(Duration::from_millis(500), blink)
+//!         .compile();
+//!
+//!     idle!();
+//! }
+//! ```
+//!
+//! A timed sleep is ended by a software timer, so `timers_init()`
+//! needs to have been called. Until it has, idle only sleeps when
+//! nothing is scheduled at all.
+//!
+//! Idle uses `SleepMode::Idle` unless told otherwise. Deeper modes
+//! save more power, but only the wake sources enabled in
+//! `phys::power` end them. The periodic timers stop in
+//! `SleepMode::Stop`, so timed sleeps use `SleepMode::Wait` instead.
+use crate::clock::*;
+use crate::gate::gate_next_wake;
+use crate::phys::power::*;
+use crate::system::map::BTreeMap;
+use crate::tasks::TaskRunner;
+use crate::timers;
+use crate::MICRO_TO_NANO;
+
+/// Sleeps shorter than this aren't worth the time
+/// it takes to go to sleep and wake up again.
+pub const MIN_IDLE_NANOS: uNano = 20 * MICRO_TO_NANO;
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut MODE: SleepMode = SleepMode::Idle;
+
+/// Sleep until the next gate or software timer is due, using
+/// the `GATES` registry which `main!` sets up.
+#[macro_export]
+macro_rules! idle {
+    () => {
+        $crate::idle::idle(unsafe { &*core::ptr::addr_of!(GATES) })
+    };
+}
+
+/// Choose how deeply the idle hook sleeps.
+pub fn idle_set_mode(mode: SleepMode) {
+    unsafe {
+        MODE = mode;
+    }
+}
+
+pub fn idle_mode() -> SleepMode {
+    return unsafe { MODE };
+}
+
+/// When the next gate or software timer is due, or None
+/// if nothing is scheduled.
+pub fn idle_next_wake(gates: &BTreeMap<u32, u32>) -> Option<uNano> {
+    return soonest(gate_next_wake(gates), timers::timers_next_due());
+}
+
+/// Sleep until the next gate or software timer is due. Returns
+/// false if something is due too soon to bother.
+pub fn idle(gates: &BTreeMap<u32, u32>) -> bool {
+    return idle_until(idle_next_wake(gates));
+}
+
+/// The same as `idle()`, but for the `main!(tasks: [...])` loop.
+/// Never sleeps if a task without a rate limit is in the list.
+pub fn idle_tasks<const N: usize>(runner: &TaskRunner<N>, gates: &BTreeMap<u32, u32>) -> bool {
+    return match runner.next_due() {
+        None => false,
+        Some(due) => idle_until(soonest(Some(due), idle_next_wake(gates))),
+    };
+}
+
+/// Sleep until the uptime reaches `wake_at`, or until an
+/// interrupt. None sleeps until an interrupt. Returns false
+/// if it didn't sleep at all.
+pub fn idle_until(wake_at: Option<uNano>) -> bool {
+    // Read once, so an interrupt can't move the clock
+    // past `wake_at` between the check and the timer.
+    let now = nanos();
+    let mode = match wake_at {
+        None => idle_mode(),
+        Some(wake_at) => {
+            if wake_at <= now + MIN_IDLE_NANOS || !timers::timers_ready() {
+                return false;
+            }
+
+            match idle_mode() {
+                SleepMode::Stop => SleepMode::Wait,
+                mode => mode,
+            }
+        }
+    };
+
+    let timer = match wake_at {
+        None => None,
+        Some(wake_at) => match timers::after_nano(wake_at - now, idle_wake) {
+            None => {
+                return false;
+            }
+            handle => handle,
+        },
+    };
+
+    // The timer can only end a deeper sleep as a wake source
+    let borrowed = timer.is_some()
+        && mode != SleepMode::Idle
+        && !power_wake_enabled(WakeSource::PeriodicTimer)
+        && power_wake_enable(WakeSource::PeriodicTimer);
+
+    // With nothing enabled to end it, fall back to a plain WFI
+    if power_sleep(mode).is_err() {
+        power_sleep(SleepMode::Idle).ok();
+    }
+
+    if borrowed {
+        power_wake_disable(WakeSource::PeriodicTimer);
+    }
+
+    match timer {
+        None => {}
+        Some(handle) => {
+            simulate_wake(wake_at);
+            timers::cancel(handle);
+        }
+    }
+
+    return true;
+}
+
+fn soonest(a: Option<uNano>, b: Option<uNano>) -> Option<uNano> {
+    return match (a, b) {
+        (None, b) => b,
+        (a, None) => a,
+        (Some(a), Some(b)) => Some(crate::math::min(a, b)),
+    };
+}
+
+/// The timer only exists to wake the core up.
+fn idle_wake() {}
+
+#[cfg(not(feature = "testing"))]
+fn simulate_wake(_wake_at: Option<uNano>) {}
+
+/// On the host nothing wakes the core up, so move
+/// the clock on as if the timer had.
+#[cfg(feature = "testing")]
+fn simulate_wake(wake_at: Option<uNano>) {
+    match wake_at {
+        Some(wake_at) if wake_at > nanos() => clock_set(wake_at),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MS_TO_NANO;
+
+    fn nop() {}
+
+    #[test]
+    fn test_idle_until() {
+        clock_set(0);
+
+        // Nothing can end a timed sleep before the timers start
+        assert!(!idle_until(Some(5 * MS_TO_NANO)));
+        timers::timers_init();
+
+        assert!(idle_until(Some(5 * MS_TO_NANO)));
+        assert_eq!(nanos(), 5 * MS_TO_NANO);
+        assert_eq!(timers::timers_next_due(), None);
+
+        // Too soon to be worth it
+        assert!(!idle_until(Some(nanos() + MIN_IDLE_NANOS)));
+
+        // Nothing to end a Stop, so it falls back to a WFI
+        idle_set_mode(SleepMode::Stop);
+        power_wake_clear();
+        assert!(idle_until(None));
+        assert!(idle_until(Some(nanos() + MS_TO_NANO)));
+        assert!(!power_wake_enabled(WakeSource::PeriodicTimer));
+        idle_set_mode(SleepMode::Idle);
+    }
+
+    #[test]
+    fn test_next_wake() {
+        clock_set(0);
+        let gates: BTreeMap<u32, u32> = BTreeMap { root: None };
+        assert_eq!(idle_next_wake(&gates), None);
+
+        let handle = timers::after(10, nop).unwrap();
+        assert_eq!(idle_next_wake(&gates), Some(10 * MS_TO_NANO));
+        timers::cancel(handle);
+
+        assert_eq!(soonest(Some(3), Some(2)), Some(2));
+        assert_eq!(soonest(None, Some(2)), Some(2));
+    }
+}
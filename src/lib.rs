@@ -10,10 +10,12 @@
 //!  - Add panic handling
 //!  - Verify memory access
 //!  - Enable FPU
+//!  - Register the kernel heap as the global allocator
 
 #![no_std]
 #![allow(internal_features)]
 #![feature(lang_items, fn_traits)]
+#![cfg_attr(feature = "testing", feature(thread_local))]
 #![crate_type = "staticlib"]
 
 #[cfg(feature = "testing")]
@@ -21,20 +23,28 @@ extern crate std;
 
 pub mod clock;
 pub mod debug;
+pub mod executor;
 pub mod gate;
+pub mod health;
 pub mod i2c;
+pub mod idle;
 pub mod math;
 pub mod mem;
 pub mod phys;
 pub mod prelude;
 pub mod serio;
 pub mod system;
+pub mod tasks;
+pub mod thread;
+pub mod timers;
 pub mod usb_serial;
 
-use crate::clock::uNano;
+use crate::clock::{uNano, Duration};
 use core::arch::asm;
 use core::arch::global_asm;
+#[cfg(not(feature = "testing"))]
 use phys::irq::*;
+#[cfg(not(feature = "testing"))]
 use phys::pins::*;
 
 /// Returns how many nanos are in a second
@@ -79,15 +89,46 @@ pub const MICRO_TO_NANO: uNano = 1000;
 /// This is the primary macro necessary to bootstrap your application.
 /// It takes a code block that will be used as the entrypoint to your
 /// logic.
+///
+/// Alternatively, it takes a list of `Task` types. Each one is
+/// constructed and initialized in order, and then run forever by
+/// a `tasks::TaskRunner`. A task followed by `=> ms` runs at most
+/// once every `ms` milliseconds. When every task has a rate
+/// limit, the loop sleeps until the next one is due, as long as
+/// `timers::timers_init()` has been called.
+///
+/// ```ignore
+/// main!(tasks: [Blinker => 500, Sensor]);
+/// ```
 #[macro_export]
 macro_rules! main {
+    (tasks: [ $( $task: ty $( => $period_ms: expr )? ),* $(,)? ]) => {
+        $crate::main!({
+            let (mut tasks, mut runner) = $crate::task_list!($( $task $( => $period_ms )? ),*);
+
+            runner.init(&mut tasks);
+            loop {
+                runner.step(&mut tasks);
+                $crate::idle::idle_tasks(&runner, unsafe { &*core::ptr::addr_of!(GATES) });
+            }
+        });
+    };
     ($app_code: block) => {
         use teensycore::prelude::*;
 
         pub static mut GATES: BTreeMap<u32, u32> = BTreeMap { root: None };
 
+        #[global_allocator]
+        static ALLOCATOR: MempageAllocator = MempageAllocator;
+
         #[no_mangle]
         pub fn main() {
+            // Paint the stack before anything has a chance to use it
+            stack_paint();
+
+            // Guard the stack and null pointers, and keep DMA buffers out of the cache
+            teensycore::phys::mpu::mpu_init();
+
             loop {
                 // Initialize irq system, (disables all interrupts)
                 disable_interrupts();
@@ -126,8 +167,27 @@ macro_rules! main {
     };
 }
 
+/// Construct every task in a `main!(tasks: [...])` list, along
+/// with the `tasks::TaskRunner` which runs them.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! task_list {
+    ($( $task: ty $( => $period_ms: expr )? ),* $(,)?) => {
+        (
+            [ $( &mut <$task as $crate::Task>::new() as &mut dyn $crate::Task ),* ],
+            $crate::tasks::TaskRunner::new([
+                $( $crate::tasks::TaskEntry::new(stringify!($task)) $( .every_ms($period_ms) )? ),*
+            ]),
+        )
+    };
+}
+
+/// A self-contained piece of firmware. See `tasks` for
+/// how to hand a list of them to `main!`.
 pub trait Task {
-    fn new() -> Self;
+    fn new() -> Self
+    where
+        Self: Sized;
     fn init(&mut self);
     fn system_loop(&mut self);
 }
@@ -164,25 +224,27 @@ pub fn wait_ns(nano: uNano) {
     wait_exact_ns(nano);
 }
 
-#[no_mangle]
-fn div(a: f32, b: f32) -> u32 {
-    return (a / b) as u32;
+/// Waits for a `clock::Duration`.
+///
+/// ```no_run
+/// use teensycore::*;
+/// use teensycore::clock::Duration;
+/// wait(Duration::from_secs(1));
+/// ```
+pub fn wait(duration: Duration) {
+    wait_exact_ns(uNano::from(duration));
 }
 
 #[inline]
 #[no_mangle]
-/// This method will wait a certain amount of milliseconds
-/// by calculating how many `nop` commands it will take
-/// and issuing them sequentially.
+/// This method will wait a certain amount of nanoseconds
+/// by spinning on the core's cycle counter.
 ///
-/// This is extremely performant and has been used
-/// successfully to drive WS2812b LEDs which require
-/// nanosecond-specific timing.
+/// It is exact to within a couple of cycles regardless of the
+/// clock speed, and has been used successfully to drive WS2812b
+/// LEDs which require nanosecond-specific timing.
 pub fn wait_exact_ns(nano: uNano) {
-    let cycles = div(nano as f32 - 98.0, 7.54);
-    for _ in 0..cycles {
-        assembly!("nop");
-    }
+    clock::delay_ns(nano as u64);
 }
 
 /// This method will intiate a pendsv interrupt
@@ -227,12 +289,17 @@ pub fn arm_dcache_delete(addr: u32, size: u32) {
     isb();
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum PanicType {
     Hardfault,
     Memfault,
     Oob,
+    HeapCorruption,
+    StackOverflow,
+    Deadlock,
 }
 
+#[cfg(not(feature = "testing"))]
 #[no_mangle]
 /// Use this method to enter a system-wide failure event.
 ///
@@ -248,6 +315,15 @@ pub enum PanicType {
 /// Memory Fault (memfault)
 /// LED is on for a long time (1.5s) and pulled low briefly (50ms).
 ///
+/// Heap Corruption (heapcorruption)
+/// LED flashes three times quickly and is pulled low for 1.5s.
+///
+/// Stack Overflow (stackoverflow)
+/// LED flashes twice slowly (500ms) and is pulled low for 1.5s.
+///
+/// Deadlock (deadlock)
+/// LED flashes four times very quickly (50ms) and is pulled low for 1.5s.
+///
 /// This blink pattern will loop indefinitely and the system will
 /// be entirely inoperable. Reserved for catastrophic, non-recoverable
 /// situations.
@@ -260,20 +336,54 @@ pub fn err(mode: PanicType) {
             }
             PanicType::Oob => {
                 pin_out(13, Power::High);
-                wait_ns(MS_TO_NANO * 50);
+                wait(Duration::from_millis(50));
                 pin_out(13, Power::Low);
-                wait_ns(MS_TO_NANO * 1500);
+                wait(Duration::from_millis(1500));
             }
             PanicType::Memfault => {
                 pin_out(13, Power::High);
-                wait_ns(MS_TO_NANO * 1500);
+                wait(Duration::from_millis(1500));
                 pin_out(13, Power::Low);
-                wait_ns(MS_TO_NANO * 50);
+                wait(Duration::from_millis(50));
+            }
+            PanicType::HeapCorruption => {
+                for _ in 0..3 {
+                    pin_out(13, Power::High);
+                    wait(Duration::from_millis(100));
+                    pin_out(13, Power::Low);
+                    wait(Duration::from_millis(100));
+                }
+                wait(Duration::from_millis(1500));
+            }
+            PanicType::StackOverflow => {
+                for _ in 0..2 {
+                    pin_out(13, Power::High);
+                    wait(Duration::from_millis(500));
+                    pin_out(13, Power::Low);
+                    wait(Duration::from_millis(200));
+                }
+                wait(Duration::from_millis(1500));
+            }
+            PanicType::Deadlock => {
+                for _ in 0..4 {
+                    pin_out(13, Power::High);
+                    wait(Duration::from_millis(50));
+                    pin_out(13, Power::Low);
+                    wait(Duration::from_millis(50));
+                }
+                wait(Duration::from_millis(1500));
             }
         }
     }
 }
 
+/// When testing on the host there is no LED to blink,
+/// so a system-wide failure becomes a regular panic.
+#[cfg(feature = "testing")]
+pub fn err(mode: PanicType) {
+    panic!("kernel panic: {:?}", mode);
+}
+
 #[no_mangle]
 /// Issue an out-of-bounds kernel panic.
 fn oob() {
@@ -284,10 +394,10 @@ fn oob() {
 /// program counter of the line of code which
 /// invokes this function.
 ///
+#[cfg(not(feature = "testing"))]
 pub fn code_hash() -> u32 {
     let mut result = 0;
 
-    #[cfg(not(feature = "testing"))]
     unsafe {
         asm!(
             "mov {result}, lr",
@@ -298,6 +408,22 @@ pub fn code_hash() -> u32 {
     return result;
 }
 
+/// When testing on the host there is no link register
+/// to read, so the source location of the caller is
+/// hashed instead.
+#[cfg(feature = "testing")]
+#[track_caller]
+pub fn code_hash() -> u32 {
+    let location = core::panic::Location::caller();
+    let mut result = 0x811C_9DC5u32;
+    for byte in location.file().bytes() {
+        result = (result ^ byte as u32).wrapping_mul(0x0100_0193);
+    }
+    result = (result ^ location.line()).wrapping_mul(0x0100_0193);
+    result = (result ^ location.column()).wrapping_mul(0x0100_0193);
+    return result;
+}
+
 #[cfg(not(feature = "testing"))]
 global_asm!(
     "
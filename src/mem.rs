@@ -7,24 +7,241 @@
 //! with teensycore properly handle memory as best they can, and offer
 //! a `drop()` method which should be invoked as soon as the variable
 //! is no longer required.
-use core::mem::size_of;
+//!
+//! The same allocator is exposed as a `GlobalAlloc` through
+//! `MempageAllocator`, which the `main!` macro registers as the
+//! `#[global_allocator]`. This means the standard `alloc` crate
+//! (`Vec`, `String`, `Box`, `BTreeMap`...) works out of the box:
+//!
+//! ```no_run
+//! extern crate alloc;
+//! use alloc::vec::Vec;
+//!
+//! let mut readings: Vec<u32> = Vec::new();
+//! readings.push(1337);
+//! ```
+//!
+//! Every memory region on the chip gets a heap of its own. By default
+//! everything lands in OCRAM2, but `alloc_in()` can place hot data in
+//! the tightly coupled memories, or buffers for DMA in a region which
+//! the data cache never touches:
+//!
+//! ```no_run
+//! use teensycore::mem::*;
+//!
+//! let descriptors = alloc_in::<[u32; 8]>(Region::Dtcm);
+//! let rx_buffer = alloc_in::<[u8; 512]>(Region::DmaSafe);
+//! ```
+//!
+//! To keep an eye on how the heap is holding up, `heap_stats()`
+//! returns a snapshot of usage and fragmentation, and `heap_dump()`
+//! prints the same thing over serial.
+//!
+//! Every page is bracketed by a header and footer canary. Freeing a
+//! pointer twice, freeing something that never came from the heap, or
+//! writing past the end of a page will trip `PanicType::HeapCorruption`.
+//! `heap_check()` validates the entire heap in one go.
+//!
+//! For memory with a well defined lifetime, create an `Arena`. Anything
+//! allocated from it (including a `Str` or `Vector` built with
+//! `with_arena`) is released together with a single call:
+//!
+//! ```no_run
+//! use teensycore::mem::*;
+//! use teensycore::system::str::*;
+//!
+//! let arena = Arena::new();
+//! let mut command = Str::with_arena(arena);
+//! command.append(b"led on");
+//! arena.release();
+//! ```
+//!
+//! The stack is painted with a known pattern at boot, which makes it
+//! possible to see how close it has ever come to running out with
+//! `stack_high_water_mark()` and `stack_free()`. Writing past the
+//! bottom of the stack trips `PanicType::StackOverflow` the next time
+//! `stack_check()` runs, which `stack_watch()` can do periodically.
+//!
+//! The heap is shared by every thread, so each change to it happens
+//! with interrupts held off. `MEMORY_SCOPE` belongs to whichever
+//! thread is running, and is swapped along with everything else when
+//! the scheduler switches threads, so a `using!` block in one thread
+//! never captures memory allocated by another.
+//!
+//! With the `testing` feature, each test thread gets blocks of host
+//! memory standing in for every region, so the exact same allocator
+//! code runs under `cargo test` as on the device.
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::{align_of, size_of};
+use core::ptr::addr_of_mut;
 
+use crate::phys::irq::CriticalSection;
+
+#[cfg(not(feature = "testing"))]
+use crate::phys::addrs::OCRAM;
 #[cfg(not(feature = "testing"))]
-use crate::phys::addrs::OCRAM2;
+use core::ptr::addr_of;
 
 pub type ScopeUnit = u32;
 
-const MEMORY_MINIMUM: u32 = 0x0_0FFC;
-const MEMORY_MAXIMUM: u32 = 0x7_FFFF - 0x0_0FFC; // 512kb - 4kb buffer
-const MEMORY_BEGIN_OFFSET: u32 = MEMORY_MINIMUM; // 4kb buffer (note: it should be word aligned)
-pub static mut MEMORY_SCOPE: ScopeUnit = 0x1337; // A not-thread-safe reference to the scope in which memory was allocated
-static mut MEMORY_OFFSET: u32 = MEMORY_BEGIN_OFFSET;
-static mut MEMORY_PAGES: Option<*mut Mempage> = None;
-static mut IS_OVERRUN: bool = false;
+/// The scope memory is allocated against outside of any
+/// `using!` block or arena.
+pub const DEFAULT_SCOPE: ScopeUnit = 0x1337;
+
+const MEMORY_ALIGNMENT: usize = align_of::<Mempage>(); // Every page is at least word aligned
+const DTCM_HEAP_BYTES: usize = 0x8000; // 32kb carved out of .bss
+const DMA_SAFE_BYTES: usize = 0x8000; // 32kb at the very top of OCRAM2
+const FLEXRAM_BANK_BYTES: usize = 0x8000;
+const REGION_COUNT: usize = 5;
+
+const HEADER_CANARY: u32 = 0x7EE9_5AFE;
+const STALE_CANARY: u32 = 0x7EE9_DEAD; // Left behind on headers that were merged away
+const FOOTER_CANARY: u32 = 0xF007_5AFE;
+
+const STACK_PAINT: u32 = 0x57AC_57AC; // Fills the stack which has never been used
+const STACK_GUARD: u32 = 0x57AC_0BAD; // Fills the guard words beneath the stack
+const STACK_GUARD_BYTES: usize = 32; // Reserved underneath the stack by the linker
+const STACK_PAINT_HEADROOM: usize = 64;
+
+/// Arenas are numbered upwards from here, well clear of
+/// the scopes which are reserved for other uses.
+const ARENA_SCOPE_BEGIN: ScopeUnit = 0x0010_0000;
+
+/// The scope given to pages allocated through `MempageAllocator`.
+/// These are owned by rust's own drop semantics, so a `using!` block
+/// must never release them.
+pub const GLOBAL_ALLOC_SCOPE: ScopeUnit = 0xA110C;
+
+/// A region of memory which can be allocated from.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Region {
+    /// Tightly coupled data memory. Single cycle access and
+    /// never cached, ideal for ISR queues and DMA descriptors.
+    Dtcm = 0,
+    /// Whatever tightly coupled instruction memory is left
+    /// over after the program has been loaded.
+    Itcm = 1,
+    /// FlexRAM banks configured as OCRAM. The linker gives every
+    /// bank to ITCM or DTCM by default, in which case this is empty.
+    Ocram1 = 2,
+    /// The dedicated 512kb of OCRAM. Everything goes here by default.
+    Ocram2 = 3,
+    /// A window at the top of OCRAM2 reserved for buffers shared with
+    /// DMA. Nothing else lives here, so it can be left out of the
+    /// data cache as a whole.
+    DmaSafe = 4,
+}
+
+/// Every region, in the order they are reported.
+pub const REGIONS: [Region; REGION_COUNT] = [
+    Region::Dtcm,
+    Region::Itcm,
+    Region::Ocram1,
+    Region::Ocram2,
+    Region::DmaSafe,
+];
+
+impl Region {
+    /// A short, human readable name for the region.
+    pub fn name(&self) -> &'static [u8] {
+        return match self {
+            Region::Dtcm => b"dtcm",
+            Region::Itcm => b"itcm",
+            Region::Ocram1 => b"ocram1",
+            Region::Ocram2 => b"ocram2",
+            Region::DmaSafe => b"dma",
+        };
+    }
+
+    /// Returns the first and last (exclusive) address
+    /// which the heap in this region can hand out.
+    pub fn bounds(&self) -> (usize, usize) {
+        let heap = heap(*self);
+        return unsafe { ((*heap).start, (*heap).end) };
+    }
+}
+
+/// Everything there is to know about the heap in a single region.
+struct Heap {
+    ready: bool,
+    start: usize,
+    end: usize,
+    top: usize, // The first byte which has never been claimed
+    pages: Option<*mut Mempage>,
+    free_pages: Option<*mut Mempage>,
+    overrun: bool,
+    used_bytes: usize,
+    high_water_mark: usize,
+    alloc_count: usize,
+    free_count: usize,
+}
+
+impl Heap {
+    const fn new() -> Self {
+        return Heap {
+            ready: false,
+            start: 0,
+            end: 0,
+            top: 0,
+            pages: None,
+            free_pages: None,
+            overrun: false,
+            used_bytes: 0,
+            high_water_mark: 0,
+            alloc_count: 0,
+            free_count: 0,
+        };
+    }
+}
+
+#[cfg_attr(feature = "testing", thread_local)]
+pub static mut MEMORY_SCOPE: ScopeUnit = DEFAULT_SCOPE; // The scope in which memory is allocated, saved and restored for each thread
+#[cfg_attr(feature = "testing", thread_local)]
+static mut HEAPS: [Heap; REGION_COUNT] = [Heap::new(), Heap::new(), Heap::new(), Heap::new(), Heap::new()];
+#[cfg_attr(feature = "testing", thread_local)]
+static mut HEAP_FAULT: Option<HeapFault> = None;
+#[cfg_attr(feature = "testing", thread_local)]
+static mut STACK_PAINTED: bool = false;
+#[cfg_attr(feature = "testing", thread_local)]
+static mut NEXT_ARENA_SCOPE: ScopeUnit = ARENA_SCOPE_BEGIN;
+
+// The DTCM heap is an ordinary static, which the linker
+// places in .bss alongside everything else in DTCM.
+#[cfg(not(feature = "testing"))]
+static mut DTCM_HEAP: [u32; DTCM_HEAP_BYTES / 4] = [0; DTCM_HEAP_BYTES / 4];
+
+#[cfg(not(feature = "testing"))]
+extern "C" {
+    static _etext: u32;
+    static _itcm_block_count: u32;
+    static _heap_start: u32;
+    static _heap_end: u32;
+    static _ebss: u32;
+    static _estack: u32;
+}
+
+// When testing on the host, every test thread gets its own
+// stand-in for each region so the real page logic can be exercised.
+#[cfg(feature = "testing")]
+#[thread_local]
+static mut HOST_REGIONS: [usize; REGION_COUNT] = [0; REGION_COUNT];
+#[cfg(feature = "testing")]
+#[thread_local]
+static mut HOST_STACK: usize = 0;
+#[cfg(feature = "testing")]
+const HOST_STACK_BYTES: usize = 0x1000;
 
 /// A page of memory
+///
+/// Pages are laid out back-to-back in the heap, each header directly
+/// in front of its payload. `next` always points at the page physically
+/// beneath this one, so the page list walks the heap from the top down.
+///
+/// `guard` holds the header canary, and the last word of every page
+/// holds the footer canary. `size` covers the header, payload and footer.
 #[repr(C)]
 pub struct Mempage {
+    pub guard: u32,
     pub size: usize,
     pub scope: ScopeUnit,
     pub used: bool,
@@ -32,280 +249,1820 @@ pub struct Mempage {
     pub ptr: *mut u32,
 }
 
-#[cfg(not(feature = "testing"))]
+/// The free list is threaded through the payload of each free
+/// page, so it costs nothing while a page is in use.
+#[repr(C)]
+struct FreeLinks {
+    prev: Option<*mut Mempage>,
+    next: Option<*mut Mempage>,
+}
+
+/// What kind of damage was found in the heap.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HeapFault {
+    /// A pointer was freed which did not come from the heap.
+    WildFree,
+    /// A pointer was freed which had already been freed.
+    DoubleFree,
+    /// A page header was overwritten.
+    HeaderCorrupt,
+    /// The end of a page was overwritten, usually by writing past its payload.
+    FooterCorrupt,
+    /// The pages no longer sit back-to-back.
+    ChainBroken,
+    /// The free list no longer matches the free pages in the heap.
+    FreeListBroken,
+}
+
+const PAGE_BYTES: usize = size_of::<Mempage>();
+const FOOTER_BYTES: usize = MEMORY_ALIGNMENT; // Room for the footer canary which keeps pages aligned
+const MIN_PAGE_BYTES: usize = PAGE_BYTES + size_of::<FreeLinks>() + FOOTER_BYTES; // Anything smaller is not worth splitting off
+
 impl Mempage {
     pub const fn new(size: usize, ptr: *mut u32) -> Self {
         return Mempage {
+            guard: HEADER_CANARY,
             size: size,
             used: true,
             ptr: ptr,
-            scope: 0x1337,
+            scope: DEFAULT_SCOPE,
             next: None,
         };
     }
 
-    /// Returns how many blocks of memory are actively allocated.
+    /// Returns how many blocks of memory are actively allocated,
+    /// across every region.
     pub fn ref_count() -> usize {
         let mut count = 0;
-        unsafe {
-            let mut ptr = MEMORY_PAGES;
-            while ptr.is_some() {
-                let node = ptr.unwrap();
-                if (*node).used == true {
-                    count += 1;
-                }
-                ptr = (*node).next;
-            }
+        for region in REGIONS {
+            count += count_pages(region, None);
         }
-
         return count;
     }
 
-    /// Returns the next available block of memory that
-    /// will fit some arbitrary amount of bytes.
-    pub fn reclaim_fast(bytes: usize) -> *mut u32 {
-        // Iterate through mempage searching for the first candidate
-        // that is currently free.
+    /// Search the free list of a region for the first page that can
+    /// fit some arbitrary amount of bytes, with a payload starting on
+    /// an `align` byte boundary. Oversized pages are split, and the
+    /// leftovers go back onto the free list.
+    pub fn reclaim_fast(region: Region, bytes: usize, align: usize) -> Option<*mut Mempage> {
+        let heap = heap(region);
         unsafe {
-            let mut ptr = MEMORY_PAGES;
+            let mut ptr = (*heap).free_pages;
 
             while ptr.is_some() {
                 let node = ptr.unwrap();
-                if (*node).size >= bytes && (*node).used == false {
-                    (*node).used = true;
-                    (*node).scope = MEMORY_SCOPE;
-                    return node as *mut u32;
+                let next = (*links(node)).next;
+                let start = (*node).ptr as usize;
+                let end = node as usize + (*node).size;
+
+                // If the payload isn't aligned already, the bytes skipped
+                // in front have to be big enough to become their own page.
+                let mut item = align_up(start, align);
+                while item != start && item - start < MIN_PAGE_BYTES {
+                    item += align;
                 }
-                ptr = (*node).next;
+
+                if item + bytes <= end {
+                    free_list_remove(heap, node);
+                    let mut page = node;
+
+                    if item != start {
+                        let above = Mempage::above(heap, node);
+                        let front_bytes = item - start;
+                        page = write_page(item - PAGE_BYTES, (*node).size - front_bytes, true, Some(node));
+                        set_size(node, front_bytes);
+                        set_below(heap, above, Some(page));
+                        free_list_push(heap, node);
+                    }
+
+                    (*page).used = true;
+                    Mempage::split(heap, page, bytes);
+                    return Some(page);
+                }
+
+                ptr = next;
             }
         }
 
-        loop {
-            crate::err(crate::PanicType::Memfault);
-        }
+        return None;
     }
 
-    /// Release all memory that was allocated with a given scope.
+    /// Release all memory that was allocated with a given scope,
+    /// in every region.
     pub fn free_scope(scope: ScopeUnit) {
+        let _cs = CriticalSection::new();
+
         // Iterate through mempage dropping all memory allocated with a given scope
-        unsafe {
-            let mut ptr = MEMORY_PAGES;
-            while ptr.is_some() {
-                let node = ptr.unwrap();
-                if (*node).scope == scope && (*node).used == true {
-                    Mempage::free((*node).ptr as u32);
+        for region in REGIONS {
+            unsafe {
+                let mut ptr = (*heap(region)).pages;
+                while ptr.is_some() {
+                    let node = ptr.unwrap();
+
+                    // Freeing can only merge this page with the pages around it.
+                    // The header of the page below is left intact either way,
+                    // so it is safe to keep walking from there.
+                    ptr = (*node).next;
+                    if (*node).scope == scope && (*node).used == true {
+                        Mempage::free((*node).ptr as usize);
+                    }
                 }
-                ptr = (*node).next;
             }
         }
     }
 
-    /// Free the page containing this ptr
-    pub fn free(ptr: u32) {
-        let bytes = size_of::<Mempage>() as u32;
-        // We know the Mempage header is
-        // right above the pointer. So we can use
-        // that knowledge to go straight there.
-        let addr = (ptr - bytes) as *mut Mempage;
+    /// Free the page containing this ptr. Neighbouring free pages
+    /// are merged together, and a free page at the very top of the
+    /// heap is handed back to the unclaimed region.
+    ///
+    /// Pointers which did not come from the heap, or which have
+    /// already been freed, raise `PanicType::HeapCorruption`.
+    pub fn free(ptr: usize) {
+        let _cs = CriticalSection::new();
+        let (heap, mut page) = match Mempage::owner(ptr) {
+            Ok(owner) => owner,
+            Err(fault) => {
+                raise_heap_fault(fault);
+                return;
+            }
+        };
+        let mut listed = false;
+
         unsafe {
-            (*addr).used = false;
+            (*page).used = false;
+            (*heap).used_bytes -= (*page).size;
+            (*heap).free_count += 1;
+
+            // Merge with the page above
+            match Mempage::above(heap, page) {
+                Some(above) if (*above).used == false => {
+                    free_list_remove(heap, above);
+                    let above_above = Mempage::above(heap, above);
+                    set_size(page, (*page).size + (*above).size);
+                    set_below(heap, above_above, Some(page));
+                    (*above).guard = STALE_CANARY;
+                }
+                _ => {}
+            }
+
+            // Merge with the page below
+            match (*page).next {
+                Some(below) if (*below).used == false => {
+                    let above = Mempage::above(heap, page);
+                    set_size(below, (*below).size + (*page).size);
+                    set_below(heap, above, Some(below));
+                    (*page).guard = STALE_CANARY;
+                    page = below;
+                    listed = true;
+                }
+                _ => {}
+            }
+
+            if Mempage::above(heap, page).is_none() {
+                // This is the top of the heap, give it back
+                if listed {
+                    free_list_remove(heap, page);
+                }
+                (*page).guard = STALE_CANARY;
+                (*heap).pages = (*page).next;
+                (*heap).top = page as usize;
+            } else if !listed {
+                free_list_push(heap, page);
+            }
         }
     }
 
     pub fn add_page<T>(bytes: usize) -> *mut T {
-        let page_bytes = size_of::<Mempage>();
-        let mut total_bytes = page_bytes + bytes;
+        return Mempage::add_page_aligned(bytes, align_of::<T>());
+    }
+
+    /// Allocate a page whose payload begins on an `align` byte
+    /// boundary. The header always sits directly in front of the
+    /// payload so `free()` can find it.
+    pub fn add_page_aligned<T>(bytes: usize, align: usize) -> *mut T {
+        return Mempage::add_page_in(bytes, align, unsafe { MEMORY_SCOPE });
+    }
+
+    /// The same as `add_page_aligned` but the page is recorded
+    /// against an explicit scope instead of `MEMORY_SCOPE`.
+    pub fn add_page_in<T>(bytes: usize, align: usize, scope: ScopeUnit) -> *mut T {
+        return Mempage::add_page_to(Region::Ocram2, bytes, align, scope);
+    }
 
-        // Word align
-        while total_bytes % 4 != 0 {
-            total_bytes += 1;
+    /// The same as `add_page_in` but the page is allocated
+    /// from a particular region.
+    pub fn add_page_to<T>(region: Region, bytes: usize, align: usize, scope: ScopeUnit) -> *mut T {
+        return match Mempage::try_add_page_to(region, bytes, align, scope) {
+            Some(ptr) => ptr,
+            None => loop {
+                crate::err(crate::PanicType::Memfault);
+            },
+        };
+    }
+
+    /// The same as `add_page_in`, but returns None instead of
+    /// raising `PanicType::Memfault` when the heap is exhausted.
+    pub fn try_add_page_in<T>(bytes: usize, align: usize, scope: ScopeUnit) -> Option<*mut T> {
+        return Mempage::try_add_page_to(Region::Ocram2, bytes, align, scope);
+    }
+
+    /// The same as `add_page_to`, but returns None instead of
+    /// raising `PanicType::Memfault` when the region is exhausted.
+    pub fn try_add_page_to<T>(region: Region, bytes: usize, align: usize, scope: ScopeUnit) -> Option<*mut T> {
+        let _cs = CriticalSection::new();
+        let heap = heap(region);
+        let align = crate::math::max(align, MEMORY_ALIGNMENT);
+
+        // Every page must be able to hold the free list links once
+        // it is released, and must end on a word boundary with
+        // room for the footer canary.
+        let bytes = align_up(crate::math::max(bytes, size_of::<FreeLinks>()), MEMORY_ALIGNMENT) + FOOTER_BYTES;
+
+        let page = match Mempage::reclaim_fast(region, bytes, align) {
+            Some(page) => page,
+            None => alloc_bytes(heap, bytes, align)?,
+        };
+
+        unsafe {
+            (*page).scope = scope;
+            (*heap).alloc_count += 1;
+            (*heap).used_bytes += (*page).size;
+            if (*heap).used_bytes > (*heap).high_water_mark {
+                (*heap).high_water_mark = (*heap).used_bytes;
+            }
+            return Some((*page).ptr as *mut T);
         }
+    }
 
-        let next_page = alloc_bytes(total_bytes) as *mut Mempage;
-        let item_ptr = ((next_page as u32) + page_bytes as u32) as *mut T;
+    /// Find the heap and page which own a pointer handed to
+    /// `free()`, making sure it is a live allocation.
+    fn owner(ptr: usize) -> Result<(*mut Heap, *mut Mempage), HeapFault> {
+        let heap = match heap_of(ptr) {
+            None => {
+                return Err(HeapFault::WildFree);
+            }
+            Some(heap) => heap,
+        };
 
-        if is_overrun() {
-            // Don't allocate a new page
-            return item_ptr;
+        if ptr % MEMORY_ALIGNMENT != 0 || ptr < unsafe { (*heap).start } + PAGE_BYTES {
+            return Err(HeapFault::WildFree);
         }
 
+        // We know the Mempage header is
+        // right above the pointer. So we can use
+        // that knowledge to go straight there.
+        let page = (ptr - PAGE_BYTES) as *mut Mempage;
         unsafe {
-            (*next_page) = Mempage {
-                size: total_bytes,
-                ptr: item_ptr as *mut u32,
-                used: true,
-                scope: MEMORY_SCOPE,
-                next: None,
-            };
-
-            match MEMORY_PAGES {
-                None => {
-                    MEMORY_PAGES = Some(next_page);
+            match (*page).guard {
+                STALE_CANARY => {
+                    return Err(HeapFault::DoubleFree);
                 }
-                Some(head) => {
-                    (*next_page).next = Some(head);
-                    MEMORY_PAGES = Some(next_page);
+                HEADER_CANARY => {
+                    if (*page).ptr as usize != ptr {
+                        return Err(HeapFault::WildFree);
+                    } else if (*page).used == false {
+                        return Err(HeapFault::DoubleFree);
+                    } else if footer(page) != FOOTER_CANARY {
+                        return Err(HeapFault::FooterCorrupt);
+                    }
+                }
+                _ => {
+                    return Err(HeapFault::WildFree);
                 }
             }
         }
 
-        return item_ptr;
+        return Ok((heap, page));
+    }
+
+    /// Returns the page physically above this one, or None if
+    /// this page is the top of the heap.
+    unsafe fn above(heap: *mut Heap, page: *mut Mempage) -> Option<*mut Mempage> {
+        let addr = page as usize + (*page).size;
+        if addr >= (*heap).top {
+            return None;
+        }
+        return Some(addr as *mut Mempage);
+    }
+
+    /// Carve the end of a page off into its own free page, if
+    /// there is enough left over past `bytes` to be worth it.
+    unsafe fn split(heap: *mut Heap, page: *mut Mempage, bytes: usize) {
+        let spare = (*page).size - PAGE_BYTES - bytes;
+        if spare < MIN_PAGE_BYTES {
+            return;
+        }
+
+        let above = Mempage::above(heap, page);
+        let tail = write_page((*page).ptr as usize + bytes, spare, false, Some(page));
+        set_size(page, (*page).size - spare);
+        set_below(heap, above, Some(tail));
+        free_list_push(heap, tail);
     }
 }
 
-/// A debug method which returns true if we've begun
-/// recyclilng memory.
-pub fn is_overrun() -> bool {
-    return unsafe { IS_OVERRUN };
+/// A `GlobalAlloc` implementation backed by `Mempage`.
+///
+/// The `main!` macro registers this as the `#[global_allocator]`
+/// so `alloc::vec::Vec`, `alloc::string::String`, `alloc::boxed::Box`
+/// and friends all draw from the same OCRAM2 heap as `Str` and `Vector`.
+///
+/// Pages handed out here are recorded against `GLOBAL_ALLOC_SCOPE`,
+/// so a `using!` block will never release memory that rust still
+/// expects to drop on its own.
+///
+/// When the heap is exhausted `alloc` returns a null pointer, as
+/// `GlobalAlloc` requires, and rust's own out of memory handling
+/// takes it from there.
+pub struct MempageAllocator;
+
+unsafe impl GlobalAlloc for MempageAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        return match Mempage::try_add_page_in(layout.size(), layout.align(), GLOBAL_ALLOC_SCOPE) {
+            Some(ptr) => ptr,
+            None => core::ptr::null_mut(),
+        };
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        Mempage::free(ptr as usize);
+    }
 }
 
-/// A method to zero out every piece of memory.
-/// If we encounter a bad sector, the device will throw an oob
-/// irq and enter error mode.
-#[cfg(not(feature = "testing"))]
-pub fn memtest() {
-    for addr in MEMORY_BEGIN_OFFSET..MEMORY_MAXIMUM / 4 {
+/// A group of allocations which are released all at once.
+///
+/// Every arena is given its own scope, so unlike a scope derived
+/// from the calling code, two arenas can never collide. An arena is
+/// just a handle, so it can be copied and passed around freely.
+/// Nothing is released until `release()` is called.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Arena {
+    scope: ScopeUnit,
+    region: Region,
+}
+
+impl Arena {
+    /// Create a new, empty arena in OCRAM2.
+    pub fn new() -> Self {
+        return Arena::new_in(Region::Ocram2);
+    }
+
+    /// Create a new, empty arena which allocates
+    /// from a particular region.
+    pub fn new_in(region: Region) -> Self {
+        let _cs = CriticalSection::new();
         unsafe {
-            let ptr = (OCRAM2 + addr * 4) as *mut u32;
-            *ptr = 0;
+            let scope = NEXT_ARENA_SCOPE;
+            NEXT_ARENA_SCOPE = match NEXT_ARENA_SCOPE.checked_add(1) {
+                None => ARENA_SCOPE_BEGIN,
+                Some(next) => next,
+            };
+
+            return Arena {
+                scope: scope,
+                region: region,
+            };
         }
     }
-}
 
-/// This method will zero out a certain amount of bytes
-/// from a particular address.
-#[cfg(not(feature = "testing"))]
-pub fn zero(addr: u32, bytes: u32) {
-    for byte in 0..bytes {
+    /// Returns the scope every page in this arena is recorded against.
+    pub fn scope(&self) -> ScopeUnit {
+        return self.scope;
+    }
+
+    /// Returns the region this arena allocates from.
+    pub fn region(&self) -> Region {
+        return self.region;
+    }
+
+    /// Allocate space for a `T` in this arena.
+    pub fn alloc<T>(&self) -> *mut T {
+        return Mempage::add_page_to(self.region, size_of::<T>(), align_of::<T>(), self.scope);
+    }
+
+    /// Make this arena the destination for any memory allocated
+    /// through `alloc()`, returning the scope that was active before.
+    pub fn enter(&self) -> ScopeUnit {
         unsafe {
-            let ptr = (addr + byte) as *mut u8;
-            *ptr = 0;
+            let previous = MEMORY_SCOPE;
+            MEMORY_SCOPE = self.scope;
+            return previous;
         }
     }
-}
 
-/// This method will copy a certain amount of bytes
-/// from src and into dest.
-#[cfg(not(feature = "testing"))]
-pub fn copy(src: u32, dest: u32, len: u32) {
-    for byte in 0..len {
-        unsafe {
-            let src_ptr = (src + byte) as *mut u8;
-            let dst_ptr = (dest + byte) as *mut u8;
-            *dst_ptr = *src_ptr;
+    /// Returns how many pages are allocated in this arena.
+    pub fn ref_count(&self) -> usize {
+        let mut count = 0;
+        for region in REGIONS {
+            count += count_pages(region, Some(self.scope));
         }
+        return count;
+    }
+
+    /// Free everything which was allocated in this arena.
+    /// The arena can still be used afterwards.
+    pub fn release(&self) {
+        Mempage::free_scope(self.scope);
     }
 }
 
-/// Internal use only.
-///
-/// This method will allocate bytes on the heap and
-/// return a raw pointer to the freshly claimed area
-/// of memory.
-#[cfg(not(feature = "testing"))]
-fn alloc_bytes(bytes: usize) -> *mut u32 {
-    // Check for boundaries and reset if applicable.
-    unsafe {
-        if MEMORY_OFFSET + bytes as u32 >= MEMORY_MAXIMUM {
-            IS_OVERRUN = true;
-            // return Mempage::reclaim(bytes);
-            return Mempage::reclaim_fast(bytes);
+/// A debug method which returns true if any heap has ever
+/// run out of fresh memory.
+pub fn is_overrun() -> bool {
+    for region in REGIONS {
+        if unsafe { (*heap(region)).overrun } {
+            return true;
         }
+    }
+    return false;
+}
+
+/// The most scopes `heap_stats()` will break usage down by.
+/// Anything beyond this is lumped together in `other_scopes`.
+pub const HEAP_STATS_SCOPES: usize = 16;
+
+/// How much of the heap a single `ScopeUnit` is holding on to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ScopeStats {
+    pub scope: ScopeUnit,
+    pub pages: usize,
+    pub bytes: usize,
+}
 
-        let ptr = (OCRAM2 + MEMORY_OFFSET) as *mut u32;
-        MEMORY_OFFSET += bytes as u32;
-        return ptr;
+impl ScopeStats {
+    pub const fn new(scope: ScopeUnit) -> Self {
+        return ScopeStats {
+            scope: scope,
+            pages: 0,
+            bytes: 0,
+        };
     }
 }
 
-/// This method will allocate a page of memory
-/// of any discreet struct. It then returns
-/// a raw pointer<T> to the location it just
-/// established.
-#[cfg(not(feature = "testing"))]
-pub fn alloc<T>() -> *mut T {
-    let bytes = size_of::<T>();
-    return Mempage::add_page(bytes);
+/// A snapshot of the heap in one region. All byte counts include
+/// the page headers, since that is what the pages actually cost.
+#[derive(Copy, Clone, Debug)]
+pub struct HeapStats {
+    /// The region this snapshot describes.
+    pub region: Region,
+    /// How many bytes the region has room for in total.
+    pub capacity: usize,
+    /// Bytes held by pages which are in use.
+    pub used_bytes: usize,
+    /// Bytes in free pages plus the unclaimed region at the top.
+    pub free_bytes: usize,
+    /// The biggest payload that could be allocated right now.
+    pub largest_free_block: usize,
+    /// The most bytes that have ever been in use at once.
+    pub high_water_mark: usize,
+    /// How many pages have been handed out since boot.
+    pub alloc_count: usize,
+    /// How many pages have been released since boot.
+    pub free_count: usize,
+    /// How many free pages sit between used ones.
+    pub free_pages: usize,
+    /// True if the region has ever run out of fresh memory.
+    pub overrun: bool,
+    /// Usage per scope, in the order the scopes were found.
+    pub scopes: [ScopeStats; HEAP_STATS_SCOPES],
+    /// How many entries in `scopes` are populated.
+    pub scope_count: usize,
+    /// Usage by any scopes which did not fit in `scopes`.
+    pub other_scopes: ScopeStats,
 }
 
-/// Free a pointer by updating the pagefile, allowing
-/// other alloc() requests to begin reusing that space.
-#[cfg(not(feature = "testing"))]
-pub fn free<T>(ptr: *mut T) {
-    let zero_ptr = ptr as u32;
-    Mempage::free(zero_ptr);
+/// Walk every page in the OCRAM2 heap and return a snapshot
+/// of how it is being used.
+pub fn heap_stats() -> HeapStats {
+    return heap_stats_in(Region::Ocram2);
 }
 
-#[cfg(not(feature = "testing"))]
-#[macro_export]
+/// Walk every page in a particular region and return a
+/// snapshot of how it is being used.
+pub fn heap_stats_in(region: Region) -> HeapStats {
+    let heap = heap(region);
+    let mut stats = HeapStats {
+        region: region,
+        capacity: 0,
+        used_bytes: 0,
+        free_bytes: 0,
+        largest_free_block: 0,
+        high_water_mark: 0,
+        alloc_count: 0,
+        free_count: 0,
+        free_pages: 0,
+        overrun: false,
+        scopes: [ScopeStats::new(0); HEAP_STATS_SCOPES],
+        scope_count: 0,
+        other_scopes: ScopeStats::new(0),
+    };
 
-/// A directive for managing memory.
-///
-/// Any memory that is dynamically allocated within the critical block
-/// of this macro will be released at the end. This is extremely useful
-/// for string-based operations which may have many side effects. You
-/// don't have to fuss with drop().
-///
-/// ```no-test
-/// use teensycore::*;
-/// use teensycore::mem::*;
-///
-/// using!({
-///     let var1 = str!(b"hello!");
-///     let var2 = str!(b"world!");
-/// });
-/// ```
-macro_rules! using {
-    ($x: block) => {
-        {
-            // Record the original scope that memory is currently being allocated against
-            // and then establish a new scope based on the line of code currently
-            // executing. With this scope, all subsequent memory will be allocated against.
-            // After executing the critical block, release all memory allocated recently
-            // and return the scope to the original.
-            let original_scope: ScopeUnit = unsafe { MEMORY_SCOPE.clone() };
-            let current_scope: ScopeUnit = crate::code_hash();
-            unsafe { MEMORY_SCOPE = current_scope };
+    unsafe {
+        stats.capacity = (*heap).end - (*heap).start;
 
-            $x
+        // The unclaimed region also has to fit a header and footer
+        let unclaimed = (*heap).end - (*heap).top;
+        stats.free_bytes = unclaimed;
+        if unclaimed > PAGE_BYTES + FOOTER_BYTES {
+            stats.largest_free_block = unclaimed - PAGE_BYTES - FOOTER_BYTES;
+        }
+
+        stats.high_water_mark = (*heap).high_water_mark;
+        stats.alloc_count = (*heap).alloc_count;
+        stats.free_count = (*heap).free_count;
+        stats.overrun = (*heap).overrun;
 
-            // Deallocate all memory in the current_scope
-            Mempage::free_scope(current_scope);
-            unsafe { MEMORY_SCOPE = original_scope; }
+        let mut ptr = (*heap).pages;
+        while ptr.is_some() {
+            let node = ptr.unwrap();
+            let size = (*node).size;
+
+            if (*node).used {
+                stats.used_bytes += size;
+
+                // Find (or start) the entry for this scope
+                let mut entry = &mut stats.other_scopes;
+                for index in 0..HEAP_STATS_SCOPES {
+                    if index == stats.scope_count {
+                        stats.scopes[index] = ScopeStats::new((*node).scope);
+                        stats.scope_count += 1;
+                    }
+
+                    if stats.scopes[index].scope == (*node).scope {
+                        entry = &mut stats.scopes[index];
+                        break;
+                    }
+                }
+
+                entry.pages += 1;
+                entry.bytes += size;
+            } else {
+                stats.free_pages += 1;
+                stats.free_bytes += size;
+                if size - PAGE_BYTES - FOOTER_BYTES > stats.largest_free_block {
+                    stats.largest_free_block = size - PAGE_BYTES - FOOTER_BYTES;
+                }
+            }
+
+            ptr = (*node).next;
         }
     }
-}
 
-#[cfg(not(feature = "testing"))]
-pub fn ref_count() -> usize {
-    return Mempage::ref_count();
+    return stats;
 }
 
-#[cfg(feature = "testing")]
-pub fn ref_count() -> usize {
-    return 0;
-}
+/// Format a heap snapshot as a compact, human readable report.
+/// Each chunk of output is handed to `write` as it is produced,
+/// so nothing is allocated from the heap being reported on.
+///
+/// ```text
+/// heap ocram2 used=1024 free=519148 largest=519120 peak=2048
+/// allocs=12 frees=4 holes=1
+/// scope 0x1337 pages=3 bytes=512
+/// ```
+pub fn heap_report<F: FnMut(&[u8])>(stats: &HeapStats, mut write: F) {
+    write(b"heap ");
+    write(stats.region.name());
+    write(b" used=");
+    write_num(&mut write, stats.used_bytes as u64, 10);
+    write(b" free=");
+    write_num(&mut write, stats.free_bytes as u64, 10);
+    write(b" largest=");
+    write_num(&mut write, stats.largest_free_block as u64, 10);
+    write(b" peak=");
+    write_num(&mut write, stats.high_water_mark as u64, 10);
+    if stats.overrun {
+        write(b" OVERRUN");
+    }
+    write(b"\nallocs=");
+    write_num(&mut write, stats.alloc_count as u64, 10);
+    write(b" frees=");
+    write_num(&mut write, stats.free_count as u64, 10);
+    write(b" holes=");
+    write_num(&mut write, stats.free_pages as u64, 10);
+    write(b"\n");
 
-#[cfg(feature = "testing")]
-#[macro_export]
-macro_rules! using {
-    ($x: block) => {{
-        $x
-    }};
+    for index in 0..stats.scope_count {
+        write(b"scope 0x");
+        write_num(&mut write, stats.scopes[index].scope as u64, 16);
+        write_scope(&mut write, &stats.scopes[index]);
+    }
+
+    if stats.other_scopes.pages > 0 {
+        write(b"other");
+        write_scope(&mut write, &stats.other_scopes);
+    }
 }
 
-#[cfg(feature = "testing")]
-pub fn alloc<T>() -> *mut T {
-    return unsafe { std::alloc::alloc(std::alloc::Layout::new::<T>()) as *mut T };
+/// Take a snapshot of every region and print the report over
+/// serial and usb serial. Useful for spotting a missing
+/// `drop()` before it takes the whole device down.
+pub fn heap_dump() {
+    for region in REGIONS {
+        // The snapshot is taken up front, so any memory the
+        // serial drivers need while printing isn't part of it.
+        let stats = heap_stats_in(region);
+        if stats.capacity > 0 {
+            heap_report(&stats, |bytes| crate::debug::print(bytes));
+        }
+    }
 }
 
-#[cfg(feature = "testing")]
-pub fn free<T>(_ptr: *mut T) {
-    // Do nothing
+fn write_scope<F: FnMut(&[u8])>(write: &mut F, entry: &ScopeStats) {
+    write(b" pages=");
+    write_num(write, entry.pages as u64, 10);
+    write(b" bytes=");
+    write_num(write, entry.bytes as u64, 10);
+    write(b"\n");
 }
 
-#[cfg(feature = "testing")]
-pub fn zero(addr: u32, bytes: u32) {}
+/// Write a number out in a particular radix without
+/// touching the heap.
+pub(crate) fn write_num<F: FnMut(&[u8])>(write: &mut F, number: u64, radix: u64) {
+    let mut digits = [0u8; 20];
+    let mut index = digits.len();
+    let mut value = number;
 
-#[cfg(feature = "testing")]
+    loop {
+        index -= 1;
+        digits[index] = crate::math::int_to_hex((value % radix) as u8);
+        value = value / radix;
+        if value == 0 {
+            break;
+        }
+    }
+
+    write(&digits[index..]);
+}
+
+/// Returns the last fault which was found in the heap, if any.
+pub fn heap_fault() -> Option<HeapFault> {
+    return unsafe { HEAP_FAULT };
+}
+
+/// Walk every heap and validate every page header, footer
+/// and free list. Returns the first problem it finds.
+pub fn heap_verify() -> Result<(), HeapFault> {
+    for region in REGIONS {
+        match heap_verify_in(region) {
+            Ok(()) => {}
+            Err(fault) => {
+                return Err(fault);
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+/// Validate the heap in a single region.
+pub fn heap_verify_in(region: Region) -> Result<(), HeapFault> {
+    let heap = heap(region);
+    unsafe {
+        let bottom = (*heap).start;
+        let mut expected_end = (*heap).top;
+        let mut free_pages = 0;
+
+        let mut ptr = (*heap).pages;
+        while ptr.is_some() {
+            let node = ptr.unwrap();
+            let addr = node as usize;
+
+            // Don't follow a pointer anywhere it couldn't possibly be
+            if addr < bottom || addr + PAGE_BYTES + FOOTER_BYTES > expected_end || addr % MEMORY_ALIGNMENT != 0 {
+                return Err(HeapFault::ChainBroken);
+            } else if (*node).guard != HEADER_CANARY {
+                return Err(HeapFault::HeaderCorrupt);
+            } else if addr + (*node).size != expected_end {
+                return Err(HeapFault::ChainBroken);
+            } else if (*node).ptr as usize != addr + PAGE_BYTES {
+                return Err(HeapFault::HeaderCorrupt);
+            } else if footer(node) != FOOTER_CANARY {
+                return Err(HeapFault::FooterCorrupt);
+            }
+
+            if (*node).used == false {
+                free_pages += 1;
+            }
+
+            expected_end = addr;
+            ptr = (*node).next;
+        }
+
+        // Every free page should be on the free list exactly once
+        let mut listed = 0;
+        let mut prev = None;
+        let mut ptr = (*heap).free_pages;
+        while ptr.is_some() {
+            let node = ptr.unwrap();
+            if listed == free_pages || (*node).guard != HEADER_CANARY || (*node).used || (*links(node)).prev != prev {
+                return Err(HeapFault::FreeListBroken);
+            }
+
+            listed += 1;
+            prev = ptr;
+            ptr = (*links(node)).next;
+        }
+
+        if listed != free_pages {
+            return Err(HeapFault::FreeListBroken);
+        }
+    }
+
+    return Ok(());
+}
+
+/// Validate the entire heap, raising `PanicType::HeapCorruption`
+/// if anything is wrong with it.
+pub fn heap_check() {
+    match heap_verify() {
+        Ok(()) => {}
+        Err(fault) => {
+            raise_heap_fault(fault);
+        }
+    }
+}
+
+/// Record what went wrong and enter the kernel panic.
+fn raise_heap_fault(fault: HeapFault) {
+    unsafe {
+        HEAP_FAULT = Some(fault);
+    }
+    crate::err(crate::PanicType::HeapCorruption);
+}
+
+/// Check that the stack hasn't grown into the guard words
+/// at its bottom, raising `PanicType::StackOverflow` if it has.
+/// Does nothing until `stack_paint()` has been called.
+pub fn stack_check() {
+    if stack_intact() == false {
+        crate::err(crate::PanicType::StackOverflow);
+    }
+}
+
+/// Returns false if anything has written over the guard
+/// words which sit at the very bottom of the stack.
+pub fn stack_intact() -> bool {
+    if unsafe { STACK_PAINTED } == false {
+        return true;
+    }
+
+    let (bottom, _) = stack_bounds();
+    let mut addr = bottom - STACK_GUARD_BYTES;
+    while addr < bottom {
+        if unsafe { *(addr as *const u32) } != STACK_GUARD {
+            return false;
+        }
+        addr += size_of::<u32>();
+    }
+
+    return true;
+}
+
+/// Fill every unused byte of the stack with a known pattern
+/// so `stack_high_water_mark()` can tell how deep it has ever
+/// gone, and arm the guard words underneath it.
+///
+/// The `main!` macro does this before anything else runs.
+pub fn stack_paint() {
+    let (bottom, _) = stack_bounds();
+
+    // Leave some headroom for whatever this function
+    // itself has on the stack.
+    let limit = stack_pointer() - STACK_PAINT_HEADROOM;
+
+    unsafe {
+        let mut addr = bottom - STACK_GUARD_BYTES;
+        while addr < bottom {
+            *(addr as *mut u32) = STACK_GUARD;
+            addr += size_of::<u32>();
+        }
+
+        while addr < limit {
+            *(addr as *mut u32) = STACK_PAINT;
+            addr += size_of::<u32>();
+        }
+
+        STACK_PAINTED = true;
+    }
+}
+
+/// Returns the first and last (exclusive) address of the
+/// guard words which sit directly beneath the stack.
+pub fn stack_guard() -> (usize, usize) {
+    let (bottom, _) = stack_bounds();
+    return (bottom - STACK_GUARD_BYTES, bottom);
+}
+
+/// Returns the total size of the stack in bytes.
+pub fn stack_size() -> usize {
+    let (bottom, top) = stack_bounds();
+    return top - bottom;
+}
+
+/// Returns the most bytes of stack that have ever been in
+/// use at once, across the main loop and every interrupt.
+/// Until the stack has been painted, this is the whole stack.
+pub fn stack_high_water_mark() -> usize {
+    if unsafe { STACK_PAINTED } == false {
+        return stack_size();
+    }
+
+    // The stack grows down, so the first word which no longer
+    // holds the paint is the deepest it has ever been.
+    let (bottom, top) = stack_bounds();
+    let mut addr = bottom;
+    while addr < top && unsafe { *(addr as *const u32) } == STACK_PAINT {
+        addr += size_of::<u32>();
+    }
+
+    return top - addr;
+}
+
+/// Returns how many bytes of stack have never been touched.
+pub fn stack_free() -> usize {
+    return stack_size() - stack_high_water_mark();
+}
+
+/// Check the stack guard periodically from the SysTick
+/// exception, every `interval_ms` milliseconds.
+///
+/// Once `thread::thread_init()` has been called, the scheduler
+/// owns SysTick and checks the stack on every tick itself. This
+/// then does nothing and returns false. It also returns false if
+/// SysTick can't count as far as `interval_ms`.
+///
+/// ```no_run
+/// use teensycore::mem::*;
+/// stack_watch(10);
+/// ```
+#[cfg(not(feature = "testing"))]
+pub fn stack_watch(interval_ms: u32) -> bool {
+    if crate::thread::thread_running() {
+        return false;
+    }
+
+    crate::phys::irq::systick_attach(stack_check);
+    return crate::phys::irq::systick_start(interval_ms);
+}
+
+/// A method to zero out every piece of memory in the OCRAM2 heap.
+/// If we encounter a bad sector, the device will throw an oob
+/// irq and enter error mode.
+#[cfg(not(feature = "testing"))]
+pub fn memtest() {
+    let heap = heap(Region::Ocram2);
+    unsafe {
+        let mut addr = (*heap).start;
+        while addr < (*heap).end {
+            let ptr = addr as *mut u32;
+            *ptr = 0;
+            addr += 4;
+        }
+    }
+}
+
+/// This method will zero out a certain amount of bytes
+/// from a particular address.
+#[cfg(not(feature = "testing"))]
+pub fn zero(addr: u32, bytes: u32) {
+    for byte in 0..bytes {
+        unsafe {
+            let ptr = (addr + byte) as *mut u8;
+            *ptr = 0;
+        }
+    }
+}
+
+/// This method will copy a certain amount of bytes
+/// from src and into dest.
+#[cfg(not(feature = "testing"))]
+pub fn copy(src: u32, dest: u32, len: u32) {
+    for byte in 0..len {
+        unsafe {
+            let src_ptr = (src + byte) as *mut u8;
+            let dst_ptr = (dest + byte) as *mut u8;
+            *dst_ptr = *src_ptr;
+        }
+    }
+}
+
+/// Round `value` up to the next multiple of `align`.
+/// `align` must be a power of two.
+fn align_up(value: usize, align: usize) -> usize {
+    return (value + align - 1) & !(align - 1);
+}
+
+/// Returns the heap for a region, working out where
+/// it lives the first time it is asked for.
+fn heap(region: Region) -> *mut Heap {
+    unsafe {
+        let heap = addr_of_mut!(HEAPS[region as usize]);
+        if (*heap).ready == false {
+            let (start, end) = region_bounds(region);
+            let start = align_up(start, MEMORY_ALIGNMENT);
+            let end = crate::math::max(end & !(MEMORY_ALIGNMENT - 1), start);
+
+            (*heap).start = start;
+            (*heap).end = end;
+            (*heap).top = start;
+            (*heap).ready = true;
+        }
+        return heap;
+    }
+}
+
+/// Returns the heap which a pointer falls inside of.
+fn heap_of(ptr: usize) -> Option<*mut Heap> {
+    for region in REGIONS {
+        let heap = heap(region);
+        if ptr >= unsafe { (*heap).start } && ptr < unsafe { (*heap).top } {
+            return Some(heap);
+        }
+    }
+    return None;
+}
+
+/// Returns the first and last (exclusive) address
+/// of the memory set aside for a region.
+#[cfg(not(feature = "testing"))]
+fn region_bounds(region: Region) -> (usize, usize) {
+    unsafe {
+        let heap_end = addr_of!(_heap_end) as usize;
+        return match region {
+            Region::Dtcm => {
+                let start = addr_of!(DTCM_HEAP) as usize;
+                (start, start + DTCM_HEAP_BYTES)
+            }
+            Region::Itcm => {
+                // ITCM starts at 0x0, so the banks given to
+                // it end at their combined size.
+                let banks = addr_of!(_itcm_block_count) as usize;
+                (addr_of!(_etext) as usize, banks * FLEXRAM_BANK_BYTES)
+            }
+            Region::Ocram1 => (OCRAM as usize, OCRAM as usize + flexram_ocram_banks() * FLEXRAM_BANK_BYTES),
+            Region::Ocram2 => (addr_of!(_heap_start) as usize, heap_end - DMA_SAFE_BYTES),
+            Region::DmaSafe => (heap_end - DMA_SAFE_BYTES, heap_end),
+        };
+    }
+}
+
+/// Count how many FlexRAM banks are configured as OCRAM.
+#[cfg(not(feature = "testing"))]
+fn flexram_ocram_banks() -> usize {
+    let config = crate::phys::read_word(crate::phys::addrs::IOMUXC_GPR_GPR17);
+    let mut banks = 0;
+    for bank in 0..16 {
+        if (config >> (bank * 2)) & 0x3 == 0x1 {
+            banks += 1;
+        }
+    }
+    return banks;
+}
+
+/// Returns the bottom and top of the stack. The guard
+/// words sit directly beneath the bottom.
+#[cfg(not(feature = "testing"))]
+fn stack_bounds() -> (usize, usize) {
+    return unsafe { (addr_of!(_ebss) as usize, addr_of!(_estack) as usize) };
+}
+
+/// Returns the current stack pointer.
+#[cfg(not(feature = "testing"))]
+fn stack_pointer() -> usize {
+    let sp: usize;
+    unsafe {
+        core::arch::asm!("mov {}, sp", out(reg) sp);
+    }
+    return sp;
+}
+
+/// Returns the bottom and top of the stack. On the host
+/// this is a per-thread block of memory which is never
+/// actually used as a stack.
+#[cfg(feature = "testing")]
+fn stack_bounds() -> (usize, usize) {
+    unsafe {
+        if HOST_STACK == 0 {
+            let layout = std::alloc::Layout::from_size_align(HOST_STACK_BYTES, 4096).unwrap();
+            HOST_STACK = std::alloc::alloc_zeroed(layout) as usize;
+        }
+        return (HOST_STACK + STACK_GUARD_BYTES, HOST_STACK + HOST_STACK_BYTES);
+    }
+}
+
+/// Nothing runs on the host stack, so it may as well be empty.
+#[cfg(feature = "testing")]
+fn stack_pointer() -> usize {
+    let (_, top) = stack_bounds();
+    return top + STACK_PAINT_HEADROOM;
+}
+
+/// Returns the first and last (exclusive) address of the
+/// memory set aside for a region. On the host each region
+/// is a per-thread block of memory.
+#[cfg(feature = "testing")]
+fn region_bounds(region: Region) -> (usize, usize) {
+    let bytes = match region {
+        Region::Dtcm => DTCM_HEAP_BYTES,
+        Region::Itcm => FLEXRAM_BANK_BYTES,
+        Region::Ocram1 => FLEXRAM_BANK_BYTES * 2,
+        Region::Ocram2 => 0x8_0000 - DMA_SAFE_BYTES - 0x1000,
+        Region::DmaSafe => DMA_SAFE_BYTES,
+    };
+
+    unsafe {
+        let index = region as usize;
+        if HOST_REGIONS[index] == 0 {
+            let layout = std::alloc::Layout::from_size_align(bytes, 4096).unwrap();
+            HOST_REGIONS[index] = std::alloc::alloc_zeroed(layout) as usize;
+        }
+        return (HOST_REGIONS[index], HOST_REGIONS[index] + bytes);
+    }
+}
+
+/// Count the pages in use within a region, optionally
+/// only those recorded against a particular scope.
+fn count_pages(region: Region, scope: Option<ScopeUnit>) -> usize {
+    let mut count = 0;
+    unsafe {
+        let mut ptr = (*heap(region)).pages;
+        while ptr.is_some() {
+            let node = ptr.unwrap();
+            if (*node).used == true && (scope.is_none() || scope == Some((*node).scope)) {
+                count += 1;
+            }
+            ptr = (*node).next;
+        }
+    }
+
+    return count;
+}
+
+/// Internal use only.
+///
+/// This method will claim enough fresh bytes at the top of
+/// the heap for a page header followed by a payload aligned to
+/// `align`. It returns the new page, or None if the heap has
+/// been exhausted.
+fn alloc_bytes(heap: *mut Heap, bytes: usize, align: usize) -> Option<*mut Mempage> {
+    unsafe {
+        let top = (*heap).top;
+        let item = align_up(top + PAGE_BYTES, align);
+        let header = item - PAGE_BYTES;
+        let end = item + bytes;
+
+        // Check for boundaries and reset if applicable.
+        if end > (*heap).end {
+            (*heap).overrun = true;
+            return None;
+        }
+
+        // Pages must stay back-to-back, so any padding in front of
+        // the header either becomes a free page of its own or is
+        // absorbed by the page beneath it.
+        let padding = header - top;
+        if padding >= MIN_PAGE_BYTES {
+            let gap = write_page(top, padding, false, (*heap).pages);
+            (*heap).pages = Some(gap);
+            free_list_push(heap, gap);
+        } else if padding > 0 {
+            match (*heap).pages {
+                None => {}
+                Some(head) => {
+                    set_size(head, (*head).size + padding);
+                    if (*head).used {
+                        (*heap).used_bytes += padding;
+                    }
+                }
+            }
+        }
+
+        let page = write_page(header, end - header, true, (*heap).pages);
+        (*heap).pages = Some(page);
+        (*heap).top = end;
+        return Some(page);
+    }
+}
+
+/// Write a fresh page header (and footer) at a particular address.
+unsafe fn write_page(addr: usize, size: usize, used: bool, next: Option<*mut Mempage>) -> *mut Mempage {
+    let page = addr as *mut Mempage;
+    (*page) = Mempage {
+        guard: HEADER_CANARY,
+        size: size,
+        scope: MEMORY_SCOPE,
+        used: used,
+        next: next,
+        ptr: (addr + PAGE_BYTES) as *mut u32,
+    };
+    set_size(page, size);
+    return page;
+}
+
+/// Resize a page and move its footer canary to the new end.
+unsafe fn set_size(page: *mut Mempage, size: usize) {
+    (*page).size = size;
+    *((page as usize + size - size_of::<u32>()) as *mut u32) = FOOTER_CANARY;
+}
+
+/// Returns the footer canary of a page.
+unsafe fn footer(page: *mut Mempage) -> u32 {
+    return *((page as usize + (*page).size - size_of::<u32>()) as *const u32);
+}
+
+/// Point the page sitting on top of another one (or the top
+/// of the heap, if there is none) at a new page beneath it.
+unsafe fn set_below(heap: *mut Heap, above: Option<*mut Mempage>, page: Option<*mut Mempage>) {
+    match above {
+        None => {
+            (*heap).pages = page;
+        }
+        Some(node) => {
+            (*node).next = page;
+        }
+    }
+}
+
+/// Returns the free list links stored in a free page.
+unsafe fn links(page: *mut Mempage) -> *mut FreeLinks {
+    return (*page).ptr as *mut FreeLinks;
+}
+
+unsafe fn free_list_push(heap: *mut Heap, page: *mut Mempage) {
+    (*links(page)) = FreeLinks {
+        prev: None,
+        next: (*heap).free_pages,
+    };
+
+    match (*heap).free_pages {
+        None => {}
+        Some(head) => {
+            (*links(head)).prev = Some(page);
+        }
+    }
+
+    (*heap).free_pages = Some(page);
+}
+
+unsafe fn free_list_remove(heap: *mut Heap, page: *mut Mempage) {
+    let prev = (*links(page)).prev;
+    let next = (*links(page)).next;
+
+    match prev {
+        None => {
+            (*heap).free_pages = next;
+        }
+        Some(node) => {
+            (*links(node)).next = next;
+        }
+    }
+
+    match next {
+        None => {}
+        Some(node) => {
+            (*links(node)).prev = prev;
+        }
+    }
+}
+
+/// This method will allocate a page of memory
+/// of any discreet struct. It then returns
+/// a raw pointer<T> to the location it just
+/// established.
+pub fn alloc<T>() -> *mut T {
+    let bytes = size_of::<T>();
+    return Mempage::add_page(bytes);
+}
+
+/// The same as `alloc()` but the memory comes from
+/// a particular region.
+///
+/// ```no_run
+/// use teensycore::mem::*;
+///
+/// let queue = alloc_in::<[u8; 64]>(Region::Dtcm);
+/// free(queue);
+/// ```
+pub fn alloc_in<T>(region: Region) -> *mut T {
+    return Mempage::add_page_to(region, size_of::<T>(), align_of::<T>(), unsafe { MEMORY_SCOPE });
+}
+
+/// Free a pointer by updating the pagefile, allowing
+/// other alloc() requests to begin reusing that space.
+/// This works for memory from any region.
+pub fn free<T>(ptr: *mut T) {
+    let zero_ptr = ptr as usize;
+    Mempage::free(zero_ptr);
+}
+
+#[macro_export]
+
+/// A directive for managing memory.
+///
+/// Any memory that is dynamically allocated within the critical block
+/// of this macro will be released at the end. This is extremely useful
+/// for string-based operations which may have many side effects. You
+/// don't have to fuss with drop().
+///
+/// ```no-test
+/// use teensycore::*;
+/// use teensycore::mem::*;
+///
+/// using!({
+///     let var1 = str!(b"hello!");
+///     let var2 = str!(b"world!");
+/// });
+/// ```
+///
+/// An existing `Arena` can be given as well. Memory allocated within
+/// the block then lands in that arena, and is only released when the
+/// arena itself is.
+///
+/// ```no-test
+/// let arena = Arena::new();
+/// using!(arena, {
+///     let var1 = str!(b"kept until arena.release()");
+/// });
+/// ```
+macro_rules! using {
+    ($x: block) => {
+        {
+            // Every block gets a fresh arena to allocate against.
+            // After executing the critical block, release all memory
+            // allocated in the arena and return the scope to the original.
+            let arena = $crate::mem::Arena::new();
+            let original_scope: $crate::mem::ScopeUnit = arena.enter();
+
+            $x
+
+            // Deallocate all memory in the arena
+            arena.release();
+            unsafe { $crate::mem::MEMORY_SCOPE = original_scope; }
+        }
+    };
+    ($arena: expr, $x: block) => {
+        {
+            let original_scope: $crate::mem::ScopeUnit = $crate::mem::Arena::enter(&$arena);
+
+            $x
+
+            unsafe { $crate::mem::MEMORY_SCOPE = original_scope; }
+        }
+    };
+}
+
+pub fn ref_count() -> usize {
+    return Mempage::ref_count();
+}
+
+#[cfg(feature = "testing")]
+pub fn zero(addr: u32, bytes: u32) {}
+
+#[cfg(feature = "testing")]
 pub fn copy(src: u32, dest: u32, len: u32) {}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_global_alloc_alignment() {
+        let allocator = MempageAllocator;
+        for align in [1usize, 2, 4, 8, 16, 64, 256, 4096] {
+            let layout = Layout::from_size_align(24, align).unwrap();
+            let ptr = unsafe { allocator.alloc(layout) };
+            assert_eq!(ptr as usize % align, 0);
+            assert_eq!(ptr as usize % MEMORY_ALIGNMENT, 0);
+
+            // The header must sit directly in front of the payload
+            let page = (ptr as usize - size_of::<Mempage>()) as *mut Mempage;
+            unsafe {
+                assert_eq!((*page).ptr as usize, ptr as usize);
+                assert_eq!((*page).scope, GLOBAL_ALLOC_SCOPE);
+                assert_eq!((*page).used, true);
+            }
+        }
+    }
+
+    #[test]
+    fn test_global_alloc_dealloc() {
+        let allocator = MempageAllocator;
+        let layout = Layout::new::<[u64; 4]>();
+        let before = Mempage::ref_count();
+
+        let first = unsafe { allocator.alloc(layout) } as *mut [u64; 4];
+        let second = unsafe { allocator.alloc(layout) } as *mut [u64; 4];
+        unsafe {
+            *first = [1, 2, 3, 4];
+            *second = [5, 6, 7, 8];
+            assert_eq!(*first, [1, 2, 3, 4]);
+        }
+        assert_eq!(Mempage::ref_count(), before + 2);
+
+        unsafe { allocator.dealloc(first as *mut u8, layout) };
+        assert_eq!(Mempage::ref_count(), before + 1);
+        assert_eq!(unsafe { *second }, [5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_global_alloc_exhausted() {
+        let allocator = MempageAllocator;
+        let layout = Layout::from_size_align(0x10_0000, 4).unwrap();
+        let before = Mempage::ref_count();
+
+        assert!(unsafe { allocator.alloc(layout) }.is_null());
+        assert_eq!(Mempage::ref_count(), before);
+        assert_eq!(heap_verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_using_never_frees_global_alloc() {
+        let allocator = MempageAllocator;
+        let layout = Layout::new::<u32>();
+        let ptr = unsafe { allocator.alloc(layout) };
+        let before = Mempage::ref_count();
+
+        Mempage::free_scope(unsafe { MEMORY_SCOPE });
+        assert_eq!(Mempage::ref_count(), before);
+
+        unsafe { allocator.dealloc(ptr, layout) };
+        assert_eq!(Mempage::ref_count(), before - 1);
+    }
+
+    /// The first unclaimed byte of the OCRAM2 heap.
+    fn top() -> usize {
+        return unsafe { (*heap(Region::Ocram2)).top };
+    }
+
+    /// Walk the heap and verify every page sits directly on
+    /// top of the one beneath it.
+    fn assert_contiguous() {
+        assert_eq!(heap_verify(), Ok(()));
+        unsafe {
+            let mut ptr = (*heap(Region::Ocram2)).pages;
+            if ptr.is_some() {
+                let head = ptr.unwrap();
+                assert_eq!(head as usize + (*head).size, (*heap(Region::Ocram2)).top);
+            }
+
+            while ptr.is_some() {
+                let node = ptr.unwrap();
+                match (*node).next {
+                    None => {}
+                    Some(below) => {
+                        assert_eq!(below as usize + (*below).size, node as usize);
+                        assert!((*below).used || (*node).used, "two free pages were left side by side");
+                    }
+                }
+                ptr = (*node).next;
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_oversized_page() {
+        let big = Mempage::add_page::<u8>(512);
+        let guard = Mempage::add_page::<u32>(4);
+        Mempage::free(big as usize);
+
+        let offset = top();
+        let small = Mempage::add_page::<u32>(16);
+        let small2 = Mempage::add_page::<u32>(16);
+
+        // Both come out of the old page rather than fresh memory
+        assert_eq!(small as usize, big as usize);
+        assert!((small2 as usize) > (small as usize) && (small2 as usize) < (guard as usize));
+        assert_eq!(top(), offset);
+        assert_contiguous();
+    }
+
+    #[test]
+    fn test_coalesce_neighbours() {
+        let a = Mempage::add_page::<u8>(64);
+        let b = Mempage::add_page::<u8>(64);
+        let c = Mempage::add_page::<u8>(64);
+        let _guard = Mempage::add_page::<u8>(64);
+
+        Mempage::free(a as usize);
+        Mempage::free(c as usize);
+        assert_contiguous();
+        Mempage::free(b as usize);
+        assert_contiguous();
+
+        // a, b and c are now a single page large enough for all three
+        let offset = top();
+        let combined = Mempage::add_page::<u8>(64 * 3 + PAGE_BYTES * 2);
+        assert_eq!(combined as usize, a as usize);
+        assert_eq!(top(), offset);
+        assert_contiguous();
+    }
+
+    #[test]
+    fn test_free_top_returns_to_heap() {
+        let _base = Mempage::add_page::<u8>(32);
+        let offset = top();
+
+        let a = Mempage::add_page::<u8>(100);
+        let b = Mempage::add_page::<u8>(100);
+        Mempage::free(a as usize);
+        Mempage::free(b as usize);
+
+        assert_eq!(top(), offset);
+        assert_eq!(unsafe { (*heap(Region::Ocram2)).free_pages }, None);
+        assert_contiguous();
+    }
+
+    #[test]
+    fn test_aligned_reclaim() {
+        let big = Mempage::add_page::<u8>(1024);
+        let _guard = Mempage::add_page::<u8>(8);
+        Mempage::free(big as usize);
+
+        let offset = top();
+        let aligned = Mempage::add_page_aligned::<u8>(64, 256);
+        assert_eq!(aligned as usize % 256, 0);
+        assert!((aligned as usize) >= (big as usize));
+        assert_eq!(top(), offset);
+        assert_contiguous();
+    }
+
+    #[test]
+    fn test_long_running_churn() {
+        let mut live: [usize; 16] = [0; 16];
+        let mut seed: usize = 1337;
+
+        for round in 0..20000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let slot = (seed >> 8) % live.len();
+            if live[slot] != 0 {
+                Mempage::free(live[slot]);
+                live[slot] = 0;
+            } else {
+                let bytes = 8 + (seed >> 16) % 900;
+                live[slot] = Mempage::add_page::<u8>(bytes) as usize;
+            }
+
+            if round % 1000 == 0 {
+                assert_contiguous();
+            }
+        }
+
+        for slot in live {
+            if slot != 0 {
+                Mempage::free(slot);
+            }
+        }
+
+        // Everything was handed back to the unclaimed region
+        assert_eq!(is_overrun(), false);
+        assert_eq!(Mempage::ref_count(), 0);
+        assert_eq!(unsafe { (*heap(Region::Ocram2)).pages }, None);
+        assert_eq!(unsafe { (*heap(Region::Ocram2)).free_pages }, None);
+    }
+
+    #[test]
+    fn test_heap_stats() {
+        // The very first page may skip a few bytes to get aligned
+        let _base = Mempage::add_page::<u8>(8);
+        let before = heap_stats();
+        let a = Mempage::add_page_in::<u8>(96, 4, 0xAA);
+        let b = Mempage::add_page_in::<u8>(200, 4, 0xBB);
+        let c = Mempage::add_page_in::<u8>(304, 4, 0xAA);
+        let _guard = Mempage::add_page_in::<u8>(8, 4, 0xBB);
+        Mempage::free(b as usize);
+
+        let stats = heap_stats();
+        assert_eq!(stats.alloc_count, before.alloc_count + 4);
+        assert_eq!(stats.free_count, before.free_count + 1);
+        assert_eq!(stats.free_pages, 1);
+        assert_eq!(stats.used_bytes + stats.free_bytes, before.used_bytes + before.free_bytes);
+        assert!(stats.high_water_mark >= stats.used_bytes + 200);
+
+        let aa = stats.scopes[..stats.scope_count].iter().find(|entry| entry.scope == 0xAA).unwrap();
+        assert_eq!(aa.pages, 2);
+        assert_eq!(aa.bytes, 96 + 304 + (PAGE_BYTES + FOOTER_BYTES) * 2);
+
+        // The hole left by b is reusable for exactly its own payload
+        let largest = stats.largest_free_block;
+        Mempage::free(a as usize);
+        Mempage::free(c as usize);
+        assert!(heap_stats().largest_free_block >= largest);
+
+        let reused = Mempage::add_page::<u8>(600 + PAGE_BYTES * 2);
+        assert_eq!(reused as usize, a as usize);
+    }
+
+    #[test]
+    fn test_heap_stats_scope_overflow() {
+        for scope in 0..(HEAP_STATS_SCOPES as u32 + 4) {
+            Mempage::add_page_in::<u8>(64, 4, 0x1000 + scope);
+        }
+
+        let stats = heap_stats();
+        assert_eq!(stats.scope_count, HEAP_STATS_SCOPES);
+        assert_eq!(stats.other_scopes.pages, 4);
+        assert_eq!(stats.other_scopes.bytes, 4 * (64 + PAGE_BYTES + FOOTER_BYTES));
+    }
+
+    #[test]
+    fn test_heap_report() {
+        Mempage::add_page_in::<u8>(32, 4, 0xBEEF);
+
+        let mut output = [0u8; 512];
+        let mut len = 0;
+        heap_report(&heap_stats(), |bytes| {
+            output[len..len + bytes.len()].copy_from_slice(bytes);
+            len += bytes.len();
+        });
+
+        let report = core::str::from_utf8(&output[..len]).unwrap();
+        assert!(report.starts_with("heap ocram2 used="));
+        assert!(report.contains("allocs=1 frees=0 holes=0\n"));
+        assert!(report.contains("scope 0xBEEF pages=1 bytes="));
+    }
+
+    /// Run something that should trip a particular heap fault.
+    fn expect_fault<F: FnOnce()>(fault: HeapFault, func: F) {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(func));
+        assert!(result.is_err());
+        assert_eq!(heap_fault(), Some(fault));
+    }
+
+    #[test]
+    fn test_double_free() {
+        let a = Mempage::add_page::<u8>(64);
+        let _guard = Mempage::add_page::<u8>(64);
+        Mempage::free(a as usize);
+        expect_fault(HeapFault::DoubleFree, || Mempage::free(a as usize));
+    }
+
+    #[test]
+    fn test_double_free_after_merge() {
+        let a = Mempage::add_page::<u8>(64);
+        let b = Mempage::add_page::<u8>(64);
+        let _guard = Mempage::add_page::<u8>(64);
+        Mempage::free(a as usize);
+        Mempage::free(b as usize);
+
+        // b no longer has a page of its own, it was merged into a
+        expect_fault(HeapFault::DoubleFree, || Mempage::free(b as usize));
+        assert_eq!(heap_verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_wild_free() {
+        let a = Mempage::add_page::<u64>(64);
+        let local: u64 = 0;
+
+        expect_fault(HeapFault::WildFree, || Mempage::free(&local as *const u64 as usize));
+        expect_fault(HeapFault::WildFree, || Mempage::free(a as usize + 16));
+        expect_fault(HeapFault::WildFree, || Mempage::free(a as usize + 1));
+
+        // None of that should have damaged the heap
+        assert_eq!(heap_verify(), Ok(()));
+        Mempage::free(a as usize);
+    }
+
+    #[test]
+    fn test_overflow_detected() {
+        let a = Mempage::add_page::<u8>(64);
+        let _guard = Mempage::add_page::<u8>(64);
+        unsafe {
+            for index in 0..(64 + FOOTER_BYTES) {
+                *a.add(index) = 0xFF;
+            }
+        }
+
+        assert_eq!(heap_verify(), Err(HeapFault::FooterCorrupt));
+        expect_fault(HeapFault::FooterCorrupt, || heap_check());
+        expect_fault(HeapFault::FooterCorrupt, || Mempage::free(a as usize));
+    }
+
+    #[test]
+    fn test_heap_check_corrupt_header() {
+        let a = Mempage::add_page::<u8>(64);
+        let _guard = Mempage::add_page::<u8>(64);
+        heap_check();
+
+        unsafe {
+            (*((a as usize - PAGE_BYTES) as *mut Mempage)).size += 8;
+        }
+        expect_fault(HeapFault::ChainBroken, || heap_check());
+
+        unsafe {
+            (*((a as usize - PAGE_BYTES) as *mut Mempage)).size -= 8;
+            (*((a as usize - PAGE_BYTES) as *mut Mempage)).guard = 0;
+        }
+        expect_fault(HeapFault::HeaderCorrupt, || heap_check());
+    }
+
+    #[test]
+    fn test_alloc_and_free() {
+        let first = alloc::<[u32; 8]>();
+        unsafe { *first = [7; 8] };
+        assert_eq!(ref_count(), 1);
+
+        free(first);
+        assert_eq!(ref_count(), 0);
+
+        // The same memory is handed straight back out
+        let second = alloc::<[u32; 8]>();
+        assert_eq!(second as usize, first as usize);
+        assert_eq!(heap_verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_using_frees_scope() {
+        let outer = alloc::<u64>();
+
+        using!({
+            let a = alloc::<[u8; 100]>();
+            let b = alloc::<[u8; 200]>();
+            assert!((a as usize) != (b as usize));
+            assert_eq!(ref_count(), 3);
+        });
+
+        assert_eq!(ref_count(), 1);
+        assert_eq!(unsafe { MEMORY_SCOPE }, 0x1337);
+        free(outer);
+        assert_eq!(heap_verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_nested_using() {
+        using!({
+            let outer = alloc::<[u32; 4]>();
+            unsafe { *outer = [1, 2, 3, 4] };
+
+            using!({
+                alloc::<[u8; 64]>();
+                alloc::<[u8; 64]>();
+                assert_eq!(ref_count(), 3);
+            });
+
+            // Only the inner block was released
+            assert_eq!(ref_count(), 1);
+            assert_eq!(unsafe { *outer }, [1, 2, 3, 4]);
+        });
+
+        assert_eq!(ref_count(), 0);
+    }
+
+    #[test]
+    fn test_overrun() {
+        let result = std::panic::catch_unwind(|| loop {
+            alloc::<[u8; 4096]>();
+        });
+
+        assert!(result.is_err());
+        assert_eq!(is_overrun(), true);
+        assert_eq!(heap_verify(), Ok(()));
+    }
+
+    /// A helper with its own `using!` block, to make sure nested
+    /// blocks across function calls don't release each other.
+    fn scratch_work() -> usize {
+        let count;
+        using!({
+            alloc::<[u8; 48]>();
+            count = ref_count();
+        });
+        return count;
+    }
+
+    #[test]
+    fn test_using_across_calls() {
+        using!({
+            let kept = alloc::<u32>();
+            unsafe { *kept = 1337 };
+
+            // The same call site, twice over
+            for _ in 0..2 {
+                assert_eq!(scratch_work(), 2);
+                assert_eq!(ref_count(), 1);
+                assert_eq!(unsafe { *kept }, 1337);
+            }
+        });
+
+        assert_eq!(ref_count(), 0);
+    }
+
+    #[test]
+    fn test_arena() {
+        let first = Arena::new();
+        let second = Arena::new();
+        assert!(first.scope() != second.scope());
+
+        first.alloc::<[u8; 64]>();
+        first.alloc::<[u8; 64]>();
+        let kept = second.alloc::<u64>();
+        unsafe { *kept = 42 };
+        assert_eq!(first.ref_count(), 2);
+        assert_eq!(second.ref_count(), 1);
+
+        first.release();
+        assert_eq!(first.ref_count(), 0);
+        assert_eq!(unsafe { *kept }, 42);
+
+        second.release();
+        assert_eq!(ref_count(), 0);
+        assert_eq!(heap_verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_using_existing_arena() {
+        let arena = Arena::new();
+        using!(arena, {
+            alloc::<[u8; 32]>();
+            alloc::<[u8; 32]>();
+        });
+
+        // Nothing was released when the block ended
+        assert_eq!(arena.ref_count(), 2);
+        assert_eq!(unsafe { MEMORY_SCOPE }, 0x1337);
+
+        arena.release();
+        assert_eq!(ref_count(), 0);
+    }
+
+    #[test]
+    fn test_alloc_in_region() {
+        let dtcm = alloc_in::<[u32; 16]>(Region::Dtcm);
+        let stats = heap_stats_in(Region::Dtcm);
+        let (start, end) = unsafe { ((*heap(Region::Dtcm)).start, (*heap(Region::Dtcm)).end) };
+
+        assert!(dtcm as usize >= start && (dtcm as usize) < end);
+        assert_eq!(stats.capacity, DTCM_HEAP_BYTES);
+        assert_eq!(stats.alloc_count, 1);
+        assert_eq!(stats.used_bytes, 64 + PAGE_BYTES + FOOTER_BYTES);
+
+        // Nothing was taken from the default heap
+        assert_eq!(heap_stats().alloc_count, 0);
+        assert_eq!(ref_count(), 1);
+
+        // Free finds its way back to the right region
+        free(dtcm);
+        assert_eq!(heap_stats_in(Region::Dtcm).used_bytes, 0);
+        assert_eq!(heap_stats_in(Region::Dtcm).free_count, 1);
+        assert_eq!(heap_verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_region_overrun() {
+        let result = std::panic::catch_unwind(|| loop {
+            alloc_in::<[u8; 1024]>(Region::DmaSafe);
+        });
+
+        assert!(result.is_err());
+        assert_eq!(heap_stats_in(Region::DmaSafe).overrun, true);
+        assert_eq!(heap_stats().overrun, false);
+        assert_eq!(is_overrun(), true);
+    }
+
+    #[test]
+    fn test_arena_in_region() {
+        let arena = Arena::new_in(Region::Itcm);
+        let table = arena.alloc::<[u16; 32]>();
+        let other = alloc::<u32>();
+
+        assert_eq!(arena.region(), Region::Itcm);
+        assert_eq!(arena.ref_count(), 1);
+        assert_eq!(heap_stats_in(Region::Itcm).scopes[0].scope, arena.scope());
+        assert!(heap_of(table as usize) != heap_of(other as usize));
+
+        arena.release();
+        assert_eq!(heap_stats_in(Region::Itcm).used_bytes, 0);
+        assert_eq!(ref_count(), 1);
+    }
+
+    #[test]
+    fn test_stack_high_water_mark() {
+        // Nothing is known about an unpainted stack
+        assert_eq!(stack_high_water_mark(), stack_size());
+        assert_eq!(stack_intact(), true);
+
+        stack_paint();
+        assert_eq!(stack_high_water_mark(), 0);
+        assert_eq!(stack_free(), stack_size());
+
+        // Pretend something used the top 100 bytes of the stack
+        let (bottom, top) = stack_bounds();
+        unsafe { *((top - 100) as *mut u32) = 0 };
+        assert_eq!(stack_high_water_mark(), 100);
+        assert_eq!(stack_free(), top - bottom - 100);
+        stack_check();
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        stack_paint();
+        let (bottom, _) = stack_bounds();
+        unsafe { *((bottom - 4) as *mut u32) = 0 };
+
+        assert_eq!(stack_intact(), false);
+        let result = std::panic::catch_unwind(|| stack_check());
+        assert!(result.is_err());
+    }
+}
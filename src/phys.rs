@@ -3,14 +3,20 @@
 
 pub mod addrs;
 pub mod analog;
+pub mod cpu;
 pub mod dma;
+pub mod dwt;
 pub mod gpio;
 pub mod irq;
+pub mod mpu;
 pub mod periodic_timers;
 pub mod pins;
+pub mod power;
+pub mod rtc;
 pub mod timer;
 pub mod uart;
 pub mod usb;
+pub mod watchdog;
 pub mod xbar;
 
 pub enum Bitwise {
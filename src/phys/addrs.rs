@@ -3,8 +3,29 @@
 #![allow(dead_code)]
 pub const NVIC_IRQ_ENABLE_REG: u32 = 0xE000E100;
 pub const NVIC_IRQ_CLEAR_REG: u32 = 0xE000E180;
+pub const NVIC_IRQ_SET_PENDING_REG: u32 = 0xE000E200;
 pub const NVIC_IRQ_CLEAR_PENDING_REG: u32 = 0xE000E280;
 pub const NVIC_IRQ_PRIORITY_REG: u32 = 0xE000E400;
+pub const SYST_CSR: u32 = 0xE000E010; // SysTick control and status
+pub const SYST_RVR: u32 = 0xE000E014; // SysTick reload value
+pub const SYST_CVR: u32 = 0xE000E018; // SysTick current value
+pub const SCB_ICSR: u32 = 0xE000ED04; // Interrupt control and state
+pub const SCB_SCR: u32 = 0xE000ED10; // System control, including SLEEPDEEP
+pub const SCB_SHPR3: u32 = 0xE000ED20; // PendSV and SysTick priority
+pub const SCB_SHCSR: u32 = 0xE000ED24; // System handler control and state
+pub const SCB_CFSR: u32 = 0xE000ED28; // Configurable fault status
+pub const SCB_MMFAR: u32 = 0xE000ED34; // MemManage fault address
+pub const DEMCR: u32 = 0xE000EDFC; // Debug exception and monitor control
+/** Data Watchpoint and Trace */
+pub const DWT_CTRL: u32 = 0xE000_1000;
+pub const DWT_CYCCNT: u32 = 0xE000_1004;
+pub const DWT_LAR: u32 = 0xE000_1FB0;
+/** Memory Protection Unit */
+pub const MPU_TYPE: u32 = 0xE000ED90;
+pub const MPU_CTRL: u32 = 0xE000ED94;
+pub const MPU_RNR: u32 = 0xE000ED98;
+pub const MPU_RBAR: u32 = 0xE000ED9C;
+pub const MPU_RASR: u32 = 0xE000EDA0;
 /** UART */
 pub const UART1: u32 = 0x4018_4000;
 pub const UART2: u32 = 0x4018_8000;
@@ -45,6 +66,8 @@ pub const ADC1_HC7: u32 = 0x400C_401C;
 pub const ADC1_HS: u32 = 0x400C_4020;
 
 /** GPIO General Purpose Registers */
+pub const IOMUXC_GPR_GPR1: u32 = 0x400A_C004; // Includes GINT, which forces IRQ 41 pending
+pub const IOMUXC_GPR_GPR17: u32 = 0x400A_C044; // FlexRAM bank configuration
 pub const IOMUXC_GPR_GPR26: u32 = 0x400A_C068; // GPIO1 and GPIO6 mux settings
 pub const IOMUXC_GPR_GPR27: u32 = 0x400A_C06C; // GPIO2 and GPIO7 mux settings
 pub const IOMUXC_GPR_GPR28: u32 = 0x400A_C070; // GPIO3 and GPIO8 mux settings
@@ -108,9 +131,12 @@ pub const IOMUXC_LPUART8_RX_SELECT_INPUT: u32 = 0x401F_8560;
 pub const CCM_CACRR: u32 = 0x400F_C010;
 pub const CCM_CBCMR: u32 = 0x400F_C018;
 pub const CCM_CBCDR: u32 = 0x400F_C014;
+pub const CCM_CDHIPR: u32 = 0x400F_C048;
+pub const CCM_CLPCR: u32 = 0x400F_C054; // Low power control
 pub const CCM_ANALOG_PLL_ARM: u32 = 0x400D_8000;
 pub const CCM_ANALOG_PLL_ARM_SET: u32 = 0x400D_8004;
 pub const CCM_ANALOG_PLL_ARM_CLR: u32 = 0x400D_8008;
+pub const DCDC: u32 = 0x4008_0000;
 /** DMA */
 pub const DMA: u32 = 0x400E_8000;
 pub const DMAMUX: u32 = 0x400E_C000;
@@ -135,3 +161,11 @@ pub const CCM_CCGR2: u32 = 0x400F_C070;
 pub const CCM_CCGR3: u32 = 0x400F_C074;
 pub const CCM_CCGR4: u32 = 0x400F_C078;
 pub const CCM_CCGR5: u32 = 0x400F_C07C;
+/** Watchdogs */
+pub const WDOG1: u32 = 0x400B_8000;
+pub const WDOG2: u32 = 0x400D_0000;
+pub const RTWDOG: u32 = 0x400B_C000;
+/** General power controller, which decides what wakes the core */
+pub const GPC: u32 = 0x400F_4000;
+/** Secure non-volatile storage, which holds the RTC */
+pub const SNVS: u32 = 0x400D_4000;
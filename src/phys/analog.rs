@@ -10,10 +10,16 @@
 //! ```
 //!
 use crate::assembly;
+use crate::executor::IrqWaker;
 
+use super::irq::{irq_attach, irq_enable, Irq};
 use super::{addrs, assign, assign_bit, read_word, Bitwise};
 
 use core::arch::asm;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{Context, Poll};
 
 pub enum Resolution {
     Bits8 = 0x0,
@@ -21,6 +27,11 @@ pub enum Resolution {
     Bits12 = 0x2,
 }
 
+// There is only one converter, so async reads take turns
+static ANALOG_BUSY: AtomicBool = AtomicBool::new(false);
+static ANALOG_RESULT: AtomicU32 = AtomicU32::new(0);
+static ANALOG_WAKER: IrqWaker = IrqWaker::new();
+
 /** The index is an arduino analog pin (0-9) the value is corresponding to the IOMUX register */
 const ANALOG_PIN_BITS: [u32; 10] = [7, 8, 12, 11, 6, 5, 15, 0, 13, 14];
 
@@ -81,3 +92,82 @@ pub fn analog_read(pin: usize) -> u32 {
     // Transfer data
     return read_word(0x400C_4024);
 }
+
+/// The same as `analog_read` but instead of spinning while
+/// the conversion happens, the task is woken by the ADC
+/// conversion complete interrupt.
+///
+/// ```no_run
+/// use teensycore::executor::*;
+/// use teensycore::phys::analog::*;
+///
+/// spawn(async {
+///     let val = analog_read_async(20).await;
+/// });
+/// ```
+pub fn analog_read_async(pin: usize) -> AnalogRead {
+    return AnalogRead {
+        pin: pin,
+        started: false,
+    };
+}
+
+/// A future which waits for an ADC conversion.
+pub struct AnalogRead {
+    pin: usize,
+    started: bool,
+}
+
+impl Future for AnalogRead {
+    type Output = u32;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+        if self.pin > 23 || self.pin < 14 {
+            // Error condition
+            return Poll::Ready(0);
+        }
+
+        if !self.started {
+            // Wait for any other conversion to finish first
+            if ANALOG_BUSY.swap(true, Ordering::AcqRel) {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+
+            self.started = true;
+            ANALOG_WAKER.take();
+            ANALOG_WAKER.register(cx.waker());
+            irq_attach(Irq::Adc1, analog_handle_irq);
+            irq_enable(Irq::Adc1);
+
+            // Start the conversion with the interrupt enabled
+            assign(addrs::ADC1_HC0, ANALOG_PIN_BITS[self.pin - 14] | (0x1 << 7));
+            return Poll::Pending;
+        }
+
+        ANALOG_WAKER.register(cx.waker());
+        if ANALOG_WAKER.take() {
+            self.started = false;
+            ANALOG_BUSY.store(false, Ordering::Release);
+            return Poll::Ready(ANALOG_RESULT.load(Ordering::Acquire));
+        }
+
+        return Poll::Pending;
+    }
+}
+
+impl Drop for AnalogRead {
+    fn drop(&mut self) {
+        // Let the next read go ahead. Whatever this conversion
+        // produces is thrown away when that read starts.
+        if self.started {
+            ANALOG_BUSY.store(false, Ordering::Release);
+        }
+    }
+}
+
+fn analog_handle_irq() {
+    // Reading the result clears the interrupt
+    ANALOG_RESULT.store(read_word(0x400C_4024), Ordering::Release);
+    ANALOG_WAKER.wake();
+}
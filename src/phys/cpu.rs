@@ -0,0 +1,308 @@
+//! This module changes how fast the core runs.
+//!
+//! The core clock comes from the ARM PLL, which runs somewhere
+//! between 648MHz and 1296MHz and is divided down by the ARM and
+//! AHB dividers. The IPG clock, which the periodic timers count,
+//! is the core clock divided down once more to 150MHz or less.
+//! The DCDC converter raises the core voltage for the fast
+//! speeds and lowers it again for the slow ones.
+//!
+//! ```no_run
+//! use teensycore::phys::cpu::*;
+//!
+//! // Run slow to save the battery
+//! cpu_set_frequency(CPU_24MHZ);
+//!
+//! // And fast when there is work to do
+//! let actual = cpu_set_frequency(CPU_600MHZ);
+//! ```
+//!
+//! Once the new speed is running, everything in the kernel which
+//! depends on it is brought up to date: `nanos()`, `micros()`,
+//! `delay_ns()`, SysTick and the software timers. UART baud rates
+//! don't depend on the core clock at all, so they keep working as
+//! they are. Anything else can ask to be told with `cpu_on_change`.
+//!
+//! Anything above 600MHz is overclocking, and needs cooling.
+use crate::clock::*;
+use crate::phys::irq::*;
+
+pub const CPU_24MHZ: u32 = 24_000_000;
+pub const CPU_150MHZ: u32 = 150_000_000;
+pub const CPU_396MHZ: u32 = 396_000_000;
+pub const CPU_528MHZ: u32 = 528_000_000;
+pub const CPU_600MHZ: u32 = 600_000_000;
+
+pub const MIN_CPU_HZ: u32 = CPU_24MHZ;
+pub const MAX_CPU_HZ: u32 = 720_000_000;
+
+/// The most functions which can ask to be told about a change.
+pub const MAX_LISTENERS: usize = 4;
+
+const PLL_MIN_HZ: u32 = 648_000_000;
+const PLL_STEP_HZ: u32 = 12_000_000;
+const MAX_IPG_HZ: u32 = 150_000_000;
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut LISTENERS: [Option<fn(u32)>; MAX_LISTENERS] = [None; MAX_LISTENERS];
+
+/// Every setting needed to run the core at a particular speed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClockPlan {
+    /// The ARM PLL runs at 12MHz times this.
+    pub pll_mult: u32,
+    pub arm_div: u32,
+    pub ahb_div: u32,
+    pub ipg_div: u32,
+    pub millivolts: u32,
+    /// The speed the core will actually run at, which is
+    /// as close to the request as the dividers allow.
+    pub cpu_hz: u32,
+    pub ipg_hz: u32,
+}
+
+/// Work out how to run the core as close to `hz` as possible.
+pub fn cpu_plan(hz: u32) -> ClockPlan {
+    let hz = crate::math::min(crate::math::max(hz, MIN_CPU_HZ), MAX_CPU_HZ);
+
+    // Divide down from the slowest the PLL can run at
+    let mut arm_div = 1;
+    let mut ahb_div = 1;
+    while hz as u64 * ((arm_div * ahb_div) as u64) < PLL_MIN_HZ as u64 {
+        if arm_div < 8 {
+            arm_div += 1;
+        } else if ahb_div < 5 {
+            ahb_div += 1;
+            arm_div = 1;
+        } else {
+            break;
+        }
+    }
+
+    let pll_hz = hz as u64 * (arm_div * ahb_div) as u64;
+    let pll_mult = ((pll_hz + PLL_STEP_HZ as u64 / 2) / PLL_STEP_HZ as u64) as u32;
+    let pll_mult = crate::math::min(crate::math::max(pll_mult, 54), 108);
+    let cpu_hz = pll_mult * PLL_STEP_HZ / arm_div / ahb_div;
+    let ipg_div = crate::math::min((cpu_hz + MAX_IPG_HZ - 1) / MAX_IPG_HZ, 4);
+
+    let millivolts = if cpu_hz <= CPU_24MHZ {
+        950
+    } else if cpu_hz <= CPU_528MHZ {
+        1150
+    } else if cpu_hz <= CPU_600MHZ {
+        1250
+    } else {
+        1300
+    };
+
+    return ClockPlan {
+        pll_mult: pll_mult,
+        arm_div: arm_div,
+        ahb_div: ahb_div,
+        ipg_div: ipg_div,
+        millivolts: millivolts,
+        cpu_hz: cpu_hz,
+        ipg_hz: cpu_hz / ipg_div,
+    };
+}
+
+/// The speed the core is running at, in HZ.
+pub fn cpu_frequency() -> u32 {
+    return cpu_hz();
+}
+
+/// Run the core as close to `hz` as possible, and
+/// return the speed it actually ended up at.
+pub fn cpu_set_frequency(hz: u32) -> u32 {
+    let plan = cpu_plan(hz);
+    {
+        let _cs = CriticalSection::new();
+        apply(&plan);
+
+        clock_set_cpu_hz(plan.cpu_hz);
+        clock_set_pit_hz(plan.ipg_hz);
+        systick_recalibrate();
+    }
+
+    crate::timers::timers_recalibrate();
+    for index in 0..MAX_LISTENERS {
+        match unsafe { LISTENERS[index] } {
+            None => {}
+            Some(func) => func(plan.cpu_hz),
+        }
+    }
+
+    return plan.cpu_hz;
+}
+
+/// Call `func` with the new speed every time the core clock
+/// changes. Returns false if there is no room for another.
+pub fn cpu_on_change(func: fn(u32)) -> bool {
+    for index in 0..MAX_LISTENERS {
+        unsafe {
+            if LISTENERS[index].is_none() {
+                LISTENERS[index] = Some(func);
+                return true;
+            }
+        }
+    }
+
+    return false;
+}
+
+#[cfg(not(feature = "testing"))]
+mod hardware {
+    use super::*;
+    use crate::phys::{addrs, assign, read_word};
+
+    // CCM_ANALOG_PLL_ARM
+    const PLL_DIV_SELECT: u32 = 0x7F;
+    const PLL_POWERDOWN: u32 = 1 << 12;
+    const PLL_ENABLE: u32 = 1 << 13;
+    const PLL_BYPASS: u32 = 1 << 16;
+    const PLL_LOCK: u32 = 1 << 31;
+
+    // CCM_CBCDR
+    const CBCDR_IPG_PODF: u32 = 0x3 << 8;
+    const CBCDR_AHB_PODF: u32 = 0x7 << 10;
+    const CBCDR_PERIPH_CLK_SEL: u32 = 1 << 25;
+    const CBCDR_PERIPH_CLK2_PODF: u32 = 0x7 << 27;
+
+    // CCM_CBCMR
+    const CBCMR_PERIPH_CLK2_SEL: u32 = 0x3 << 12;
+    const CBCMR_PRE_PERIPH_CLK_SEL: u32 = 0x3 << 18;
+
+    // CCM_CDHIPR
+    const CDHIPR_AHB_PODF_BUSY: u32 = 1 << 1;
+    const CDHIPR_PERIPH_CLK_SEL_BUSY: u32 = 1 << 5;
+    const CDHIPR_ARM_PODF_BUSY: u32 = 1 << 16;
+
+    // DCDC
+    const DCDC_REG0: u32 = 0x00;
+    const DCDC_REG3: u32 = 0x0C;
+    const DCDC_STS_DC_OK: u32 = 1 << 31;
+    const DCDC_TRG: u32 = 0x1F;
+
+    /// The DCDC target field for a core voltage. Each step is 25mV up from 800mV.
+    fn dcdc_target(millivolts: u32) -> u32 {
+        return crate::math::min((millivolts - 800) / 25, DCDC_TRG);
+    }
+
+    pub fn apply(plan: &ClockPlan) {
+        let dcdc = addrs::DCDC + DCDC_REG3;
+        let target = dcdc_target(plan.millivolts);
+
+        // Raise the voltage before speeding up
+        if read_word(dcdc) & DCDC_TRG < target {
+            assign(dcdc, (read_word(dcdc) & !DCDC_TRG) | target);
+            while read_word(addrs::DCDC + DCDC_REG0) & DCDC_STS_DC_OK == 0 {}
+        }
+
+        // Run from the 24MHz oscillator while the PLL changes
+        let mut cbcdr = read_word(addrs::CCM_CBCDR);
+        if cbcdr & CBCDR_PERIPH_CLK_SEL == 0 {
+            assign(
+                addrs::CCM_CBCMR,
+                (read_word(addrs::CCM_CBCMR) & !CBCMR_PERIPH_CLK2_SEL) | (0x1 << 12),
+            );
+            cbcdr = (cbcdr & !CBCDR_PERIPH_CLK2_PODF) | CBCDR_PERIPH_CLK_SEL;
+            assign(addrs::CCM_CBCDR, cbcdr);
+            while read_word(addrs::CCM_CDHIPR) & CDHIPR_PERIPH_CLK_SEL_BUSY > 0 {}
+        }
+
+        let pll_mask = PLL_LOCK | PLL_BYPASS | PLL_ENABLE | PLL_POWERDOWN | PLL_DIV_SELECT;
+        if read_word(addrs::CCM_ANALOG_PLL_ARM) & pll_mask != PLL_LOCK | PLL_ENABLE | plan.pll_mult
+        {
+            assign(addrs::CCM_ANALOG_PLL_ARM, PLL_POWERDOWN);
+            assign(addrs::CCM_ANALOG_PLL_ARM, PLL_ENABLE | plan.pll_mult);
+            while read_word(addrs::CCM_ANALOG_PLL_ARM) & PLL_LOCK == 0 {}
+        }
+
+        if read_word(addrs::CCM_CACRR) & 0x7 != plan.arm_div - 1 {
+            assign(addrs::CCM_CACRR, plan.arm_div - 1);
+            while read_word(addrs::CCM_CDHIPR) & CDHIPR_ARM_PODF_BUSY > 0 {}
+        }
+
+        cbcdr = (cbcdr & !CBCDR_AHB_PODF) | ((plan.ahb_div - 1) << 10);
+        assign(addrs::CCM_CBCDR, cbcdr);
+        while read_word(addrs::CCM_CDHIPR) & CDHIPR_AHB_PODF_BUSY > 0 {}
+
+        cbcdr = (cbcdr & !CBCDR_IPG_PODF) | ((plan.ipg_div - 1) << 8);
+        assign(addrs::CCM_CBCDR, cbcdr);
+
+        // Back onto the ARM PLL
+        assign(
+            addrs::CCM_CBCMR,
+            (read_word(addrs::CCM_CBCMR) & !CBCMR_PRE_PERIPH_CLK_SEL) | (0x3 << 18),
+        );
+        cbcdr &= !CBCDR_PERIPH_CLK_SEL;
+        assign(addrs::CCM_CBCDR, cbcdr);
+        while read_word(addrs::CCM_CDHIPR) & CDHIPR_PERIPH_CLK_SEL_BUSY > 0 {}
+
+        // Lower the voltage after slowing down
+        if read_word(dcdc) & DCDC_TRG > target {
+            assign(dcdc, (read_word(dcdc) & !DCDC_TRG) | target);
+        }
+    }
+}
+
+#[cfg(not(feature = "testing"))]
+use hardware::apply;
+
+/// There are no clocks to change on the host.
+#[cfg(feature = "testing")]
+fn apply(_plan: &ClockPlan) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plans() {
+        // The speed the kernel boots at
+        let plan = cpu_plan(CPU_396MHZ);
+        assert_eq!((plan.pll_mult, plan.arm_div, plan.ahb_div), (66, 2, 1));
+        assert_eq!(plan.ipg_hz, CLOCK_CPU);
+
+        let plan = cpu_plan(CPU_600MHZ);
+        assert_eq!((plan.pll_mult, plan.arm_div, plan.ipg_div), (100, 2, 4));
+        assert_eq!(plan.cpu_hz, CPU_600MHZ);
+        assert_eq!(plan.millivolts, 1250);
+
+        let plan = cpu_plan(CPU_24MHZ);
+        assert_eq!(plan.cpu_hz, CPU_24MHZ);
+        assert_eq!((plan.arm_div, plan.ahb_div), (7, 4));
+        assert_eq!(plan.ipg_div, 1);
+        assert_eq!(plan.millivolts, 950);
+
+        // Not every speed can be hit exactly
+        let plan = cpu_plan(CPU_150MHZ);
+        assert_eq!(plan.cpu_hz, 151_200_000);
+        assert_eq!(plan.ipg_hz, 75_600_000);
+
+        // Requests out of range are clamped
+        assert_eq!(cpu_plan(1_000_000).cpu_hz, MIN_CPU_HZ);
+        assert_eq!(cpu_plan(2_000_000_000).cpu_hz, MAX_CPU_HZ);
+    }
+
+    #[test]
+    fn test_set_frequency() {
+        static mut SEEN: u32 = 0;
+
+        fn listener(hz: u32) {
+            unsafe { SEEN = hz };
+        }
+
+        clock_set(0);
+        assert!(cpu_on_change(listener));
+        assert_eq!(cpu_set_frequency(CPU_24MHZ), CPU_24MHZ);
+        assert_eq!(unsafe { SEEN }, CPU_24MHZ);
+        assert_eq!(cpu_frequency(), CPU_24MHZ);
+        assert_eq!(pit_hz(), CPU_24MHZ);
+
+        // Delays are counted at the new speed
+        let start = micros();
+        delay_ns(10_000);
+        assert_eq!(micros() - start, 10);
+    }
+}
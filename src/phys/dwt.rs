@@ -0,0 +1,34 @@
+//! This module provides access to the DWT cycle counter.
+//!
+//! The Cortex-M7 counts every core clock cycle in `DWT_CYCCNT`.
+//! The counter is only 32 bits wide, so at 396MHz it wraps
+//! around every 10.8 seconds. `clock::cycles()` extends it
+//! to 64 bits, and is what most code should use instead.
+use crate::phys::{addrs, assign, read_word};
+
+const TRCENA: u32 = 1 << 24;
+const CYCCNTENA: u32 = 1 << 0;
+const UNLOCK: u32 = 0xC5AC_CE55;
+
+/// Start the cycle counter. Safe to call more than once.
+pub fn dwt_enable() {
+    if dwt_enabled() {
+        return;
+    }
+
+    assign(addrs::DEMCR, read_word(addrs::DEMCR) | TRCENA);
+    assign(addrs::DWT_LAR, UNLOCK);
+    assign(addrs::DWT_CYCCNT, 0);
+    assign(addrs::DWT_CTRL, read_word(addrs::DWT_CTRL) | CYCCNTENA);
+}
+
+/// Returns true if the cycle counter is running.
+pub fn dwt_enabled() -> bool {
+    return read_word(addrs::DEMCR) & TRCENA > 0 && read_word(addrs::DWT_CTRL) & CYCCNTENA > 0;
+}
+
+/// The raw 32-bit cycle count.
+#[inline(always)]
+pub fn dwt_cycles() -> u32 {
+    return read_word(addrs::DWT_CYCCNT);
+}
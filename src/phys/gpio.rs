@@ -41,28 +41,28 @@ fn get_addr(pin: &Pin) -> u32 {
     }
 }
 
-pub fn gpio_speed(pin: &Pin, speed: MuxSpeed) {
+fn get_gpr_addr(pin: &Pin) -> Option<u32> {
+    return match pin {
+        Pin::Gpio1 => Some(addrs::IOMUXC_GPR_GPR26),
+        Pin::Gpio6 => Some(addrs::IOMUXC_GPR_GPR26),
+        Pin::Gpio2 => Some(addrs::IOMUXC_GPR_GPR27),
+        Pin::Gpio7 => Some(addrs::IOMUXC_GPR_GPR27),
+        Pin::Gpio3 => Some(addrs::IOMUXC_GPR_GPR28),
+        Pin::Gpio8 => Some(addrs::IOMUXC_GPR_GPR28),
+        Pin::Gpio4 => Some(addrs::IOMUXC_GPR_GPR29),
+        Pin::Gpio9 => Some(addrs::IOMUXC_GPR_GPR29),
 
-    // Gpio5 cannot be muxed.
-    if match pin {
-        Pin::Gpio5 => true,
-        _ => false,
-    } {
-        return;
+        // Gpio5 cannot be muxed.
+        Pin::Gpio5 => None,
     }
+}
 
-    let addr = match pin {
-        Pin::Gpio1 => addrs::IOMUXC_GPR_GPR26,
-        Pin::Gpio6 => addrs::IOMUXC_GPR_GPR26,
-        Pin::Gpio2 => addrs::IOMUXC_GPR_GPR27,
-        Pin::Gpio7 => addrs::IOMUXC_GPR_GPR27,
-        Pin::Gpio3 => addrs::IOMUXC_GPR_GPR28,
-        Pin::Gpio8 => addrs::IOMUXC_GPR_GPR28,
-        Pin::Gpio4 => addrs::IOMUXC_GPR_GPR29,
-        Pin::Gpio9 => addrs::IOMUXC_GPR_GPR29,
-
-        // This can't ever happen because Gpio5 is escape-hatched already
-        _ => addrs::IOMUXC_GPR_GPR26,
+pub fn gpio_speed(pin: &Pin, speed: MuxSpeed) {
+    let addr = match get_gpr_addr(pin) {
+        None => {
+            return;
+        },
+        Some(addr) => addr,
     };
 
     match speed {
@@ -75,6 +75,26 @@ pub fn gpio_speed(pin: &Pin, speed: MuxSpeed) {
     }
 }
 
+/// Hand a single pad over to the slow gpio (1 to 4) or back to
+/// the fast one (6 to 9). Only the slow ones can wake the core.
+pub fn gpio_speed_pad(pin: &Pin, pad: u32, speed: MuxSpeed) {
+    let addr = match get_gpr_addr(pin) {
+        None => {
+            return;
+        },
+        Some(addr) => addr,
+    };
+
+    match speed {
+        MuxSpeed::Slow => {
+            assign(addr, read_word(addr) & !(0x1 << pad));
+        },
+        MuxSpeed::Fast => {
+            assign(addr, read_word(addr) | (0x1 << pad));
+        }
+    }
+}
+
 pub fn gpio_direction(pin: &Pin, pad: u32, direction: Dir) {
     let addr = get_addr(pin) + 0x4;
     let original_value = read_word(addr);
@@ -106,4 +126,55 @@ pub fn gpio_read(pin: &Pin, mask: u32) -> u32 {
     let word = read_word(get_addr(pin));
 
     return (read_word(addr) | word) & mask;
-}
\ No newline at end of file
+}
+/// Which change on an input raises an interrupt.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
+}
+
+/// Raise an interrupt on a particular edge of a pad. This only
+/// sets up detection, `gpio_interrupt_enable` lets it through.
+pub fn gpio_interrupt_config(pin: &Pin, pad: u32, edge: Edge) {
+    let icr = get_addr(pin) + if pad < 16 { 0x0C } else { 0x10 };
+    let shift = (pad % 16) * 2;
+    let field = match edge {
+        Edge::Rising => 0x2,
+        Edge::Falling | Edge::Both => 0x3,
+    };
+
+    assign(icr, (read_word(icr) & !(0x3 << shift)) | (field << shift));
+
+    // EDGE_SEL overrides ICR with any edge at all
+    let edge_sel = get_addr(pin) + 0x1C;
+    match edge {
+        Edge::Both => {
+            assign(edge_sel, read_word(edge_sel) | (0x1 << pad));
+        },
+        _ => {
+            assign(edge_sel, read_word(edge_sel) & !(0x1 << pad));
+        }
+    }
+}
+
+pub fn gpio_interrupt_enable(pin: &Pin, pad: u32, enabled: bool) {
+    let addr = get_addr(pin) + 0x14;
+    if enabled {
+        assign(addr, read_word(addr) | (0x1 << pad));
+    } else {
+        assign(addr, read_word(addr) & !(0x1 << pad));
+    }
+}
+
+/// Returns true if the pad has seen its edge since the flag was
+/// last cleared, and clears it.
+pub fn gpio_interrupt_take(pin: &Pin, pad: u32) -> bool {
+    let addr = get_addr(pin) + 0x18;
+    let pending = read_word(addr) & (0x1 << pad) > 0;
+
+    // Write one to clear
+    assign(addr, 0x1 << pad);
+    return pending;
+}
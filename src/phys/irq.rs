@@ -79,8 +79,22 @@ pub enum Irq {
     Uart6 = 25,
     Uart7 = 26,
     Uart8 = 29,
+    GprIrq = 41, // Only used to work around low power errata
+    Wdog2 = 45,
+    Snvs = 46, // SNVS_HP functional, including the RTC alarm
     UsbPhy1 = 65, // UTMI0
+    Adc1 = 67,
     UsbPhy2 = 66, // UTMI1
+    Gpio1Low = 80, // GPIO1 bits 0 to 15
+    Gpio1High = 81, // GPIO1 bits 16 to 31
+    Gpio2Low = 82,
+    Gpio2High = 83,
+    Gpio3Low = 84,
+    Gpio3High = 85,
+    Gpio4Low = 86,
+    Gpio4High = 87,
+    Wdog1 = 92,
+    Rtwdog = 93,
     Gpt1 = 100,
     Gpt2 = 101,
     Usb1 = 113, // USB OTG1
@@ -88,6 +102,7 @@ pub enum Irq {
     PeriodicTimer = 122,
 }
 
+#[cfg_attr(feature = "testing", thread_local)]
 static mut IRQ_DISABLE_COUNT: usize = 0;
 
 /// System-level command to resume processing interrupts
@@ -116,6 +131,146 @@ pub fn disable_interrupts() {
     assembly!("CPSID i");
 }
 
+/// Returns true if interrupts are being processed right now.
+pub fn interrupts_enabled() -> bool {
+    return primask() == 0;
+}
+
+#[cfg(not(feature = "testing"))]
+fn primask() -> u32 {
+    let result: u32;
+    unsafe {
+        asm!("mrs {}, primask", out(reg) result);
+    }
+    return result & 0x1;
+}
+
+#[cfg(not(feature = "testing"))]
+fn basepri() -> u8 {
+    let result: u32;
+    unsafe {
+        asm!("mrs {}, basepri", out(reg) result);
+    }
+    return result as u8;
+}
+
+#[cfg(not(feature = "testing"))]
+fn set_basepri(priority: u8, only_raise: bool) {
+    unsafe {
+        if only_raise {
+            asm!("msr basepri_max, {}", in(reg) priority as u32);
+        } else {
+            asm!("msr basepri, {}", in(reg) priority as u32);
+        }
+    }
+}
+
+// On the host, interrupts are "disabled" whenever the
+// nesting counter says so, and BASEPRI is just a number.
+#[cfg(feature = "testing")]
+#[thread_local]
+static mut HOST_BASEPRI: u8 = 0;
+
+#[cfg(feature = "testing")]
+fn primask() -> u32 {
+    return unsafe { (IRQ_DISABLE_COUNT > 0) as u32 };
+}
+
+#[cfg(feature = "testing")]
+fn basepri() -> u8 {
+    return unsafe { HOST_BASEPRI };
+}
+
+#[cfg(feature = "testing")]
+fn set_basepri(priority: u8, only_raise: bool) {
+    unsafe {
+        let raises = HOST_BASEPRI == 0 || (priority != 0 && priority < HOST_BASEPRI);
+        if !only_raise || raises {
+            HOST_BASEPRI = priority;
+        }
+    }
+}
+
+/// Returns the current BASEPRI value. Interrupts with a
+/// priority value at or above it are held off. Zero means
+/// nothing is held off.
+pub fn interrupt_mask() -> u8 {
+    return basepri();
+}
+
+enum Restore {
+    Primask(bool),
+    Basepri(u8),
+}
+
+/// Holds off interrupts for as long as it is alive, and
+/// puts things back exactly as they were when it is dropped.
+///
+/// ```no_run
+/// use teensycore::phys::irq::*;
+///
+/// {
+///     let _cs = CriticalSection::new();
+///     // No interrupt can run here
+/// }
+///
+/// {
+///     // Only interrupts less important than 64 are held off
+///     let _cs = CriticalSection::masking(64);
+/// }
+/// ```
+///
+/// `CriticalSection::new()` shares the nesting counter with
+/// `disable_interrupts()`, so the two can be mixed freely.
+pub struct CriticalSection {
+    restore: Restore,
+}
+
+impl CriticalSection {
+    /// Hold off every interrupt, using PRIMASK.
+    pub fn new() -> Self {
+        let was_enabled = interrupts_enabled();
+        disable_interrupts();
+        return CriticalSection {
+            restore: Restore::Primask(was_enabled),
+        };
+    }
+
+    /// Hold off interrupts whose priority value is `priority`
+    /// or higher, using BASEPRI. More important interrupts
+    /// keep running. This never lowers an existing mask.
+    pub fn masking(priority: u8) -> Self {
+        let previous = basepri();
+        set_basepri(priority, true);
+        return CriticalSection {
+            restore: Restore::Basepri(previous),
+        };
+    }
+}
+
+impl Drop for CriticalSection {
+    fn drop(&mut self) {
+        match self.restore {
+            Restore::Primask(was_enabled) => {
+                unsafe {
+                    if IRQ_DISABLE_COUNT > 0 {
+                        IRQ_DISABLE_COUNT -= 1;
+                    }
+                }
+
+                // Interrupts may have been off before the
+                // counter was ever involved, so leave them off.
+                if was_enabled && unsafe { IRQ_DISABLE_COUNT } == 0 {
+                    assembly!("CPSIE i");
+                }
+            }
+            Restore::Basepri(previous) => {
+                set_basepri(previous, false);
+            }
+        }
+    }
+}
+
 /// Return the current address stored
 /// in the NVIC
 fn irq_addr() -> u32 {
@@ -162,6 +317,15 @@ pub fn irq_priority(irq_number: Irq, priority: u8) {
     put_irq_priority(num, priority);
 }
 
+/// Returns true if a specific interrupt is waiting to be handled.
+pub fn irq_pending(irq_number: Irq) -> bool {
+    let num = irq_number as u32;
+    let bank = num / 32;
+    let bit = num - bank * 32;
+    let addr = addrs::NVIC_IRQ_SET_PENDING_REG + (bank * 4);
+    return read_word(addr) & (0x1 << bit) > 0;
+}
+
 pub fn irq_clear_pending() {
     assign(addrs::NVIC_IRQ_CLEAR_PENDING_REG + 0x0, 0x0);
     assign(addrs::NVIC_IRQ_CLEAR_PENDING_REG + 0x4, 0x0);
@@ -253,6 +417,95 @@ pub fn irq_attach(irq_number: Irq, func: Fn) {
     put_irq(irq_number as usize, func);
 }
 
+/// Attach a handler to the SysTick exception. SysTick is
+/// not part of the NVIC, so it can't go through `irq_attach`.
+pub fn systick_attach(func: Fn) {
+    unsafe {
+        VECTORS.systick_handler = func;
+    }
+    update_ivt();
+}
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut SYSTICK_INTERVAL_MS: u32 = 0;
+
+/// Start the SysTick exception firing every `interval_ms`
+/// milliseconds. SysTick counts processor cycles and only has
+/// 24 bits, so the longest interval is around 40ms at 396MHz.
+/// Returns false, and leaves SysTick alone, if `interval_ms`
+/// is zero or longer than that.
+pub fn systick_start(interval_ms: u32) -> bool {
+    let reload = match systick_reload(crate::clock::cpu_hz(), interval_ms) {
+        None => {
+            return false;
+        }
+        Some(reload) => reload,
+    };
+
+    unsafe {
+        SYSTICK_INTERVAL_MS = interval_ms;
+    }
+
+    assign(addrs::SYST_RVR, reload);
+    assign(addrs::SYST_CVR, 0);
+
+    // Processor clock, exception enabled, counter enabled
+    assign(addrs::SYST_CSR, 0x7);
+    return true;
+}
+
+/// Reload SysTick after the core clock changes speed, so that
+/// it keeps firing at the interval it was started with. If the
+/// interval no longer fits at the new speed, SysTick is stopped
+/// and this returns false.
+pub fn systick_recalibrate() -> bool {
+    let interval_ms = unsafe { SYSTICK_INTERVAL_MS };
+    if interval_ms == 0 || systick_start(interval_ms) {
+        return true;
+    }
+
+    assign(addrs::SYST_CSR, 0);
+    unsafe {
+        SYSTICK_INTERVAL_MS = 0;
+    }
+    return false;
+}
+
+/// The SysTick reload value which fires every `interval_ms`
+/// milliseconds at `hz`, if the counter can reach that far.
+fn systick_reload(hz: u32, interval_ms: u32) -> Option<u32> {
+    let cycles = (hz as u64 / 1000) * interval_ms as u64;
+    if cycles == 0 || cycles > 0x0100_0000 {
+        return None;
+    }
+
+    return Some((cycles - 1) as u32);
+}
+
+/// Attach a handler to the PendSV exception.
+pub fn pendsv_attach(func: Fn) {
+    unsafe {
+        VECTORS.pendsv_handler = func;
+    }
+    update_ivt();
+}
+
+/// Set the priority of the PendSV and SysTick exceptions.
+/// Like interrupts, the lower the value the more important.
+pub fn system_priority(pendsv: u8, systick: u8) {
+    assign_8(addrs::SCB_SHPR3 + 2, pendsv);
+    assign_8(addrs::SCB_SHPR3 + 3, systick);
+}
+
+/// Attach a handler to the MemManage fault, which is
+/// raised whenever the MPU blocks an access.
+pub fn mpufault_attach(func: Fn) {
+    unsafe {
+        VECTORS.mpufault_handler = func;
+    }
+    update_ivt();
+}
+
 /// Some kind of hard-fault, typically
 /// this is a catastrophic function that hangs
 /// the program.
@@ -264,3 +517,17 @@ fn fault_handler() {
 fn noop() {
     assembly!("nop");
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_systick_reload() {
+        assert_eq!(systick_reload(396_000_000, 1), Some(395_999));
+        assert_eq!(systick_reload(396_000_000, 42), Some(16_631_999));
+        assert_eq!(systick_reload(396_000_000, 43), None);
+        assert_eq!(systick_reload(396_000_000, 0), None);
+        assert_eq!(systick_reload(600_000_000, u32::MAX), None);
+    }
+}
@@ -0,0 +1,393 @@
+//! This module provides access to the Memory Protection Unit.
+//!
+//! The Cortex-M7 has 16 MPU regions. Each region covers a power
+//! of two number of bytes (at least 32) and must start on a
+//! multiple of its own size. Where regions overlap, the one with
+//! the highest number wins.
+//!
+//! `mpu_init()` installs the default map which the `main!` macro
+//! uses. On top of describing each memory, it adds:
+//!
+//! - A guard at address zero, so dereferencing a null pointer
+//!   faults instead of quietly reading code out of ITCM.
+//! - A read-only guard beneath the stack, so overflowing it
+//!   faults immediately with `PanicType::StackOverflow`.
+//! - Non-cacheable regions over the DMA buffers, so the data
+//!   cache can never hold a stale copy of them.
+//!
+//! Additional regions can be layered on top:
+//!
+//! ```no_run
+//! use teensycore::phys::mpu::*;
+//!
+//! let region = MpuRegion::new(0x2020_0000, 4096, Memory::NonCacheable, Access::ReadWrite);
+//! mpu_configure(MPU_USER_REGION, &region).unwrap();
+//! ```
+#[cfg(not(feature = "testing"))]
+use core::arch::asm;
+use crate::assembly;
+use crate::phys::{addrs, assign, read_word};
+
+/// How many regions the MPU supports.
+pub const MPU_REGIONS: u8 = 16;
+
+/// The first region which `mpu_init()` leaves free.
+pub const MPU_USER_REGION: u8 = 9;
+
+const MIN_REGION_BYTES: u32 = 32;
+
+/// Who is allowed to touch a region, and how.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Access {
+    NoAccess = 0,
+    PrivilegedReadWrite = 1,
+    UnprivilegedReadOnly = 2,
+    ReadWrite = 3,
+    PrivilegedReadOnly = 5,
+    ReadOnly = 6,
+}
+
+/// How the memory in a region behaves, which
+/// includes whether the data cache may hold it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Memory {
+    /// Every access happens in order and completes before the next.
+    StronglyOrdered,
+    /// Peripheral registers.
+    Device,
+    /// Normal memory, cached with write-through.
+    WriteThrough,
+    /// Normal memory, cached with write-back and write-allocate.
+    WriteBack,
+    /// Normal memory which is never cached.
+    NonCacheable,
+}
+
+impl Memory {
+    /// The TEX, C and B bits for this kind of memory,
+    /// already shifted into place for RASR.
+    fn attributes(&self) -> u32 {
+        let (tex, cacheable, bufferable) = match self {
+            Memory::StronglyOrdered => (0, 0, 0),
+            Memory::Device => (2, 0, 0),
+            Memory::WriteThrough => (0, 1, 0),
+            Memory::WriteBack => (1, 1, 1),
+            Memory::NonCacheable => (1, 0, 0),
+        };
+        return (tex << 19) | (cacheable << 17) | (bufferable << 16);
+    }
+}
+
+/// Why a region could not be programmed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MpuError {
+    /// Regions must cover a power of two number of bytes.
+    SizeNotPowerOfTwo,
+    /// Regions must be at least 32 bytes.
+    SizeTooSmall,
+    /// Regions must start on a multiple of their size.
+    Misaligned,
+    /// There are only 16 regions.
+    InvalidRegion,
+}
+
+/// A single region of the memory map.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MpuRegion {
+    pub base: u32,
+    /// The size of the region in bytes. A size of zero covers
+    /// the entire 4gb address space.
+    pub size: u64,
+    pub memory: Memory,
+    pub access: Access,
+    /// Instruction fetches from the region will fault.
+    pub execute_never: bool,
+    pub shareable: bool,
+    /// Each region is split into eight equal subregions.
+    /// Setting a bit here leaves that subregion out.
+    pub disabled_subregions: u8,
+}
+
+impl MpuRegion {
+    /// A region which is never executed from and not shared.
+    pub const fn new(base: u32, size: u64, memory: Memory, access: Access) -> Self {
+        return MpuRegion {
+            base: base,
+            size: size,
+            memory: memory,
+            access: access,
+            execute_never: true,
+            shareable: false,
+            disabled_subregions: 0,
+        };
+    }
+
+    /// The same region, but code may run from it.
+    pub const fn executable(mut self) -> Self {
+        self.execute_never = false;
+        return self;
+    }
+
+    /// The smallest region which covers every byte from `addr`
+    /// up to `addr + bytes`. Because regions are aligned to their
+    /// own size, this may extend well past either end.
+    pub const fn covering(addr: u32, bytes: u32, memory: Memory, access: Access) -> Self {
+        let (base, size) = mpu_covering(addr, bytes);
+        return MpuRegion::new(base, size, memory, access);
+    }
+
+    /// The value to write into RBAR for this region.
+    pub fn rbar(&self, index: u8) -> Result<u32, MpuError> {
+        if index >= MPU_REGIONS {
+            return Err(MpuError::InvalidRegion);
+        }
+
+        // Validate the size, which also validates the base
+        match self.rasr() {
+            Ok(_) => {}
+            Err(error) => {
+                return Err(error);
+            }
+        }
+
+        // VALID means the region number comes from RBAR
+        return Ok(self.base | (1 << 4) | index as u32);
+    }
+
+    /// The value to write into RASR for this region.
+    pub fn rasr(&self) -> Result<u32, MpuError> {
+        let size = match mpu_size_field(self.size) {
+            Ok(size) => size,
+            Err(error) => {
+                return Err(error);
+            }
+        };
+
+        if self.size != 0 && (self.base as u64) % self.size != 0 {
+            return Err(MpuError::Misaligned);
+        }
+
+        let mut rasr = ((self.access as u32) << 24)
+            | self.memory.attributes()
+            | ((self.disabled_subregions as u32) << 8)
+            | (size << 1)
+            | 1;
+
+        if self.execute_never {
+            rasr |= 1 << 28;
+        }
+
+        if self.shareable {
+            rasr |= 1 << 18;
+        }
+
+        return Ok(rasr);
+    }
+}
+
+/// Encode a region size for the SIZE field of RASR, where
+/// the region covers 2^(SIZE + 1) bytes.
+pub fn mpu_size_field(bytes: u64) -> Result<u32, MpuError> {
+    if bytes == 0 {
+        return Ok(31);
+    } else if bytes < MIN_REGION_BYTES as u64 {
+        return Err(MpuError::SizeTooSmall);
+    } else if bytes & (bytes - 1) != 0 {
+        return Err(MpuError::SizeNotPowerOfTwo);
+    }
+
+    return Ok(bytes.trailing_zeros() - 1);
+}
+
+/// Returns the base and size of the smallest region
+/// which covers every byte from `addr` up to `addr + bytes`.
+pub const fn mpu_covering(addr: u32, bytes: u32) -> (u32, u64) {
+    let last = addr as u64 + if bytes == 0 { 0 } else { bytes as u64 - 1 };
+    let mut size = MIN_REGION_BYTES as u64;
+
+    // Grow the region until the first and last byte share a base
+    while size < 0x1_0000_0000 && (addr as u64) / size != last / size {
+        size = size * 2;
+    }
+
+    if size >= 0x1_0000_0000 {
+        return (0, 0);
+    }
+
+    return ((addr as u64 / size * size) as u32, size);
+}
+
+/// Program a single region. It takes effect immediately
+/// if the MPU is enabled.
+pub fn mpu_configure(index: u8, region: &MpuRegion) -> Result<(), MpuError> {
+    let rbar = match region.rbar(index) {
+        Ok(rbar) => rbar,
+        Err(error) => {
+            return Err(error);
+        }
+    };
+
+    // rbar() has already validated these
+    let rasr = region.rasr().unwrap_or(0);
+
+    assign(addrs::MPU_RBAR, rbar);
+    assign(addrs::MPU_RASR, rasr);
+    assembly!("dsb");
+    assembly!("isb");
+    return Ok(());
+}
+
+/// Turn off a single region.
+pub fn mpu_disable_region(index: u8) {
+    assign(addrs::MPU_RNR, index as u32);
+    assign(addrs::MPU_RASR, 0);
+    assembly!("dsb");
+    assembly!("isb");
+}
+
+/// Turn on the MPU. Anything not covered by a region falls
+/// back to the default memory map, and any access the MPU
+/// blocks raises a MemManage fault.
+pub fn mpu_enable() {
+    // Enable the MemManage fault, otherwise it escalates to a hardfault
+    assign(addrs::SCB_SHCSR, read_word(addrs::SCB_SHCSR) | (1 << 16));
+
+    // ENABLE and PRIVDEFENA
+    assign(addrs::MPU_CTRL, (1 << 2) | 1);
+    assembly!("dsb");
+    assembly!("isb");
+}
+
+/// Turn off the MPU entirely.
+pub fn mpu_disable() {
+    assembly!("dmb");
+    assign(addrs::MPU_CTRL, 0);
+    assembly!("dsb");
+    assembly!("isb");
+}
+
+/// Install the default memory map, with guards at address
+/// zero and beneath the stack, then turn on the MPU.
+pub fn mpu_init() {
+    mpu_disable();
+
+    for (index, region) in mpu_default_regions().iter().enumerate() {
+        // The default regions are all well formed
+        mpu_configure(index as u8, region).unwrap_or(());
+    }
+
+    for index in (mpu_default_regions().len() as u8)..MPU_REGIONS {
+        mpu_disable_region(index);
+    }
+
+    crate::phys::irq::mpufault_attach(mpu_fault_handler);
+    mpu_enable();
+}
+
+/// The regions which `mpu_init()` installs, lowest priority first.
+pub fn mpu_default_regions() -> [MpuRegion; MPU_USER_REGION as usize] {
+    let (dma_start, dma_end) = crate::mem::Region::DmaSafe.bounds();
+    let (guard_start, guard_end) = crate::mem::stack_guard();
+
+    // The statically allocated DMA buffers are at
+    // the very beginning of OCRAM2.
+    let ocram2 = addrs::OCRAM2;
+    let (heap_start, _) = crate::mem::Region::Ocram2.bounds();
+
+    return [
+        // ITCM holds code, and is never cached
+        MpuRegion::new(0x0000_0000, 0x8_0000, Memory::NonCacheable, Access::ReadWrite).executable(),
+        // DTCM holds data and the stack, and is never cached
+        MpuRegion::new(0x2000_0000, 0x8_0000, Memory::NonCacheable, Access::ReadWrite),
+        // OCRAM2 and the FlexRAM banks configured as OCRAM
+        MpuRegion::new(0x2020_0000, 0x10_0000, Memory::WriteBack, Access::ReadWrite),
+        // Peripherals
+        MpuRegion::new(0x4000_0000, 0x400_0000, Memory::Device, Access::ReadWrite),
+        // Flash
+        MpuRegion::new(0x6000_0000, 0x100_0000, Memory::WriteBack, Access::ReadOnly).executable(),
+        // DMA buffers and USB descriptors
+        MpuRegion::covering(ocram2, heap_start as u32 - ocram2, Memory::NonCacheable, Access::ReadWrite),
+        // The DMA-safe heap
+        MpuRegion::covering(dma_start as u32, (dma_end - dma_start) as u32, Memory::NonCacheable, Access::ReadWrite),
+        // Null pointer guard
+        MpuRegion::new(0x0000_0000, MIN_REGION_BYTES as u64, Memory::StronglyOrdered, Access::NoAccess),
+        // Stack guard. It can still be read, so `mem::stack_check()` keeps working.
+        MpuRegion::covering(
+            guard_start as u32,
+            (guard_end - guard_start) as u32,
+            Memory::NonCacheable,
+            Access::PrivilegedReadOnly,
+        ),
+    ];
+}
+
+/// Work out what a MemManage fault was caused by, and
+/// enter the matching kernel panic.
+fn mpu_fault_handler() {
+    let status = read_word(addrs::SCB_CFSR);
+    let (guard_start, guard_end) = crate::mem::stack_guard();
+
+    // MSTKERR means the fault happened pushing onto the stack.
+    // Otherwise MMARVALID says MMFAR holds the address.
+    let stacking = status & (1 << 4) != 0;
+    let address = read_word(addrs::SCB_MMFAR) as usize;
+    let in_guard = status & (1 << 7) != 0 && address >= guard_start && address < guard_end;
+
+    if stacking || in_guard {
+        crate::err(crate::PanicType::StackOverflow);
+    } else {
+        crate::err(crate::PanicType::Memfault);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_size_field() {
+        assert_eq!(mpu_size_field(32), Ok(4));
+        assert_eq!(mpu_size_field(4096), Ok(11));
+        assert_eq!(mpu_size_field(0x8_0000), Ok(18));
+        assert_eq!(mpu_size_field(0), Ok(31));
+        assert_eq!(mpu_size_field(16), Err(MpuError::SizeTooSmall));
+        assert_eq!(mpu_size_field(48), Err(MpuError::SizeNotPowerOfTwo));
+    }
+
+    #[test]
+    fn test_covering() {
+        assert_eq!(mpu_covering(0x2020_0000, 32), (0x2020_0000, 32));
+        assert_eq!(mpu_covering(0x2020_0000, 33), (0x2020_0000, 64));
+        assert_eq!(mpu_covering(0x2027_8000, 0x8000), (0x2027_8000, 0x8000));
+
+        // Straddling a boundary needs a much bigger region
+        assert_eq!(mpu_covering(0x1FF0, 0x20), (0x0, 0x4000));
+        assert_eq!(mpu_covering(0x8000_0000, 0x8000_0000), (0x8000_0000, 0x8000_0000));
+        assert_eq!(mpu_covering(0x7FFF_FFF0, 0x20), (0, 0));
+    }
+
+    #[test]
+    fn test_region_registers() {
+        let region = MpuRegion::new(0x2000_0000, 0x8_0000, Memory::NonCacheable, Access::ReadWrite);
+        assert_eq!(region.rbar(1), Ok(0x2000_0000 | 0x10 | 1));
+        assert_eq!(region.rasr(), Ok((1 << 28) | (3 << 24) | (1 << 19) | (18 << 1) | 1));
+
+        let flash = MpuRegion::new(0x6000_0000, 0x100_0000, Memory::WriteBack, Access::ReadOnly).executable();
+        assert_eq!(flash.rasr(), Ok((6 << 24) | (1 << 19) | (1 << 17) | (1 << 16) | (23 << 1) | 1));
+
+        let mut guard = MpuRegion::new(0x0, 32, Memory::StronglyOrdered, Access::NoAccess);
+        guard.disabled_subregions = 0x81;
+        guard.shareable = true;
+        assert_eq!(guard.rasr(), Ok((1 << 28) | (1 << 18) | (0x81 << 8) | (4 << 1) | 1));
+    }
+
+    #[test]
+    fn test_invalid_regions() {
+        let region = MpuRegion::new(0x2000_0100, 0x1000, Memory::WriteBack, Access::ReadWrite);
+        assert_eq!(region.rasr(), Err(MpuError::Misaligned));
+        assert_eq!(region.rbar(0), Err(MpuError::Misaligned));
+
+        let region = MpuRegion::new(0x2000_0000, 0x1000, Memory::WriteBack, Access::ReadWrite);
+        assert_eq!(region.rbar(MPU_REGIONS), Err(MpuError::InvalidRegion));
+    }
+}
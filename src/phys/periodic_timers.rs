@@ -130,6 +130,19 @@ pub fn pit_clear_interrupts(source: &PeriodicTimerSource) {
     assign(addr, 0x1);
 }
 
+/// Returns true if the timer has counted down to zero since
+/// its interrupt flag was last cleared.
+pub fn pit_interrupt_pending(source: &PeriodicTimerSource) -> bool {
+    let addr = addrs::PIT + match source {
+        PeriodicTimerSource::Timer0 => 0x10C,
+        PeriodicTimerSource::Timer1 => 0x11C,
+        PeriodicTimerSource::Timer2 => 0x12C,
+        PeriodicTimerSource::Timer3 => 0x13C,
+    };
+
+    return (read_word(addr) & 0x1) > 0;
+}
+
 pub fn pit_load_value(source: &PeriodicTimerSource, value: u32) {
     let addr = addrs::PIT + match source {
         PeriodicTimerSource::Timer0 => 0x100,
@@ -201,6 +201,34 @@ pub fn pin_mux_config(pin: usize, alt: Alt) {
     assign(addr, (1 << 4) | alt as u32);
 }
 
+/// The raw mux setting of a pin, so that it can be put
+/// back with `pin_mux_restore` after borrowing the pad.
+pub fn pin_mux_value(pin: usize) -> u32 {
+    return read_word(PIN_MUX[pin]);
+}
+
+pub fn pin_mux_restore(pin: usize, value: u32) {
+    assign(PIN_MUX[pin], value);
+}
+
+/// The fast gpio (6 to 9) and the bit within it which
+/// control a particular pin.
+pub fn pin_gpio(pin: usize) -> (u32, u32) {
+    let port = match &PIN_TO_GPIO_PIN[pin] {
+        Pin::Gpio1 => 1,
+        Pin::Gpio2 => 2,
+        Pin::Gpio3 => 3,
+        Pin::Gpio4 => 4,
+        Pin::Gpio5 => 5,
+        Pin::Gpio6 => 6,
+        Pin::Gpio7 => 7,
+        Pin::Gpio8 => 8,
+        Pin::Gpio9 => 9,
+    };
+
+    return (port, PIN_BITS[pin] as u32);
+}
+
 /// Configure all aspects of the pad.
 ///
 /// This includes the speed, the resistance, the drive strength,
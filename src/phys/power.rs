@@ -0,0 +1,557 @@
+//! This module puts the core to sleep until something needs it.
+//!
+//! There are three ways to sleep, each saving more power and
+//! taking longer to wake up from than the last:
+//!
+//! - `SleepMode::Idle` stops the core clock until any interrupt
+//!   at all. Everything else keeps running.
+//! - `SleepMode::Wait` turns the core clock off entirely. The
+//!   peripherals keep running, but only an enabled wake source
+//!   brings the core back.
+//! - `SleepMode::Stop` stops every clock except the 32kHz crystal.
+//!   The periodic timers stop with it, so only pins, serial ports
+//!   and the RTC alarm can end it.
+//!
+//! ```no_run
+//! use teensycore::phys::gpio::Edge;
+//! use teensycore::phys::power::*;
+//! use teensycore::phys::rtc::*;
+//!
+//! rtc_init();
+//! rtc_alarm(rtc_get() + 60, alarm);
+//!
+//! power_wake_enable(WakeSource::Pin(2, Edge::Falling));
+//! power_wake_enable(WakeSource::RtcAlarm);
+//! power_sleep(SleepMode::Stop).unwrap();
+//!
+//! match power_last_wake() {
+//!     Some(WakeSource::Pin(pin, _)) => {
+//!         // Someone pressed the button
+//!     }
+//!     _ => {}
+//! }
+//!
+//! fn alarm() {}
+//! ```
+//!
+//! A pin can only wake the core through the slow gpio, so for the
+//! length of a sleep its pad is handed over from the fast gpio which
+//! `pins` uses. Pins used this way should be inputs. Outside of Idle,
+//! a serial port wakes up on the start bit of the first byte, which
+//! is lost. When the RTC is running, `clock::nanos()` is moved on by
+//! however long a Stop lasted.
+#![allow(dead_code)]
+use crate::phys::gpio::Edge;
+use crate::phys::irq::*;
+use crate::phys::pins::pin_gpio;
+use crate::serio::{serial_rx_pin, SerioDevice};
+
+/// The most wake sources which can be enabled at once.
+pub const MAX_WAKE_SOURCES: usize = 8;
+
+// CCM_CLPCR fields
+const CLPCR_LPM: u32 = 0x3;
+const CLPCR_LPM_WAIT: u32 = 0x1;
+const CLPCR_LPM_STOP: u32 = 0x2;
+const CLPCR_ARM_CLK_DIS_ON_LPM: u32 = 1 << 5;
+const CLPCR_STBY_COUNT: u32 = 0x3 << 9;
+const CLPCR_BYPASS_LPM_HS1: u32 = 1 << 19;
+const CLPCR_BYPASS_LPM_HS0: u32 = 1 << 21;
+const CLPCR_MASK_SCU_IDLE: u32 = 1 << 26;
+const CLPCR_MASK_L2CC_IDLE: u32 = 1 << 27;
+
+/// The GPC mask registers, for IRQs 32 to 63, 64 to 95 and so on.
+const GPC_IMR: [u32; 5] = [0x08, 0x0C, 0x10, 0x14, 0x34];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SleepMode {
+    Idle,
+    Wait,
+    Stop,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WakeSource {
+    /// An edge on a pin, numbered the same way as `pins`.
+    Pin(usize, Edge),
+    /// The alarm set with `rtc::rtc_alarm`.
+    RtcAlarm,
+    /// Anything arriving on a serial port.
+    Serial(SerioDevice),
+    /// The periodic timers, which is what `timers` and
+    /// the idle hook use to wake up on time. Stop
+    /// halts them, so they only work with Idle and Wait.
+    PeriodicTimer,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PowerError {
+    /// Wait and Stop only end with a wake source, and none are enabled.
+    NoWakeSource,
+    /// The wake source can't end this kind of sleep.
+    Unsupported(WakeSource),
+}
+
+impl WakeSource {
+    /// Returns true if the source can end this kind of sleep.
+    pub fn supports(&self, mode: SleepMode) -> bool {
+        return match self {
+            WakeSource::PeriodicTimer => mode != SleepMode::Stop,
+            _ => true,
+        };
+    }
+}
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut WAKE_SOURCES: [Option<WakeSource>; MAX_WAKE_SOURCES] = [None; MAX_WAKE_SOURCES];
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut LAST_WAKE: Option<WakeSource> = None;
+
+fn wake_sources() -> &'static mut [Option<WakeSource>; MAX_WAKE_SOURCES] {
+    return unsafe { &mut *core::ptr::addr_of_mut!(WAKE_SOURCES) };
+}
+
+/// Let `source` end a sleep. Returns false if there is no room.
+pub fn power_wake_enable(source: WakeSource) -> bool {
+    if power_wake_enabled(source) {
+        return true;
+    }
+
+    for slot in wake_sources().iter_mut() {
+        if slot.is_none() {
+            *slot = Some(source);
+            return true;
+        }
+    }
+
+    return false;
+}
+
+/// Stop `source` from ending a sleep. Returns false
+/// if it was not enabled.
+pub fn power_wake_disable(source: WakeSource) -> bool {
+    for slot in wake_sources().iter_mut() {
+        if *slot == Some(source) {
+            *slot = None;
+            return true;
+        }
+    }
+
+    return false;
+}
+
+pub fn power_wake_enabled(source: WakeSource) -> bool {
+    return wake_sources().iter().any(|slot| *slot == Some(source));
+}
+
+pub fn power_wake_clear() {
+    for slot in wake_sources().iter_mut() {
+        *slot = None;
+    }
+}
+
+/// Which of the enabled sources ended the last sleep, if it is
+/// known. Serial ports are only reported after Wait or Stop.
+pub fn power_last_wake() -> Option<WakeSource> {
+    return unsafe { LAST_WAKE };
+}
+
+/// Sleep until something wakes the core up. Interrupts which
+/// ended the sleep are handled before this returns.
+pub fn power_sleep(mode: SleepMode) -> Result<(), PowerError> {
+    let mut any = false;
+    for slot in wake_sources().iter() {
+        match slot {
+            None => {}
+            Some(source) => {
+                if !source.supports(mode) {
+                    return Err(PowerError::Unsupported(*source));
+                }
+                any = true;
+            }
+        }
+    }
+
+    if !any && mode != SleepMode::Idle {
+        return Err(PowerError::NoWakeSource);
+    }
+
+    unsafe {
+        LAST_WAKE = None;
+    }
+
+    sleep(mode);
+    return Ok(());
+}
+
+/// The GPC mask register and bit which let an IRQ wake the core.
+/// The first 32 IRQs don't go through the GPC, so they can only
+/// end an Idle sleep.
+fn gpc_mask(irq: u32) -> Option<(u32, u32)> {
+    if irq < 32 || irq >= 192 {
+        return None;
+    }
+
+    return Some((GPC_IMR[(irq / 32 - 1) as usize], 1 << (irq % 32)));
+}
+
+/// The low power control register, set up for a kind of sleep.
+/// The handshake bypasses and masks follow the reference manual.
+fn clpcr_value(clpcr: u32, mode: SleepMode) -> u32 {
+    let run = clpcr
+        & !(CLPCR_LPM
+            | CLPCR_ARM_CLK_DIS_ON_LPM
+            | CLPCR_STBY_COUNT
+            | CLPCR_BYPASS_LPM_HS0
+            | CLPCR_BYPASS_LPM_HS1
+            | CLPCR_MASK_SCU_IDLE
+            | CLPCR_MASK_L2CC_IDLE);
+
+    let lpm = match mode {
+        SleepMode::Idle => {
+            return run;
+        }
+        SleepMode::Wait => CLPCR_LPM_WAIT,
+        SleepMode::Stop => CLPCR_LPM_STOP,
+    };
+
+    return run
+        | lpm
+        | CLPCR_ARM_CLK_DIS_ON_LPM
+        | CLPCR_STBY_COUNT
+        | CLPCR_BYPASS_LPM_HS0
+        | CLPCR_BYPASS_LPM_HS1
+        | CLPCR_MASK_SCU_IDLE
+        | CLPCR_MASK_L2CC_IDLE;
+}
+
+/// The pin a source watches, if it watches one.
+fn wake_pin(source: WakeSource, mode: SleepMode) -> Option<(usize, Edge)> {
+    return match source {
+        WakeSource::Pin(pin, edge) => Some((pin, edge)),
+        // Idle wakes on the uart's own interrupt
+        WakeSource::Serial(device) => match mode {
+            SleepMode::Idle => None,
+            _ => Some((serial_rx_pin(device), Edge::Falling)),
+        },
+        _ => None,
+    };
+}
+
+/// The slow gpio interrupt which covers a pin.
+fn pin_irq(pin: usize) -> Irq {
+    let (port, pad) = pin_gpio(pin);
+    return match (port, pad < 16) {
+        (6, true) => Irq::Gpio1Low,
+        (6, false) => Irq::Gpio1High,
+        (7, true) => Irq::Gpio2Low,
+        (7, false) => Irq::Gpio2High,
+        (8, true) => Irq::Gpio3Low,
+        (8, false) => Irq::Gpio3High,
+        (9, true) => Irq::Gpio4Low,
+        _ => Irq::Gpio4High,
+    };
+}
+
+/// The interrupt which a source raises to wake the core.
+fn wake_irq(source: WakeSource, mode: SleepMode) -> Option<Irq> {
+    match wake_pin(source, mode) {
+        None => {}
+        Some((pin, _)) => {
+            return Some(pin_irq(pin));
+        }
+    }
+
+    return match source {
+        WakeSource::RtcAlarm => Some(Irq::Snvs),
+        WakeSource::PeriodicTimer => Some(Irq::PeriodicTimer),
+        _ => None,
+    };
+}
+
+#[cfg(not(feature = "testing"))]
+mod hardware {
+    use super::*;
+    use crate::clock::{clock_sleep, clock_wake};
+    use crate::phys::gpio::*;
+    use crate::phys::pins::*;
+    use crate::phys::rtc::*;
+    use crate::phys::{addrs, assign, read_word, Dir};
+    use crate::{assembly, dsb, isb};
+    use core::arch::asm;
+
+    const SCR_SLEEPDEEP: u32 = 1 << 2;
+    const GPR1_GINT: u32 = 1 << 12;
+
+    /// A pin borrowed for the length of a sleep, and the
+    /// pad mux setting to give back, if it was changed.
+    #[derive(Copy, Clone)]
+    struct Borrowed {
+        pin: usize,
+        mux: Option<u32>,
+    }
+
+    /// The slow gpio which sits behind a fast one.
+    fn slow_gpio(port: u32) -> Pin {
+        return match port {
+            6 => Pin::Gpio1,
+            7 => Pin::Gpio2,
+            8 => Pin::Gpio3,
+            _ => Pin::Gpio4,
+        };
+    }
+
+    pub fn sleep(mode: SleepMode) {
+        let _cs = CriticalSection::new();
+        let sources = *wake_sources();
+        let mut borrowed: [Option<Borrowed>; MAX_WAKE_SOURCES] = [None; MAX_WAKE_SOURCES];
+        let mut masks = [0u32; 5];
+
+        for index in 0..MAX_WAKE_SOURCES {
+            match sources[index] {
+                None => {}
+                Some(source) => {
+                    borrowed[index] = borrow(source, mode);
+                }
+            }
+        }
+
+        if mode != SleepMode::Idle {
+            // Only let the enabled sources through
+            for index in 0..GPC_IMR.len() {
+                masks[index] = read_word(addrs::GPC + GPC_IMR[index]);
+                assign(addrs::GPC + GPC_IMR[index], 0xFFFF_FFFF);
+            }
+
+            for source in sources.iter() {
+                match source.and_then(|source| wake_irq(source, mode)) {
+                    None => {}
+                    Some(irq) => gpc_unmask(irq),
+                }
+            }
+        }
+
+        let before = match mode {
+            SleepMode::Stop => rtc_ticks(),
+            _ => None,
+        };
+
+        clock_sleep();
+        enter(mode);
+        dsb();
+        assembly!("wfi");
+        isb();
+        enter(SleepMode::Idle);
+
+        if mode != SleepMode::Idle {
+            for index in 0..GPC_IMR.len() {
+                assign(addrs::GPC + GPC_IMR[index], masks[index]);
+            }
+        }
+
+        for index in 0..MAX_WAKE_SOURCES {
+            match sources[index] {
+                None => {}
+                Some(source) => {
+                    let woke = match borrowed[index] {
+                        Some(pin) => give_back(pin),
+                        None => match wake_irq(source, mode) {
+                            Some(irq) => irq_pending(irq),
+                            None => false,
+                        },
+                    };
+
+                    if woke && unsafe { LAST_WAKE }.is_none() {
+                        unsafe {
+                            LAST_WAKE = Some(source);
+                        }
+                    }
+                }
+            }
+        }
+
+        // The periodic timers stopped along with everything else
+        let skipped = match (before, rtc_ticks()) {
+            (Some(before), Some(after)) => rtc_ticks_to_nanos(after - before),
+            _ => 0,
+        };
+
+        clock_wake(skipped);
+    }
+
+    /// Set the core up to enter `mode` on the next WFI.
+    fn enter(mode: SleepMode) {
+        let scr = read_word(addrs::SCB_SCR);
+        match mode {
+            SleepMode::Stop => assign(addrs::SCB_SCR, scr | SCR_SLEEPDEEP),
+            _ => assign(addrs::SCB_SCR, scr & !SCR_SLEEPDEEP),
+        }
+
+        if mode == SleepMode::Idle {
+            assign(
+                addrs::CCM_CLPCR,
+                clpcr_value(read_word(addrs::CCM_CLPCR), mode),
+            );
+            return;
+        }
+
+        // ERR050143: the GPC can start a low power mode before the
+        // core reaches WFI, unless IRQ 41 is pending and unmasked
+        // while the mode is set. GINT keeps IRQ 41 pending.
+        assign(
+            addrs::IOMUXC_GPR_GPR1,
+            read_word(addrs::IOMUXC_GPR_GPR1) | GPR1_GINT,
+        );
+        gpc_unmask(Irq::GprIrq);
+        assign(
+            addrs::CCM_CLPCR,
+            clpcr_value(read_word(addrs::CCM_CLPCR), mode),
+        );
+        gpc_mask_irq(Irq::GprIrq);
+    }
+
+    fn gpc_unmask(irq: Irq) {
+        match gpc_mask(irq as u32) {
+            None => {}
+            Some((offset, bit)) => {
+                assign(addrs::GPC + offset, read_word(addrs::GPC + offset) & !bit);
+            }
+        }
+    }
+
+    fn gpc_mask_irq(irq: Irq) {
+        match gpc_mask(irq as u32) {
+            None => {}
+            Some((offset, bit)) => {
+                assign(addrs::GPC + offset, read_word(addrs::GPC + offset) | bit);
+            }
+        }
+    }
+
+    /// Hand the pin a source watches over to the slow gpio,
+    /// and have it raise an interrupt on the right edge.
+    fn borrow(source: WakeSource, mode: SleepMode) -> Option<Borrowed> {
+        let (pin, edge) = wake_pin(source, mode)?;
+        let (port, pad) = pin_gpio(pin);
+        let gpio = slow_gpio(port);
+
+        // A uart pad has to be muxed over to gpio to see the start bit
+        let mux = match source {
+            WakeSource::Serial(_) => {
+                let mux = pin_mux_value(pin);
+                pin_mux_config(pin, Alt::Alt5);
+                Some(mux)
+            }
+            _ => None,
+        };
+
+        gpio_direction(&gpio, pad, Dir::Input);
+        gpio_interrupt_config(&gpio, pad, edge);
+        gpio_interrupt_take(&gpio, pad);
+        gpio_interrupt_enable(&gpio, pad, true);
+        gpio_speed_pad(&gpio, pad, MuxSpeed::Slow);
+
+        let irq = pin_irq(pin);
+        irq_attach(irq, handle_pin_wake);
+        irq_enable(irq);
+
+        return Some(Borrowed { pin: pin, mux: mux });
+    }
+
+    /// Give a pin back to the fast gpio, and return
+    /// whether it saw its edge.
+    fn give_back(borrowed: Borrowed) -> bool {
+        let (port, pad) = pin_gpio(borrowed.pin);
+        let gpio = slow_gpio(port);
+
+        gpio_interrupt_enable(&gpio, pad, false);
+        let woke = gpio_interrupt_take(&gpio, pad);
+        gpio_speed_pad(&gpio, pad, MuxSpeed::Fast);
+
+        match borrowed.mux {
+            None => {}
+            Some(mux) => pin_mux_restore(borrowed.pin, mux),
+        }
+
+        return woke;
+    }
+
+    /// The edge has already been dealt with by the time
+    /// interrupts come back on, so there is nothing to do.
+    fn handle_pin_wake() {}
+}
+
+#[cfg(not(feature = "testing"))]
+use hardware::sleep;
+
+/// The host has nothing to put to sleep.
+#[cfg(feature = "testing")]
+fn sleep(_mode: SleepMode) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wake_sources() {
+        power_wake_clear();
+        assert_eq!(power_sleep(SleepMode::Wait), Err(PowerError::NoWakeSource));
+        assert_eq!(power_sleep(SleepMode::Idle), Ok(()));
+
+        let button = WakeSource::Pin(2, Edge::Falling);
+        assert!(power_wake_enable(button));
+        assert!(power_wake_enable(button));
+        assert!(power_wake_enable(WakeSource::PeriodicTimer));
+        assert_eq!(power_sleep(SleepMode::Wait), Ok(()));
+        assert_eq!(
+            power_sleep(SleepMode::Stop),
+            Err(PowerError::Unsupported(WakeSource::PeriodicTimer))
+        );
+
+        assert!(power_wake_disable(WakeSource::PeriodicTimer));
+        assert!(!power_wake_disable(WakeSource::PeriodicTimer));
+        assert!(power_wake_enabled(button));
+        assert_eq!(power_sleep(SleepMode::Stop), Ok(()));
+
+        for pin in 0..MAX_WAKE_SOURCES {
+            power_wake_enable(WakeSource::Pin(pin, Edge::Both));
+        }
+        assert!(!power_wake_enable(WakeSource::RtcAlarm));
+        power_wake_clear();
+    }
+
+    #[test]
+    fn test_registers() {
+        // The RTC alarm, the periodic timers and the first IMR5 IRQ
+        assert_eq!(gpc_mask(46), Some((0x08, 1 << 14)));
+        assert_eq!(gpc_mask(122), Some((0x10, 1 << 26)));
+        assert_eq!(gpc_mask(160), Some((0x34, 1)));
+        assert_eq!(gpc_mask(Irq::Uart6 as u32), None);
+
+        let wait = clpcr_value(0x0000_0078, SleepMode::Wait);
+        assert_eq!(wait & CLPCR_LPM, CLPCR_LPM_WAIT);
+        assert_eq!(wait & 0x58, 0x58);
+        assert!(wait & CLPCR_ARM_CLK_DIS_ON_LPM > 0);
+        assert_eq!(
+            clpcr_value(wait, SleepMode::Stop) & CLPCR_LPM,
+            CLPCR_LPM_STOP
+        );
+        assert_eq!(clpcr_value(wait, SleepMode::Idle), 0x58);
+
+        // Pin 13 is bit 3 of GPIO7, which sits in front of GPIO2
+        let led = WakeSource::Pin(13, Edge::Rising);
+        assert_eq!(
+            wake_irq(led, SleepMode::Stop).map(|irq| irq as u32),
+            Some(82)
+        );
+        assert_eq!(
+            wake_irq(WakeSource::Pin(14, Edge::Rising), SleepMode::Wait).map(|irq| irq as u32),
+            Some(81)
+        );
+        assert_eq!(
+            wake_irq(WakeSource::RtcAlarm, SleepMode::Stop).map(|irq| irq as u32),
+            Some(46)
+        );
+    }
+}
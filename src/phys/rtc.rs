@@ -0,0 +1,501 @@
+//! This module provides access to the real-time clock.
+//!
+//! The SNVS low power RTC keeps counting, off the coin cell or
+//! VBAT, while the rest of the chip is powered down. It holds
+//! seconds since the unix epoch (1970-01-01T00:00:00Z). At boot
+//! that count is copied into the high power RTC, which is the
+//! one that gets read and which raises alarms.
+//!
+//! ```no_run
+//! use teensycore::phys::rtc::*;
+//!
+//! rtc_init();
+//!
+//! if !rtc_is_set() {
+//!     rtc_set(DateTime::new(2024, 2, 29, 12, 0, 0).unwrap().to_timestamp());
+//! }
+//!
+//! let now = DateTime::from_timestamp(rtc_get());
+//! let text = now.to_iso8601(); // 2024-02-29T12:00:00Z
+//!
+//! // Ring in one minute
+//! rtc_alarm(rtc_get() + 60, wake_up);
+//!
+//! fn wake_up() {}
+//! ```
+use crate::phys::irq::*;
+use crate::phys::{addrs, assign, read_word};
+use crate::system::str::Str;
+
+// SNVS registers
+const HPCR: u32 = 0x08;
+const HPSR: u32 = 0x14;
+const HPRTCMR: u32 = 0x24;
+const HPRTCLR: u32 = 0x28;
+const HPTAMR: u32 = 0x2C;
+const HPTALR: u32 = 0x30;
+const LPCR: u32 = 0x38;
+const LPSRTCMR: u32 = 0x50;
+const LPSRTCLR: u32 = 0x54;
+
+const HPCR_RTC_EN: u32 = 1 << 0;
+const HPCR_HPTA_EN: u32 = 1 << 1;
+const HPCR_HP_TS: u32 = 1 << 16;
+const HPSR_HPTA: u32 = 1 << 0;
+const LPCR_SRTC_ENV: u32 = 1 << 0;
+
+/// The RTC counts a 32.768kHz crystal, so the bottom
+/// 15 bits of each counter are fractions of a second.
+const FRACTION_BITS: u32 = 15;
+
+/// Anything earlier than this is a clock which was never set.
+const EARLIEST_VALID: u64 = 946_684_800; // 2000-01-01
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// ISO-8601 only has room for four digits of year.
+const LATEST_TIMESTAMP: u64 = 253_402_300_799; // 9999-12-31T23:59:59
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut ALARM_HANDLER: Option<fn()> = None;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+/// A calendar date and time, in UTC, between 1970 and 9999.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+pub fn is_leap_year(year: u16) -> bool {
+    return (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    return match month {
+        2 => {
+            if is_leap_year(year) {
+                29
+            } else {
+                28
+            }
+        }
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+}
+
+/// Days between 1970-01-01 and the given date. Shifting the year
+/// to start in March puts the leap day at the very end of it.
+fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    let year = if month <= 2 {
+        year as u64 - 1
+    } else {
+        year as u64
+    };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as u64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as u64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    return era * 146_097 + day_of_era - 719_468;
+}
+
+/// The reverse of `days_from_civil`.
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    return (year as u16, month as u8, day as u8);
+}
+
+impl DateTime {
+    /// Returns None unless every field is in range. Dates
+    /// before the unix epoch or after 9999 are not supported.
+    pub fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Option<Self> {
+        if year < 1970
+            || year > 9999
+            || month < 1
+            || month > 12
+            || day < 1
+            || day > days_in_month(year, month)
+            || hour > 23
+            || minute > 59
+            || second > 59
+        {
+            return None;
+        }
+
+        return Some(DateTime {
+            year: year,
+            month: month,
+            day: day,
+            hour: hour,
+            minute: minute,
+            second: second,
+        });
+    }
+
+    /// The date and time `timestamp` seconds after the unix epoch.
+    /// Anything past the end of 9999 stops there.
+    pub fn from_timestamp(timestamp: u64) -> Self {
+        let timestamp = crate::math::min(timestamp, LATEST_TIMESTAMP);
+        let (year, month, day) = civil_from_days(timestamp / SECONDS_PER_DAY);
+        let seconds = timestamp % SECONDS_PER_DAY;
+        return DateTime {
+            year: year,
+            month: month,
+            day: day,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        };
+    }
+
+    /// Seconds since the unix epoch.
+    pub fn to_timestamp(&self) -> u64 {
+        return days_from_civil(self.year, self.month, self.day) * SECONDS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64;
+    }
+
+    /// Move forwards, or backwards, by some number of seconds.
+    /// Going back past the unix epoch stops at the epoch, and
+    /// going past the end of 9999 stops there.
+    pub fn add_seconds(&self, seconds: i64) -> Self {
+        let timestamp = (self.to_timestamp() as i64).saturating_add(seconds);
+        if timestamp < 0 {
+            return DateTime::from_timestamp(0);
+        }
+
+        return DateTime::from_timestamp(timestamp as u64);
+    }
+
+    /// How many seconds `self` is after `other`. Negative
+    /// if it is actually before.
+    pub fn seconds_since(&self, other: &DateTime) -> i64 {
+        return self.to_timestamp() as i64 - other.to_timestamp() as i64;
+    }
+
+    pub fn day_of_week(&self) -> Weekday {
+        // The unix epoch was a Thursday
+        return match (days_from_civil(self.year, self.month, self.day) + 3) % 7 {
+            0 => Weekday::Monday,
+            1 => Weekday::Tuesday,
+            2 => Weekday::Wednesday,
+            3 => Weekday::Thursday,
+            4 => Weekday::Friday,
+            5 => Weekday::Saturday,
+            _ => Weekday::Sunday,
+        };
+    }
+
+    /// 1 for the first of January.
+    pub fn day_of_year(&self) -> u16 {
+        return (days_from_civil(self.year, self.month, self.day) - days_from_civil(self.year, 1, 1)
+            + 1) as u16;
+    }
+
+    /// Append the date and time to `out`, in the
+    /// form `2024-02-29T13:05:09Z`.
+    pub fn write_iso8601(&self, out: &mut Str) -> bool {
+        let mut text = *b"0000-00-00T00:00:00Z";
+        let year = self.year as usize;
+        text[0] += (year / 1000 % 10) as u8;
+        text[1] += (year / 100 % 10) as u8;
+        text[2] += (year / 10 % 10) as u8;
+        text[3] += (year % 10) as u8;
+
+        let fields = [
+            (5, self.month),
+            (8, self.day),
+            (11, self.hour),
+            (14, self.minute),
+            (17, self.second),
+        ];
+        for (position, value) in fields {
+            text[position] += value / 10;
+            text[position + 1] += value % 10;
+        }
+
+        return out.append(&text);
+    }
+
+    pub fn to_iso8601(&self) -> Str {
+        let mut result = Str::new();
+        self.write_iso8601(&mut result);
+        return result;
+    }
+}
+
+/// Turn on the RTC and copy the time kept by the low
+/// power side over into the high power side.
+pub fn rtc_init() {
+    // Undo clock gating for snvs_hp and snvs_lp
+    assign(
+        addrs::CCM_CCGR5,
+        read_word(addrs::CCM_CCGR5) | (0x3 << 14) | (0x3 << 16),
+    );
+
+    if read_word(addrs::SNVS + LPCR) & LPCR_SRTC_ENV == 0 {
+        assign(
+            addrs::SNVS + LPCR,
+            read_word(addrs::SNVS + LPCR) | LPCR_SRTC_ENV,
+        );
+        while read_word(addrs::SNVS + LPCR) & LPCR_SRTC_ENV == 0 {}
+    }
+
+    rtc_sync();
+}
+
+/// Seconds since the unix epoch.
+pub fn rtc_get() -> u64 {
+    return read_rtc_stable() >> FRACTION_BITS;
+}
+
+/// Set the time, as seconds since the unix epoch. The low
+/// power side keeps it for as long as it has battery power.
+pub fn rtc_set(timestamp: u64) {
+    let lpcr = addrs::SNVS + LPCR;
+    assign(lpcr, read_word(lpcr) & !LPCR_SRTC_ENV);
+    while read_word(lpcr) & LPCR_SRTC_ENV > 0 {}
+
+    let ticks = timestamp << FRACTION_BITS;
+    assign(addrs::SNVS + LPSRTCMR, (ticks >> 32) as u32);
+    assign(addrs::SNVS + LPSRTCLR, ticks as u32);
+
+    assign(lpcr, read_word(lpcr) | LPCR_SRTC_ENV);
+    while read_word(lpcr) & LPCR_SRTC_ENV == 0 {}
+
+    rtc_sync();
+}
+
+/// Returns false if the clock has obviously never been
+/// set, for example after the battery was removed.
+pub fn rtc_is_set() -> bool {
+    return rtc_get() >= EARLIEST_VALID;
+}
+
+/// The raw count of the 32.768kHz crystal, or None if the RTC
+/// isn't running. This keeps counting in every sleep mode, so
+/// it can time a sleep which stops every other clock.
+pub fn rtc_ticks() -> Option<u64> {
+    if read_word(addrs::SNVS + HPCR) & HPCR_RTC_EN == 0 {
+        return None;
+    }
+
+    return Some(read_rtc_stable());
+}
+
+/// Turn a count of `rtc_ticks()` into nanoseconds.
+pub fn rtc_ticks_to_nanos(ticks: u64) -> u64 {
+    return (ticks >> FRACTION_BITS) * 1_000_000_000
+        + ((ticks & ((1 << FRACTION_BITS) - 1)) * 1_000_000_000 >> FRACTION_BITS);
+}
+
+/// Run `func` from an interrupt once the clock
+/// reaches `timestamp`. Replaces any earlier alarm.
+pub fn rtc_alarm(timestamp: u64, func: fn()) {
+    rtc_alarm_cancel();
+
+    unsafe {
+        ALARM_HANDLER = Some(func);
+    }
+
+    let ticks = timestamp << FRACTION_BITS;
+    assign(addrs::SNVS + HPTAMR, (ticks >> 32) as u32);
+    assign(addrs::SNVS + HPTALR, ticks as u32);
+
+    irq_attach(Irq::Snvs, handle_alarm);
+    irq_enable(Irq::Snvs);
+
+    let hpcr = addrs::SNVS + HPCR;
+    assign(hpcr, read_word(hpcr) | HPCR_HPTA_EN);
+    while read_word(hpcr) & HPCR_HPTA_EN == 0 {}
+}
+
+/// Stop a pending alarm from going off.
+pub fn rtc_alarm_cancel() {
+    let hpcr = addrs::SNVS + HPCR;
+    assign(hpcr, read_word(hpcr) & !HPCR_HPTA_EN);
+    while read_word(hpcr) & HPCR_HPTA_EN > 0 {}
+    assign(addrs::SNVS + HPSR, HPSR_HPTA);
+}
+
+/// Copy the low power counter into the high power one.
+fn rtc_sync() {
+    let hpcr = addrs::SNVS + HPCR;
+    assign(hpcr, read_word(hpcr) & !HPCR_RTC_EN);
+    while read_word(hpcr) & HPCR_RTC_EN > 0 {}
+
+    assign(hpcr, read_word(hpcr) | HPCR_HP_TS);
+    assign(hpcr, read_word(hpcr) | HPCR_RTC_EN);
+    while read_word(hpcr) & HPCR_RTC_EN == 0 {}
+}
+
+/// The two halves can't be read at once, so read
+/// until two readings in a row agree.
+fn read_rtc_stable() -> u64 {
+    let mut previous = read_rtc();
+    loop {
+        let current = read_rtc();
+        if current == previous {
+            return current;
+        }
+
+        previous = current;
+    }
+}
+
+fn read_rtc() -> u64 {
+    let high = read_word(addrs::SNVS + HPRTCMR) as u64;
+    let low = read_word(addrs::SNVS + HPRTCLR) as u64;
+    return (high << 32) | low;
+}
+
+fn handle_alarm() {
+    // An alarm only goes off once
+    rtc_alarm_cancel();
+
+    match unsafe { ALARM_HANDLER } {
+        None => {}
+        Some(func) => func(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        return DateTime::new(year, month, day, hour, minute, second).unwrap();
+    }
+
+    #[test]
+    fn test_leap_years() {
+        assert!(is_leap_year(2024));
+        assert!(is_leap_year(2000));
+        assert!(!is_leap_year(1900));
+        assert!(!is_leap_year(2023));
+        assert_eq!(days_in_month(2024, 2), 29);
+        assert_eq!(days_in_month(2100, 2), 28);
+        assert_eq!(days_in_month(2023, 4), 30);
+
+        assert!(DateTime::new(2024, 2, 29, 0, 0, 0).is_some());
+        assert!(DateTime::new(2023, 2, 29, 0, 0, 0).is_none());
+        assert!(DateTime::new(2023, 13, 1, 0, 0, 0).is_none());
+        assert!(DateTime::new(2023, 1, 1, 24, 0, 0).is_none());
+        assert!(DateTime::new(1969, 12, 31, 0, 0, 0).is_none());
+        assert!(DateTime::new(9999, 12, 31, 23, 59, 59).is_some());
+        assert!(DateTime::new(10000, 1, 1, 0, 0, 0).is_none());
+    }
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(DateTime::from_timestamp(0), date(1970, 1, 1, 0, 0, 0));
+        assert_eq!(date(2000, 1, 1, 0, 0, 0).to_timestamp(), 946_684_800);
+        assert_eq!(rtc_ticks_to_nanos((3 << 15) | 16_384), 3_500_000_000);
+        assert_eq!(rtc_ticks_to_nanos(1), 30_517);
+        assert_eq!(date(2024, 2, 29, 13, 5, 9).to_timestamp(), 1_709_211_909);
+        assert_eq!(
+            DateTime::from_timestamp(1_709_211_909),
+            date(2024, 2, 29, 13, 5, 9)
+        );
+        assert_eq!(
+            DateTime::from_timestamp(4_107_542_399),
+            date(2100, 2, 28, 23, 59, 59)
+        );
+        assert_eq!(
+            DateTime::from_timestamp(4_107_542_400),
+            date(2100, 3, 1, 0, 0, 0)
+        );
+
+        // The last second of 9999 is as far as it goes
+        let latest = date(9999, 12, 31, 23, 59, 59);
+        assert_eq!(latest.to_timestamp(), LATEST_TIMESTAMP);
+        assert_eq!(DateTime::from_timestamp(LATEST_TIMESTAMP + 1), latest);
+        assert_eq!(DateTime::from_timestamp(u64::MAX), latest);
+        assert_eq!(latest.add_seconds(i64::MAX), latest);
+
+        // Every day from 1970 to 2200 survives the round trip
+        let mut expected = date(1970, 1, 1, 0, 0, 0);
+        for days in 0..84_000u64 {
+            let actual = DateTime::from_timestamp(days * SECONDS_PER_DAY);
+            assert_eq!(actual, expected);
+            assert_eq!(actual.to_timestamp(), days * SECONDS_PER_DAY);
+
+            expected.day += 1;
+            if expected.day > days_in_month(expected.year, expected.month) {
+                expected.day = 1;
+                expected.month += 1;
+                if expected.month > 12 {
+                    expected.month = 1;
+                    expected.year += 1;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_calendar() {
+        assert_eq!(date(1970, 1, 1, 0, 0, 0).day_of_week(), Weekday::Thursday);
+        assert_eq!(date(2000, 2, 29, 0, 0, 0).day_of_week(), Weekday::Tuesday);
+        assert_eq!(date(2024, 12, 29, 0, 0, 0).day_of_week(), Weekday::Sunday);
+        assert_eq!(date(2024, 12, 31, 0, 0, 0).day_of_year(), 366);
+        assert_eq!(date(2023, 3, 1, 0, 0, 0).day_of_year(), 60);
+
+        let leap_day = date(2024, 2, 28, 23, 59, 30);
+        assert_eq!(leap_day.add_seconds(30), date(2024, 2, 29, 0, 0, 0));
+        assert_eq!(leap_day.add_seconds(86_400), date(2024, 2, 29, 23, 59, 30));
+        assert_eq!(
+            date(2024, 1, 1, 0, 0, 0).add_seconds(-1),
+            date(2023, 12, 31, 23, 59, 59)
+        );
+        assert_eq!(
+            date(1970, 1, 1, 0, 0, 5).add_seconds(-10),
+            date(1970, 1, 1, 0, 0, 0)
+        );
+        assert_eq!(leap_day.seconds_since(&date(2024, 2, 29, 0, 0, 0)), -30);
+    }
+
+    #[test]
+    fn test_iso8601() {
+        let text = date(2024, 2, 9, 3, 5, 7).to_iso8601();
+        assert!(text == Str::with_content(b"2024-02-09T03:05:07Z"));
+
+        let mut log = Str::with_content(b"at ");
+        date(1999, 12, 31, 23, 59, 59).write_iso8601(&mut log);
+        assert!(log == Str::with_content(b"at 1999-12-31T23:59:59Z"));
+
+        let text = DateTime::from_timestamp(u64::MAX).to_iso8601();
+        assert!(text == Str::with_content(b"9999-12-31T23:59:59Z"));
+    }
+}
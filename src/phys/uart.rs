@@ -408,9 +408,18 @@ pub fn uart_has_data(device: Device) -> bool {
     return (read_word(addr) & (0x1 << 12)) == 0;
 }
 
+/// The speed of the clock the baud rate is divided down from.
+/// It comes from PLL3 or the 24MHz oscillator, never the ARM
+/// PLL, so baud rates survive the core clock changing speed.
+pub fn uart_clock_hz() -> u32 {
+    let cscdr1 = read_word(0x400F_C024);
+    let source = if cscdr1 & (0x1 << 6) > 0 { 24_000_000 } else { 80_000_000 };
+    return source / ((cscdr1 & 0x3F) + 1);
+}
+
 pub fn uart_baud_rate(device: Device, rate: u32) {
-    // TODO: Explain why this works (if it works)
-    let baud_clock = 80000000; // MHz
+    // Oversample 16 times per bit
+    let baud_clock = uart_clock_hz();
     let sbr = baud_clock / (rate * 16);
     uart_disable(device);
     let addr = get_addr(device) + 0x10;
@@ -0,0 +1,414 @@
+//! This module provides access to the watchdog timers.
+//!
+//! The Teensy-4.0 has three watchdogs. WDOG1 and WDOG2 count
+//! in half second steps, up to 128 seconds, and can raise an
+//! interrupt some time before they reset the device. The RTWDOG
+//! counts the 32kHz low power oscillator, supports window mode
+//! (feeding too early also resets the device) and raises its
+//! interrupt just before the reset.
+//!
+//! ```no_run
+//! use teensycore::phys::watchdog::*;
+//!
+//! watchdog_configure(Watchdog::Wdog1, WatchdogConfig {
+//!     timeout_ms: 2000,
+//!     window_ms: None,
+//!     pre_timeout_ms: Some(500),
+//! }).unwrap();
+//! watchdog_attach(Watchdog::Wdog1, last_words);
+//!
+//! loop {
+//!     watchdog_feed(Watchdog::Wdog1);
+//! }
+//!
+//! fn last_words() {
+//!     // Roughly 500ms until the device resets
+//! }
+//! ```
+//!
+//! Once WDOG1 or WDOG2 has been enabled it can't be turned off
+//! again until the device resets. Most code should use the
+//! `health` module rather than feeding a watchdog directly.
+use crate::phys::irq::*;
+use crate::phys::{addrs, assign, assign_16, read_16, read_word};
+
+// WDOG1 and WDOG2 registers
+const WCR: u32 = 0x0;
+const WSR: u32 = 0x2;
+const WRSR: u32 = 0x4;
+const WICR: u32 = 0x6;
+const WMCR: u32 = 0x8;
+
+// RTWDOG registers
+const CS: u32 = 0x0;
+const CNT: u32 = 0x4;
+const TOVAL: u32 = 0x8;
+const WIN: u32 = 0xC;
+
+const RTWDOG_UNLOCK: u32 = 0xD928_C520;
+const RTWDOG_REFRESH: u32 = 0xB480_A602;
+
+/// The RTWDOG counts the low power oscillator.
+const LPO_HZ: u64 = 32_768;
+
+/// Each step of a WDOG1 or WDOG2 timeout is half a second.
+const WDOG_STEP_MS: u32 = 500;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Watchdog {
+    Wdog1,
+    Wdog2,
+    Rtwdog,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WatchdogError {
+    /// The timeout is longer or shorter than the watchdog can count.
+    TimeoutOutOfRange,
+    /// Only the RTWDOG supports window mode.
+    WindowUnsupported,
+    /// The window has to close before the timeout.
+    WindowTooLong,
+    /// The interrupt has to fire before the timeout.
+    PreTimeoutTooLong,
+    /// `health::health_start` couldn't schedule the timer
+    /// which feeds the watchdog.
+    TimerUnavailable,
+}
+
+pub struct WatchdogConfig {
+    /// How long the watchdog waits to be fed
+    /// before it resets the device.
+    pub timeout_ms: u32,
+    /// RTWDOG only. Feeding the watchdog sooner than this
+    /// after the previous feed resets the device.
+    pub window_ms: Option<u32>,
+    /// Raise the watchdog's interrupt. For WDOG1 and WDOG2 this is
+    /// how long before the reset it fires. The RTWDOG always fires
+    /// its interrupt a moment before the reset.
+    pub pre_timeout_ms: Option<u32>,
+}
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut HANDLERS: [Option<fn()>; 3] = [None; 3];
+
+fn base(dog: Watchdog) -> u32 {
+    return match dog {
+        Watchdog::Wdog1 => addrs::WDOG1,
+        Watchdog::Wdog2 => addrs::WDOG2,
+        Watchdog::Rtwdog => addrs::RTWDOG,
+    };
+}
+
+fn irq(dog: Watchdog) -> Irq {
+    return match dog {
+        Watchdog::Wdog1 => Irq::Wdog1,
+        Watchdog::Wdog2 => Irq::Wdog2,
+        Watchdog::Rtwdog => Irq::Rtwdog,
+    };
+}
+
+/// The WT field for a WDOG1 or WDOG2 timeout, rounded up.
+fn wdog_timeout_field(timeout_ms: u32) -> Result<u16, WatchdogError> {
+    let steps = (timeout_ms + WDOG_STEP_MS - 1) / WDOG_STEP_MS;
+    if steps == 0 || steps > 256 {
+        return Err(WatchdogError::TimeoutOutOfRange);
+    }
+
+    return Ok((steps - 1) as u16);
+}
+
+/// The WICT field for a WDOG1 or WDOG2 pre-timeout interrupt.
+fn wdog_interrupt_field(timeout_ms: u32, pre_timeout_ms: u32) -> Result<u16, WatchdogError> {
+    let steps = pre_timeout_ms / WDOG_STEP_MS;
+    if steps > 255 || pre_timeout_ms >= timeout_ms {
+        return Err(WatchdogError::PreTimeoutTooLong);
+    }
+
+    return Ok(steps as u16);
+}
+
+/// Convert milliseconds into RTWDOG ticks. Returns the count
+/// and whether the divide-by-256 prescaler is needed to reach it.
+fn rtwdog_ticks(ms: u32, prescale: bool) -> u32 {
+    let ticks = ms as u64 * LPO_HZ / 1000;
+    if prescale {
+        return (ticks / 256) as u32;
+    }
+
+    return ticks as u32;
+}
+
+fn rtwdog_timeout(timeout_ms: u32) -> Result<(u32, bool), WatchdogError> {
+    for prescale in [false, true] {
+        let ticks = rtwdog_ticks(timeout_ms, prescale);
+        if ticks > 0xFFFF {
+            continue;
+        } else if ticks == 0 {
+            return Err(WatchdogError::TimeoutOutOfRange);
+        }
+
+        return Ok((ticks, prescale));
+    }
+
+    return Err(WatchdogError::TimeoutOutOfRange);
+}
+
+/// Work out the register values for the RTWDOG. Returns CS, TOVAL and WIN.
+fn rtwdog_registers(config: &WatchdogConfig) -> Result<(u32, u32, u32), WatchdogError> {
+    let (toval, prescale) = rtwdog_timeout(config.timeout_ms)?;
+
+    // Clock from the low power oscillator, 32 bit commands, and
+    // allow updates later on. DBG is left clear so the counter
+    // stops while a debugger has the core halted.
+    let mut cs = (1 << 13) | (0x1 << 8) | (1 << 7) | (1 << 5);
+    if prescale {
+        cs |= 1 << 12;
+    }
+
+    if config.pre_timeout_ms.is_some() {
+        cs |= 1 << 6;
+    }
+
+    let mut win = 0;
+    match config.window_ms {
+        None => {}
+        Some(window_ms) => {
+            if window_ms >= config.timeout_ms {
+                return Err(WatchdogError::WindowTooLong);
+            }
+
+            win = rtwdog_ticks(window_ms, prescale);
+            cs |= 1 << 15;
+        }
+    }
+
+    return Ok((cs, toval, win));
+}
+
+/// Start a watchdog. Once started, it has to be fed with
+/// `watchdog_feed()` more often than `timeout_ms`.
+pub fn watchdog_configure(dog: Watchdog, config: WatchdogConfig) -> Result<(), WatchdogError> {
+    match dog {
+        Watchdog::Wdog1 | Watchdog::Wdog2 => {
+            if config.window_ms.is_some() {
+                return Err(WatchdogError::WindowUnsupported);
+            }
+
+            let timeout = wdog_timeout_field(config.timeout_ms)?;
+            let interrupt = match config.pre_timeout_ms {
+                None => None,
+                Some(pre_timeout_ms) => {
+                    Some(wdog_interrupt_field(config.timeout_ms, pre_timeout_ms)?)
+                }
+            };
+
+            watchdog_clock(dog);
+            let base = base(dog);
+
+            // The power down counter resets the device 16
+            // seconds after boot unless it is turned off.
+            assign_16(base + WMCR, 0);
+
+            match interrupt {
+                None => {}
+                Some(count) => {
+                    assign_16(base + WICR, (1 << 15) | (1 << 14) | count);
+                }
+            }
+
+            // Timeout, leave the software reset alone, assert the
+            // reset output, enable, and pause while debugging.
+            let wcr = (timeout << 8) | (1 << 5) | (1 << 4) | (1 << 3) | (1 << 2) | (1 << 1);
+            assign_16(base + WCR, wcr);
+            watchdog_feed(dog);
+        }
+        Watchdog::Rtwdog => {
+            let (cs, toval, win) = rtwdog_registers(&config)?;
+            watchdog_clock(dog);
+            let base = base(dog);
+
+            // The new configuration has to land within a
+            // few clocks of unlocking, so nothing can interrupt.
+            let _cs = CriticalSection::new();
+            assign(base + CNT, RTWDOG_UNLOCK);
+            while read_word(base + CS) & (1 << 11) == 0 {}
+            assign(base + TOVAL, toval);
+            assign(base + WIN, win);
+            assign(base + CS, cs | (1 << 14));
+            while read_word(base + CS) & (1 << 10) == 0 {}
+        }
+    }
+
+    return Ok(());
+}
+
+/// Tell a watchdog that everything is fine. In window mode,
+/// feeding too soon is just as bad as feeding too late.
+pub fn watchdog_feed(dog: Watchdog) {
+    let base = base(dog);
+    match dog {
+        Watchdog::Wdog1 | Watchdog::Wdog2 => {
+            let _cs = CriticalSection::new();
+            assign_16(base + WSR, 0x5555);
+            assign_16(base + WSR, 0xAAAA);
+        }
+        Watchdog::Rtwdog => {
+            assign(base + CNT, RTWDOG_REFRESH);
+        }
+    }
+}
+
+/// Turn off the RTWDOG. WDOG1 and WDOG2 can't be turned off
+/// once they are running, so this does nothing for them.
+pub fn watchdog_disable(dog: Watchdog) {
+    match dog {
+        Watchdog::Wdog1 | Watchdog::Wdog2 => {}
+        Watchdog::Rtwdog => {
+            let base = base(dog);
+            let _cs = CriticalSection::new();
+            assign(base + CNT, RTWDOG_UNLOCK);
+            while read_word(base + CS) & (1 << 11) == 0 {}
+            assign(base + TOVAL, 0xFFFF);
+            assign(base + CS, (read_word(base + CS) & !(1 << 7)) | (1 << 5));
+        }
+    }
+}
+
+/// Run `func` when the watchdog's interrupt fires, which
+/// is the last chance to save anything before the reset.
+pub fn watchdog_attach(dog: Watchdog, func: fn()) {
+    unsafe {
+        HANDLERS[dog as usize] = Some(func);
+    }
+
+    let handler = match dog {
+        Watchdog::Wdog1 => handle_wdog1 as fn(),
+        Watchdog::Wdog2 => handle_wdog2 as fn(),
+        Watchdog::Rtwdog => handle_rtwdog as fn(),
+    };
+
+    irq_attach(irq(dog), handler);
+    irq_priority(irq(dog), 0);
+    irq_enable(irq(dog));
+}
+
+/// Returns true if the last reset was caused by this watchdog
+/// running out. Only WDOG1 and WDOG2 keep track of this.
+pub fn watchdog_caused_reset(dog: Watchdog) -> bool {
+    return match dog {
+        Watchdog::Wdog1 | Watchdog::Wdog2 => read_16(base(dog) + WRSR) & (1 << 1) > 0,
+        Watchdog::Rtwdog => false,
+    };
+}
+
+fn watchdog_clock(dog: Watchdog) {
+    let (register, shift) = match dog {
+        Watchdog::Wdog1 => (addrs::CCM_CCGR3, 16),
+        Watchdog::Wdog2 => (addrs::CCM_CCGR5, 10),
+        Watchdog::Rtwdog => (addrs::CCM_CCGR5, 4),
+    };
+
+    assign(register, read_word(register) | (0x3 << shift));
+}
+
+fn handle_interrupt(dog: Watchdog) {
+    let base = base(dog);
+    match dog {
+        Watchdog::Wdog1 | Watchdog::Wdog2 => {
+            assign_16(base + WICR, read_16(base + WICR) | (1 << 14));
+        }
+        Watchdog::Rtwdog => {
+            assign(base + CS, read_word(base + CS) | (1 << 14));
+        }
+    }
+
+    match unsafe { HANDLERS[dog as usize] } {
+        None => {}
+        Some(func) => func(),
+    }
+}
+
+fn handle_wdog1() {
+    handle_interrupt(Watchdog::Wdog1);
+}
+
+fn handle_wdog2() {
+    handle_interrupt(Watchdog::Wdog2);
+}
+
+fn handle_rtwdog() {
+    handle_interrupt(Watchdog::Rtwdog);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wdog_fields() {
+        assert_eq!(wdog_timeout_field(500), Ok(0));
+        assert_eq!(wdog_timeout_field(1200), Ok(2));
+        assert_eq!(wdog_timeout_field(128_000), Ok(255));
+        assert_eq!(wdog_timeout_field(0), Err(WatchdogError::TimeoutOutOfRange));
+        assert_eq!(
+            wdog_timeout_field(128_001),
+            Err(WatchdogError::TimeoutOutOfRange)
+        );
+
+        assert_eq!(wdog_interrupt_field(2000, 500), Ok(1));
+        assert_eq!(
+            wdog_interrupt_field(2000, 2000),
+            Err(WatchdogError::PreTimeoutTooLong)
+        );
+    }
+
+    #[test]
+    fn test_rtwdog_registers() {
+        // One second fits without the prescaler
+        let (cs, toval, win) = rtwdog_registers(&WatchdogConfig {
+            timeout_ms: 1000,
+            window_ms: Some(250),
+            pre_timeout_ms: None,
+        })
+        .unwrap();
+        assert_eq!(toval, 32_768);
+        assert_eq!(win, 8192);
+        assert_eq!(cs & (1 << 15), 1 << 15);
+        assert_eq!(cs & (1 << 12), 0);
+        assert_eq!(cs & (1 << 6), 0);
+        assert_eq!(cs & (1 << 2), 0);
+
+        // A minute needs it
+        let (cs, toval, win) = rtwdog_registers(&WatchdogConfig {
+            timeout_ms: 60_000,
+            window_ms: None,
+            pre_timeout_ms: Some(0),
+        })
+        .unwrap();
+        assert_eq!(toval, 7680);
+        assert_eq!(win, 0);
+        assert_eq!(cs & (1 << 12), 1 << 12);
+        assert_eq!(cs & (1 << 6), 1 << 6);
+
+        let too_long = WatchdogConfig {
+            timeout_ms: 600_000,
+            window_ms: None,
+            pre_timeout_ms: None,
+        };
+        assert_eq!(
+            rtwdog_registers(&too_long),
+            Err(WatchdogError::TimeoutOutOfRange)
+        );
+
+        let bad_window = WatchdogConfig {
+            timeout_ms: 1000,
+            window_ms: Some(1000),
+            pre_timeout_ms: None,
+        };
+        assert_eq!(
+            rtwdog_registers(&bad_window),
+            Err(WatchdogError::WindowTooLong)
+        );
+    }
+}
@@ -20,21 +20,27 @@
 //! serial_write(SerioDevice::Uart6, b"Hello, world!\r\n");
 //!
 //! while serial_available(SerioDevice::Uart6) > 0 {
-//!     let sb = serial_read(SerioDevice::Uart6);
+//!     let mut sb = serial_read(SerioDevice::Uart6);
 //!     // Do something with the Str
+//!     sb.drop();
 //! }
 //! ```
 
 #![allow(unused)]
 
 use crate::debug::*;
+use crate::executor::IrqWaker;
 use crate::phys::addrs;
 use crate::phys::irq::*;
 use crate::phys::pins::*;
 use crate::phys::uart::*;
 use crate::system::buffer::*;
+use crate::system::irq_mutex::*;
 use crate::system::str::*;
 use crate::system::vector::*;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
 
 struct HardwareConfig {
     device: Device,
@@ -48,79 +54,79 @@ struct HardwareConfig {
 static mut TEMP_BUF: [u8; 128] = [0; 128];
 const UART_WATERMARK_SIZE: u32 = 0x2;
 const UART_BUFFER_DEPTH: usize = 512; // Note: this is repeated for every uart device. Don't make it too big.
-static mut UART1: Uart = Uart::new(HardwareConfig {
+static UART1: IrqMutex<Uart> = IrqMutex::new(Uart::new(HardwareConfig {
     device: Device::Uart1,
     tx_pin: 24,
     rx_pin: 25,
     irq: Irq::Uart1,
     sel_inp_reg: None,
     sel_inp_val: None,
-});
+}));
 
-static mut UART2: Uart = Uart::new(HardwareConfig {
+static UART2: IrqMutex<Uart> = IrqMutex::new(Uart::new(HardwareConfig {
     device: Device::Uart2,
     tx_pin: 14,
     rx_pin: 15,
     irq: Irq::Uart2,
     sel_inp_reg: Some(addrs::IOMUXC_LPUART2_RX_SELECT_INPUT),
     sel_inp_val: Some(0x1),
-});
+}));
 
-static mut UART3: Uart = Uart::new(HardwareConfig {
+static UART3: IrqMutex<Uart> = IrqMutex::new(Uart::new(HardwareConfig {
     device: Device::Uart3,
     tx_pin: 17,
     rx_pin: 16,
     irq: Irq::Uart3,
     sel_inp_reg: Some(addrs::IOMUXC_LPUART3_RX_SELECT_INPUT),
     sel_inp_val: Some(0x0),
-});
+}));
 
-static mut UART4: Uart = Uart::new(HardwareConfig {
+static UART4: IrqMutex<Uart> = IrqMutex::new(Uart::new(HardwareConfig {
     device: Device::Uart4,
     tx_pin: 8,
     rx_pin: 7,
     irq: Irq::Uart4,
     sel_inp_reg: Some(addrs::IOMUXC_LPUART4_RX_SELECT_INPUT),
     sel_inp_val: Some(0x2),
-});
+}));
 
-static mut UART5: Uart = Uart::new(HardwareConfig {
+static UART5: IrqMutex<Uart> = IrqMutex::new(Uart::new(HardwareConfig {
     device: Device::Uart5,
     tx_pin: 1,
     rx_pin: 0,
     irq: Irq::Uart5,
     sel_inp_reg: Some(addrs::IOMUXC_LPUART5_RX_SELECT_INPUT),
     sel_inp_val: Some(0x0),
-}); // NOTE: THIS DEVICE DOESN'T HAVE VALID PINS
+})); // NOTE: THIS DEVICE DOESN'T HAVE VALID PINS
 
-static mut UART6: Uart = Uart::new(HardwareConfig {
+static UART6: IrqMutex<Uart> = IrqMutex::new(Uart::new(HardwareConfig {
     device: Device::Uart6,
     tx_pin: 1,
     rx_pin: 0,
     irq: Irq::Uart6,
     sel_inp_reg: Some(addrs::IOMUXC_LPUART6_RX_SELECT_INPUT),
     sel_inp_val: Some(0x1),
-});
+}));
 
-static mut UART7: Uart = Uart::new(HardwareConfig {
+static UART7: IrqMutex<Uart> = IrqMutex::new(Uart::new(HardwareConfig {
     device: Device::Uart7,
     tx_pin: 29,
     rx_pin: 28,
     irq: Irq::Uart7,
     sel_inp_reg: Some(addrs::IOMUXC_LPUART7_RX_SELECT_INPUT),
     sel_inp_val: Some(0x1),
-});
+}));
 
-static mut UART8: Uart = Uart::new(HardwareConfig {
+static UART8: IrqMutex<Uart> = IrqMutex::new(Uart::new(HardwareConfig {
     device: Device::Uart8,
     tx_pin: 20,
     rx_pin: 21,
     irq: Irq::Uart8,
     sel_inp_reg: Some(addrs::IOMUXC_LPUART8_RX_SELECT_INPUT),
     sel_inp_val: Some(0x0),
-});
+}));
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SerioDevice {
     Uart1 = 0x0,
     Uart2 = 0x1,
@@ -151,8 +157,14 @@ struct Uart {
     buffer_head: usize,
     tx_count: u32,
     paused: bool,
+    rx_waker: IrqWaker,
 }
 
+// Every uart lives behind an IrqMutex, which
+// is the only way to reach it from the main loop
+// or from an interrupt handler.
+unsafe impl Send for Uart {}
+
 impl Uart {
     pub const fn new(config: HardwareConfig) -> Uart {
         return Uart {
@@ -171,6 +183,7 @@ impl Uart {
             irq: config.irq,
             tx_count: 0,
             paused: false,
+            rx_waker: IrqWaker::new(),
         };
     }
 
@@ -331,6 +344,10 @@ impl Uart {
             count += 1;
         }
 
+        if count > 0 {
+            self.rx_waker.wake();
+        }
+
         if rx_overrun {
             crate::debug::blink_accumulate();
         }
@@ -373,22 +390,25 @@ impl Uart {
     }
 }
 
-fn get_uart_interface(device: SerioDevice) -> &'static mut Uart {
-    unsafe {
-        return match device {
-            SerioDevice::Uart1 => &mut UART1,
-            SerioDevice::Uart2 => &mut UART2,
-            SerioDevice::Uart3 => &mut UART3,
-            SerioDevice::Uart4 => &mut UART4,
-            SerioDevice::Uart5 => &mut UART5,
-            SerioDevice::Uart6 => &mut UART6,
-            SerioDevice::Uart7 => &mut UART7,
-            SerioDevice::Uart8 => &mut UART8,
-
-            // Specify defaut here
-            SerioDevice::Default => &mut UART6,
-        };
-    }
+fn get_uart_interface(device: SerioDevice) -> &'static IrqMutex<Uart> {
+    return match device {
+        SerioDevice::Uart1 => &UART1,
+        SerioDevice::Uart2 => &UART2,
+        SerioDevice::Uart3 => &UART3,
+        SerioDevice::Uart4 => &UART4,
+        SerioDevice::Uart5 => &UART5,
+        SerioDevice::Uart6 => &UART6,
+        SerioDevice::Uart7 => &UART7,
+        SerioDevice::Uart8 => &UART8,
+
+        // Specify defaut here
+        SerioDevice::Default => &UART6,
+    };
+}
+
+/// Run some code against a uart with interrupts held off.
+fn with_uart<R, F: FnOnce(&mut Uart) -> R>(device: SerioDevice, func: F) -> R {
+    return get_uart_interface(device).lock(func);
 }
 
 /// Initializes the serial device. This configures
@@ -396,26 +416,71 @@ fn get_uart_interface(device: SerioDevice) -> &'static mut Uart {
 /// enables peripheral device, and generally
 /// wakes up the uart.
 pub fn serial_init(device: SerioDevice) {
-    let uart = get_uart_interface(device);
-    uart.initialize();
+    with_uart(device, |uart| uart.initialize());
 }
 
-/// Retuns the current buffer of data the serial interface
-/// has accumulated.
+/// Takes the data the serial interface has accumulated, and
+/// leaves the interface with an empty buffer to fill.
 ///
-/// You can interact with this buffer, modify it, etc. It is
-/// recommended to call `.clear()` on the buffer as soon as you are
-/// done with the data, otherwise the buffer will eventually
-/// overflow.
-pub fn serial_read<'a>(device: SerioDevice) -> &'a mut Str {
-    let uart = get_uart_interface(device);
-    return uart.get_rx_buffer();
+/// The data is yours to keep, so call `.drop()` on it as
+/// soon as you are done with it. Nothing the interrupt handler
+/// receives afterwards ends up in it.
+pub fn serial_read(device: SerioDevice) -> Str {
+    return with_uart(device, |uart| {
+        let data = *uart.get_rx_buffer();
+        *uart.get_rx_buffer() = Str::new();
+        return data;
+    });
+}
+
+/// The same as `serial_read` but waits for data to arrive
+/// before taking it.
+///
+/// ```no_run
+/// use teensycore::executor::*;
+/// use teensycore::serio::*;
+///
+/// spawn(async {
+///     let mut buffer = serial_read_async(SerioDevice::Default).await;
+///     buffer.drop();
+/// });
+/// ```
+pub fn serial_read_async(device: SerioDevice) -> SerialRead {
+    return SerialRead { device: device };
+}
+
+/// A future which waits for a serial device to receive data.
+pub struct SerialRead {
+    device: SerioDevice,
+}
+
+impl Future for SerialRead {
+    type Output = Str;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // With interrupts held off, nothing can arrive
+        // between checking and registering.
+        let ready = with_uart(self.device, |uart| {
+            if uart.available() > 0 {
+                return true;
+            }
+
+            uart.rx_waker.register(cx.waker());
+            uart.rx_waker.take();
+            return false;
+        });
+
+        if !ready {
+            return Poll::Pending;
+        }
+
+        return Poll::Ready(serial_read(self.device));
+    }
 }
 
 /// Returns the amount of data in the currenet read buffer.
 pub fn serial_available(device: SerioDevice) -> usize {
-    let uart = get_uart_interface(device);
-    return uart.available();
+    return with_uart(device, |uart| uart.available());
 }
 
 /// Enqueue data to be written over serial.
@@ -423,22 +488,23 @@ pub fn serial_available(device: SerioDevice) -> usize {
 /// This data will be written at the next available interrupt
 /// cycle.
 pub fn serial_write(device: SerioDevice, bytes: &[u8]) {
-    let uart = get_uart_interface(device);
-    uart.write(bytes);
+    with_uart(device, |uart| uart.write(bytes));
 }
 
 pub fn serial_write_vec(device: SerioDevice, bytes: &Vector<u8>) {
-    let uart = get_uart_interface(device);
-    for byte in bytes.into_iter() {
-        uart.write(&[byte]);
-    }
+    with_uart(device, |uart| {
+        for byte in bytes.into_iter() {
+            uart.write(&[byte]);
+        }
+    });
 }
 
 pub fn serial_write_str(device: SerioDevice, bytes: &mut Str) {
-    let uart = get_uart_interface(device);
-    for byte in bytes.into_iter() {
-        uart.write(&[byte]);
-    }
+    with_uart(device, |uart| {
+        for byte in bytes.into_iter() {
+            uart.write(&[byte]);
+        }
+    });
 
     // Fixes memory leak. When calling this function you'll
     // usually be operating with an intermediary string and
@@ -447,8 +513,13 @@ pub fn serial_write_str(device: SerioDevice, bytes: &mut Str) {
 }
 
 pub fn serial_baud(device: SerioDevice, rate: u32) {
-    let uart = get_uart_interface(device);
-    uart_baud_rate(uart.device, rate);
+    let uart_device = with_uart(device, |uart| uart.device);
+    uart_baud_rate(uart_device, rate);
+}
+
+/// The pin which a serial device receives on.
+pub fn serial_rx_pin(device: SerioDevice) -> usize {
+    return with_uart(device, |uart| uart.rx_pin);
 }
 
 pub fn serio_handle_irq() {
@@ -461,14 +532,14 @@ pub fn serio_handle_irq() {
     irq_disable(Irq::Uart7);
     irq_disable(Irq::Uart8);
 
-    get_uart_interface(SerioDevice::Uart1).handle_irq();
-    get_uart_interface(SerioDevice::Uart2).handle_irq();
-    get_uart_interface(SerioDevice::Uart3).handle_irq();
-    get_uart_interface(SerioDevice::Uart4).handle_irq();
-    get_uart_interface(SerioDevice::Uart5).handle_irq();
-    get_uart_interface(SerioDevice::Uart6).handle_irq();
-    get_uart_interface(SerioDevice::Uart7).handle_irq();
-    get_uart_interface(SerioDevice::Uart8).handle_irq();
+    with_uart(SerioDevice::Uart1, |uart| uart.handle_irq());
+    with_uart(SerioDevice::Uart2, |uart| uart.handle_irq());
+    with_uart(SerioDevice::Uart3, |uart| uart.handle_irq());
+    with_uart(SerioDevice::Uart4, |uart| uart.handle_irq());
+    with_uart(SerioDevice::Uart5, |uart| uart.handle_irq());
+    with_uart(SerioDevice::Uart6, |uart| uart.handle_irq());
+    with_uart(SerioDevice::Uart7, |uart| uart.handle_irq());
+    with_uart(SerioDevice::Uart8, |uart| uart.handle_irq());
 
     irq_enable(Irq::Uart1);
     irq_enable(Irq::Uart2);
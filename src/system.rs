@@ -3,8 +3,12 @@
 
 pub mod boxed;
 pub mod buffer;
+pub mod channel;
 pub mod closure;
+pub mod event_flags;
+pub mod irq_mutex;
 pub mod map;
 pub mod observable;
+pub mod semaphore;
 pub mod str;
 pub mod vector;
\ No newline at end of file
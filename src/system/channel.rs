@@ -0,0 +1,363 @@
+//! Fixed capacity message passing between interrupt
+//! handlers and the main loop.
+//!
+//! A `Channel` has exactly one producer and one consumer,
+//! typically an interrupt handler feeding the main loop.
+//! An `MpscQueue` can be fed from any number of interrupt
+//! handlers at once, still with a single consumer.
+//!
+//! Neither one disables interrupts or allocates. Sending into
+//! a full channel fails and counts an overflow instead of
+//! blocking, so it is always safe to call from an interrupt.
+//!
+//! ```no_run
+//! use teensycore::gate::*;
+//! use teensycore::prelude::*;
+//! use teensycore::system::channel::*;
+//! # static mut GATES: BTreeMap<u32, u32> = BTreeMap { root: None };
+//!
+//! static RX: Channel<64, u8> = Channel::new();
+//!
+//! fn handle_irq() {
+//!     RX.try_send(b'!').ok();
+//! }
+//!
+//! fn handle_rx() {
+//!     while let Some(byte) = RX.try_recv() {
+//!         // ...
+//!     }
+//! }
+//!
+//! gate_open!()
+//!     .when_ready(&RX, handle_rx)
+//!     .compile();
+//! ```
+//!
+//! `SIZE` must be a power of two, so that the read and write
+//! counters keep lining up with the slots when they wrap.
+//! Anything else fails to compile:
+//!
+//! ```compile_fail
+//! use teensycore::system::channel::*;
+//!
+//! static RX: Channel<48, u8> = Channel::new();
+//! ```
+use crate::gate::Signal;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// A single-producer, single-consumer channel.
+pub struct Channel<const SIZE: usize, T> {
+    data: [UnsafeCell<MaybeUninit<T>>; SIZE],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    overflows: AtomicU32,
+}
+
+// The producer only ever writes slots the consumer is done
+// with, and the consumer only reads slots the producer has
+// published, so the channel can be shared.
+unsafe impl<const SIZE: usize, T: Send> Sync for Channel<SIZE, T> {}
+
+impl<const SIZE: usize, T: Copy> Channel<SIZE, T> {
+    pub const fn new() -> Self {
+        const { assert!(SIZE.is_power_of_two(), "SIZE must be a power of two") };
+        return Channel {
+            data: [const { UnsafeCell::new(MaybeUninit::uninit()) }; SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overflows: AtomicU32::new(0),
+        };
+    }
+
+    /// Send an item to the consumer. If the channel is full
+    /// the item is handed back and the overflow counter goes up.
+    pub fn try_send(&self, item: T) -> Result<(), T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) >= SIZE {
+            self.overflows.fetch_add(1, Ordering::Relaxed);
+            return Err(item);
+        }
+
+        unsafe { (*self.data[head % SIZE].get()).write(item) };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        return Ok(());
+    }
+
+    /// Take the oldest item out of the channel, if there is one.
+    pub fn try_recv(&self) -> Option<T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        let item = unsafe { (*self.data[tail % SIZE].get()).assume_init() };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        return Some(item);
+    }
+
+    /// The number of items waiting to be received.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        return head.wrapping_sub(tail);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn capacity(&self) -> usize {
+        return SIZE;
+    }
+
+    /// How many items have been dropped because
+    /// the channel was full.
+    pub fn overflows(&self) -> u32 {
+        return self.overflows.load(Ordering::Relaxed);
+    }
+}
+
+impl<const SIZE: usize, T: Copy> Signal for Channel<SIZE, T> {
+    fn is_ready(&self) -> bool {
+        return !self.is_empty();
+    }
+}
+
+struct Slot<T> {
+    // Stored relative to the slot's own index,
+    // so that every slot can start out at zero.
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A multi-producer, single-consumer queue. Any number of
+/// interrupt handlers, at any priority, can send at once.
+pub struct MpscQueue<const SIZE: usize, T> {
+    slots: [Slot<T>; SIZE],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    overflows: AtomicU32,
+}
+
+// Producers claim a slot before touching it, and only
+// hand it to the consumer once the item is written.
+unsafe impl<const SIZE: usize, T: Send> Sync for MpscQueue<SIZE, T> {}
+
+impl<const SIZE: usize, T: Copy> MpscQueue<SIZE, T> {
+    pub const fn new() -> Self {
+        const { assert!(SIZE.is_power_of_two(), "SIZE must be a power of two") };
+        return MpscQueue {
+            slots: [const {
+                Slot {
+                    sequence: AtomicUsize::new(0),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                }
+            }; SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overflows: AtomicU32::new(0),
+        };
+    }
+
+    fn sequence(&self, position: usize) -> usize {
+        let index = position % SIZE;
+        return self.slots[index].sequence.load(Ordering::Acquire).wrapping_add(index);
+    }
+
+    fn publish(&self, position: usize, sequence: usize) {
+        let index = position % SIZE;
+        self.slots[index].sequence.store(sequence.wrapping_sub(index), Ordering::Release);
+    }
+
+    /// Send an item to the consumer. If the queue is full
+    /// the item is handed back and the overflow counter goes up.
+    pub fn try_send(&self, item: T) -> Result<(), T> {
+        let mut position = self.head.load(Ordering::Relaxed);
+        loop {
+            let difference = self.sequence(position).wrapping_sub(position) as isize;
+            if difference == 0 {
+                // The slot is free, try to claim it
+                match self.head.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*self.slots[position % SIZE].value.get()).write(item) };
+                        self.publish(position, position.wrapping_add(1));
+                        return Ok(());
+                    }
+                    Err(current) => {
+                        position = current;
+                    }
+                }
+            } else if difference < 0 {
+                // The consumer hasn't caught up with this slot
+                self.overflows.fetch_add(1, Ordering::Relaxed);
+                return Err(item);
+            } else {
+                // Another producer got here first
+                position = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Take the oldest item out of the queue, if there is one.
+    pub fn try_recv(&self) -> Option<T> {
+        let position = self.tail.load(Ordering::Relaxed);
+        if self.sequence(position) != position.wrapping_add(1) {
+            return None;
+        }
+
+        let item = unsafe { (*self.slots[position % SIZE].value.get()).assume_init() };
+        self.publish(position, position.wrapping_add(SIZE));
+        self.tail.store(position.wrapping_add(1), Ordering::Release);
+        return Some(item);
+    }
+
+    /// The number of items waiting to be received, including
+    /// any a producer is still in the middle of writing.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        return head.wrapping_sub(tail);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn capacity(&self) -> usize {
+        return SIZE;
+    }
+
+    /// How many items have been dropped because
+    /// the queue was full.
+    pub fn overflows(&self) -> u32 {
+        return self.overflows.load(Ordering::Relaxed);
+    }
+}
+
+impl<const SIZE: usize, T: Copy> Signal for MpscQueue<SIZE, T> {
+    fn is_ready(&self) -> bool {
+        return !self.is_empty();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_channel() {
+        let channel = Channel::<4, u32>::new();
+        assert_eq!(channel.try_recv(), None);
+
+        for item in 0..4 {
+            assert_eq!(channel.try_send(item), Ok(()));
+        }
+        assert_eq!(channel.try_send(4), Err(4));
+        assert_eq!(channel.overflows(), 1);
+        assert_eq!(channel.len(), 4);
+
+        assert_eq!(channel.try_recv(), Some(0));
+        assert_eq!(channel.try_send(5), Ok(()));
+        for item in [1, 2, 3, 5] {
+            assert_eq!(channel.try_recv(), Some(item));
+        }
+        assert!(channel.is_empty());
+    }
+
+    #[test]
+    fn test_channel_threads() {
+        static CHANNEL: Channel<16, u32> = Channel::new();
+        const COUNT: u32 = 100_000;
+
+        let producer = thread::spawn(|| {
+            for item in 0..COUNT {
+                while CHANNEL.try_send(item).is_err() {
+                    thread::yield_now();
+                }
+            }
+        });
+
+        // Every item arrives, in order
+        let mut expected = 0;
+        while expected < COUNT {
+            match CHANNEL.try_recv() {
+                None => thread::yield_now(),
+                Some(item) => {
+                    assert_eq!(item, expected);
+                    expected += 1;
+                }
+            }
+        }
+
+        producer.join().unwrap();
+        assert_eq!(CHANNEL.try_recv(), None);
+    }
+
+    #[test]
+    fn test_queue() {
+        let queue = MpscQueue::<4, u32>::new();
+        assert_eq!(queue.try_recv(), None);
+
+        // Go around the ring a few times
+        for round in 0..3 {
+            for item in 0..4 {
+                assert_eq!(queue.try_send(round * 10 + item), Ok(()));
+            }
+            assert_eq!(queue.try_send(99), Err(99));
+            for item in 0..4 {
+                assert_eq!(queue.try_recv(), Some(round * 10 + item));
+            }
+            assert_eq!(queue.try_recv(), None);
+        }
+
+        assert_eq!(queue.overflows(), 3);
+    }
+
+    #[test]
+    fn test_queue_threads() {
+        static QUEUE: MpscQueue<8, u32> = MpscQueue::new();
+        const PRODUCERS: u32 = 4;
+        const COUNT: u32 = 20_000;
+
+        let mut producers = std::vec::Vec::new();
+        for producer in 0..PRODUCERS {
+            producers.push(thread::spawn(move || {
+                for item in 0..COUNT {
+                    while QUEUE.try_send(producer * COUNT + item).is_err() {
+                        thread::yield_now();
+                    }
+                }
+            }));
+        }
+
+        // Items from each producer arrive in the order they were sent
+        let mut next = [0u32; PRODUCERS as usize];
+        let mut received = 0;
+        while received < PRODUCERS * COUNT {
+            match QUEUE.try_recv() {
+                None => thread::yield_now(),
+                Some(item) => {
+                    let producer = (item / COUNT) as usize;
+                    assert_eq!(item % COUNT, next[producer]);
+                    next[producer] += 1;
+                    received += 1;
+                }
+            }
+        }
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert!(QUEUE.is_empty());
+    }
+}
@@ -0,0 +1,329 @@
+//! A group of 32 event flags.
+//!
+//! Interrupt handlers set flags, and the rest of the system
+//! waits for any or all of some set of them. Waiting can
+//! optionally clear the flags it was waiting on, so that
+//! each event is only handled once.
+//!
+//! ```no_run
+//! use teensycore::executor::*;
+//! use teensycore::gate::*;
+//! use teensycore::prelude::*;
+//! use teensycore::system::event_flags::*;
+//! # static mut GATES: BTreeMap<u32, u32> = BTreeMap { root: None };
+//!
+//! const RX_DONE: u32 = 1 << 0;
+//! const TX_DONE: u32 = 1 << 1;
+//!
+//! static EVENTS: EventFlags = EventFlags::new();
+//! static TRANSFER_DONE: EventMask = EVENTS.all(RX_DONE | TX_DONE, true);
+//!
+//! fn handle_rx_irq() {
+//!     EVENTS.set(RX_DONE);
+//! }
+//!
+//! gate_open!()
+//!     .when_ready(&TRANSFER_DONE, start_next_transfer)
+//!     .compile();
+//!
+//! spawn(async {
+//!     let flags = EVENTS.wait_any(RX_DONE | TX_DONE, false).await;
+//! });
+//!
+//! fn start_next_transfer() {}
+//! ```
+use crate::executor::IrqWakers;
+use crate::gate::Signal;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll};
+
+pub struct EventFlags {
+    flags: AtomicU32,
+    wakers: IrqWakers,
+}
+
+impl EventFlags {
+    pub const fn new() -> Self {
+        return EventFlags {
+            flags: AtomicU32::new(0),
+            wakers: IrqWakers::new(),
+        };
+    }
+
+    /// Set some flags. Safe to call from an interrupt handler.
+    pub fn set(&self, bits: u32) {
+        self.flags.fetch_or(bits, Ordering::AcqRel);
+        self.wakers.wake_all();
+    }
+
+    /// Clear some flags.
+    pub fn clear(&self, bits: u32) {
+        self.flags.fetch_and(!bits, Ordering::AcqRel);
+    }
+
+    /// Returns every flag which is currently set.
+    pub fn get(&self) -> u32 {
+        return self.flags.load(Ordering::Acquire);
+    }
+
+    /// If any flag in `mask` is set, returns those of them which
+    /// are. With `clear`, the returned flags are cleared as well.
+    pub fn try_wait_any(&self, mask: u32, clear: bool) -> Option<u32> {
+        return self.try_wait(mask, false, clear);
+    }
+
+    /// If every flag in `mask` is set, returns them. With
+    /// `clear`, they are cleared as well.
+    pub fn try_wait_all(&self, mask: u32, clear: bool) -> Option<u32> {
+        return self.try_wait(mask, true, clear);
+    }
+
+    /// Returns a future which completes once any flag in `mask`
+    /// is set. Every waiting task is woken by a `set`, and checks
+    /// its own mask.
+    pub fn wait_any(&self, mask: u32, clear: bool) -> EventWait<'_> {
+        return EventWait {
+            flags: self,
+            mask: mask,
+            all: false,
+            clear: clear,
+        };
+    }
+
+    /// Returns a future which completes once every
+    /// flag in `mask` is set.
+    pub fn wait_all(&self, mask: u32, clear: bool) -> EventWait<'_> {
+        return EventWait {
+            flags: self,
+            mask: mask,
+            all: true,
+            clear: clear,
+        };
+    }
+
+    /// Something for a gate stage to wait on, which is
+    /// ready once any flag in `mask` is set.
+    pub const fn any(&'static self, mask: u32, clear: bool) -> EventMask {
+        return EventMask {
+            flags: self,
+            mask: mask,
+            all: false,
+            clear: clear,
+        };
+    }
+
+    /// Something for a gate stage to wait on, which is
+    /// ready once every flag in `mask` is set.
+    pub const fn all(&'static self, mask: u32, clear: bool) -> EventMask {
+        return EventMask {
+            flags: self,
+            mask: mask,
+            all: true,
+            clear: clear,
+        };
+    }
+
+    fn try_wait(&self, mask: u32, all: bool, clear: bool) -> Option<u32> {
+        let mut matched = 0;
+        let result = self
+            .flags
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |flags| {
+                matched = flags & mask;
+                let satisfied = if all { matched == mask } else { matched != 0 };
+                if !satisfied {
+                    return None;
+                }
+
+                if clear {
+                    return Some(flags & !matched);
+                } else {
+                    return Some(flags);
+                }
+            });
+
+        if result.is_err() {
+            return None;
+        }
+
+        return Some(matched);
+    }
+}
+
+/// Some flags of an `EventFlags`, for a gate stage to wait on.
+pub struct EventMask {
+    flags: &'static EventFlags,
+    mask: u32,
+    all: bool,
+    clear: bool,
+}
+
+impl Signal for EventMask {
+    fn is_ready(&self) -> bool {
+        return self.flags.try_wait(self.mask, self.all, false).is_some();
+    }
+
+    fn try_acquire(&self) -> bool {
+        return self.flags.try_wait(self.mask, self.all, self.clear).is_some();
+    }
+}
+
+/// A future which waits on an `EventFlags`,
+/// and returns the flags it was waiting for.
+pub struct EventWait<'a> {
+    flags: &'a EventFlags,
+    mask: u32,
+    all: bool,
+    clear: bool,
+}
+
+impl<'a> Future for EventWait<'a> {
+    type Output = u32;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+        match self.flags.try_wait(self.mask, self.all, self.clear) {
+            Some(flags) => {
+                return Poll::Ready(flags);
+            }
+            None => {}
+        }
+
+        self.flags.wakers.register(cx.waker());
+
+        // A flag may have been set while registering
+        return match self.flags.try_wait(self.mask, self.all, self.clear) {
+            Some(flags) => Poll::Ready(flags),
+            None => Poll::Pending,
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::executor::*;
+    use crate::gate::Gate;
+    use std::thread;
+
+    const A: u32 = 1 << 0;
+    const B: u32 = 1 << 1;
+    const C: u32 = 1 << 2;
+
+    #[test]
+    fn test_wait_any_and_all() {
+        let events = EventFlags::new();
+        assert_eq!(events.try_wait_any(A | B, false), None);
+
+        events.set(B | C);
+        assert_eq!(events.try_wait_any(A | B, false), Some(B));
+        assert_eq!(events.try_wait_all(A | B, false), None);
+
+        events.set(A);
+        assert_eq!(events.try_wait_all(A | B, true), Some(A | B));
+
+        // Only the flags which were waited on are cleared
+        assert_eq!(events.get(), C);
+        events.clear(C);
+        assert_eq!(events.get(), 0);
+    }
+
+    #[test]
+    fn test_wait_async() {
+        static EVENTS: EventFlags = EventFlags::new();
+        static mut RESULT: u32 = 0;
+
+        spawn(async {
+            let flags = EVENTS.wait_all(A | C, true).await;
+            unsafe { RESULT = flags };
+        })
+        .unwrap();
+
+        executor_poll();
+        EVENTS.set(A);
+        executor_poll();
+        assert_eq!(unsafe { RESULT }, 0);
+
+        EVENTS.set(C | B);
+        executor_poll();
+        assert_eq!(unsafe { RESULT }, A | C);
+        assert_eq!(EVENTS.get(), B);
+    }
+
+    #[test]
+    fn test_several_waiters() {
+        static EVENTS: EventFlags = EventFlags::new();
+        let on_a = spawn(async {
+            EVENTS.wait_any(A, false).await;
+        })
+        .unwrap();
+        let on_b = spawn(async {
+            EVENTS.wait_any(B, false).await;
+        })
+        .unwrap();
+        executor_poll();
+
+        // The task waiting on A registered first, and still hears about it
+        EVENTS.set(A);
+        executor_poll();
+        assert!(!is_running(on_a));
+        assert!(is_running(on_b));
+
+        EVENTS.set(B);
+        executor_poll();
+        assert!(!is_running(on_b));
+    }
+
+    #[test]
+    fn test_gate_mask() {
+        static EVENTS: EventFlags = EventFlags::new();
+        static EITHER: EventMask = EVENTS.any(A | B, true);
+        static mut RUNS: u32 = 0;
+
+        fn run() {
+            unsafe { RUNS += 1 };
+        }
+
+        let mut gate = Gate::new();
+        gate.when_ready(&EITHER, run).compile();
+        gate.compile();
+        assert_eq!(unsafe { RUNS }, 0);
+
+        EVENTS.set(B);
+        gate.compile();
+        gate.compile();
+        assert_eq!(unsafe { RUNS }, 1);
+        assert_eq!(EVENTS.get(), 0);
+    }
+
+    #[test]
+    fn test_threads() {
+        static EVENTS: EventFlags = EventFlags::new();
+        const COUNT: u32 = 10_000;
+
+        // Each side sets its own flag and waits for the other
+        // to consume it, so every set is seen exactly once.
+        let setter = thread::spawn(|| {
+            for _ in 0..COUNT {
+                EVENTS.set(A);
+                while EVENTS.get() & A != 0 {
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut seen = 0;
+        while seen < COUNT {
+            match EVENTS.try_wait_any(A, true) {
+                Some(flags) => {
+                    assert_eq!(flags, A);
+                    seen += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+
+        setter.join().unwrap();
+        assert_eq!(EVENTS.get(), 0);
+    }
+}
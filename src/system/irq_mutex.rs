@@ -0,0 +1,196 @@
+//! Data which is shared with interrupt handlers.
+//!
+//! A `static mut` can be changed by an interrupt handler right in
+//! the middle of the main loop reading it. `IrqMutex` ties the data
+//! to a `CriticalSection`, so the only way to reach it is with
+//! interrupts held off.
+//!
+//! ```no_run
+//! use teensycore::system::irq_mutex::*;
+//!
+//! static COUNTS: IrqMutex<[u32; 4]> = IrqMutex::new([0; 4]);
+//! static LAST: IrqCell<u32> = IrqCell::new(0);
+//!
+//! fn handle_irq() {
+//!     COUNTS.lock(|counts| counts[0] += 1);
+//!     LAST.set(42);
+//! }
+//!
+//! let total: u32 = COUNTS.lock(|counts| counts.iter().sum());
+//! ```
+use crate::phys::irq::CriticalSection;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A value which can only be reached with interrupts held off.
+pub struct IrqMutex<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+// Interrupts are held off for as long as the data is borrowed,
+// and re-entering the lock is caught, so there is only ever
+// one `&mut T` at a time.
+unsafe impl<T: Send> Sync for IrqMutex<T> {}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        return IrqMutex {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(value),
+        };
+    }
+
+    /// Hold off interrupts and run `func` with the data.
+    ///
+    /// Locking the same mutex again from inside `func` is a
+    /// bug, and raises `PanicType::Deadlock`.
+    pub fn lock<R, F: FnOnce(&mut T) -> R>(&self, func: F) -> R {
+        let _cs = CriticalSection::new();
+        if self.locked.swap(true, Ordering::Acquire) {
+            crate::err(crate::PanicType::Deadlock);
+        }
+
+        let result = func(unsafe { &mut *self.data.get() });
+        self.locked.store(false, Ordering::Release);
+        return result;
+    }
+
+    /// The same as `lock`, but returns None instead of
+    /// panicking if the mutex is already locked.
+    pub fn try_lock<R, F: FnOnce(&mut T) -> R>(&self, func: F) -> Option<R> {
+        let _cs = CriticalSection::new();
+        if self.locked.swap(true, Ordering::Acquire) {
+            return None;
+        }
+
+        let result = func(unsafe { &mut *self.data.get() });
+        self.locked.store(false, Ordering::Release);
+        return Some(result);
+    }
+}
+
+/// A small `Copy` value shared with interrupt handlers.
+/// Values go in and out whole, so no reference ever escapes.
+pub struct IrqCell<T: Copy> {
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for IrqCell<T> {}
+
+impl<T: Copy> IrqCell<T> {
+    pub const fn new(value: T) -> Self {
+        return IrqCell {
+            data: UnsafeCell::new(value),
+        };
+    }
+
+    pub fn get(&self) -> T {
+        let _cs = CriticalSection::new();
+        return unsafe { *self.data.get() };
+    }
+
+    pub fn set(&self, value: T) {
+        let _cs = CriticalSection::new();
+        unsafe { *self.data.get() = value };
+    }
+
+    /// Change the value in place, without an interrupt
+    /// handler sneaking in between the read and the write.
+    /// Returns the new value.
+    pub fn update<F: FnOnce(T) -> T>(&self, func: F) -> T {
+        let _cs = CriticalSection::new();
+        unsafe {
+            let value = func(*self.data.get());
+            *self.data.get() = value;
+            return value;
+        }
+    }
+
+    /// Put a new value in and return the old one.
+    pub fn replace(&self, value: T) -> T {
+        let _cs = CriticalSection::new();
+        unsafe {
+            let previous = *self.data.get();
+            *self.data.get() = value;
+            return previous;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::phys::irq::*;
+
+    #[test]
+    fn test_critical_section_nesting() {
+        assert!(interrupts_enabled());
+        {
+            let _outer = CriticalSection::new();
+            {
+                let _inner = CriticalSection::new();
+                assert!(!interrupts_enabled());
+            }
+            assert!(!interrupts_enabled());
+
+            disable_interrupts();
+            enable_interrupts();
+            assert!(!interrupts_enabled());
+        }
+        assert!(interrupts_enabled());
+    }
+
+    #[test]
+    fn test_critical_section_masking() {
+        assert_eq!(interrupt_mask(), 0);
+        {
+            let _outer = CriticalSection::masking(64);
+            assert_eq!(interrupt_mask(), 64);
+            {
+                // A weaker mask never lowers the current one
+                let _inner = CriticalSection::masking(128);
+                assert_eq!(interrupt_mask(), 64);
+                let _stronger = CriticalSection::masking(16);
+                assert_eq!(interrupt_mask(), 16);
+            }
+            assert_eq!(interrupt_mask(), 64);
+        }
+        assert_eq!(interrupt_mask(), 0);
+    }
+
+    #[test]
+    fn test_irq_mutex() {
+        let mutex = IrqMutex::new(5u32);
+        let doubled = mutex.lock(|value| {
+            assert!(!interrupts_enabled());
+            *value *= 2;
+            return *value;
+        });
+
+        assert_eq!(doubled, 10);
+        assert!(interrupts_enabled());
+
+        // Re-entering the lock is refused
+        let nested = mutex.lock(|_| mutex.try_lock(|value| *value));
+        assert_eq!(nested, None);
+        assert_eq!(mutex.try_lock(|value| *value), Some(10));
+
+        let result = std::panic::catch_unwind(|| {
+            let mutex = IrqMutex::new(0u32);
+            mutex.lock(|_| mutex.lock(|_| {}));
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_irq_cell() {
+        let cell = IrqCell::new(1u32);
+        assert_eq!(cell.get(), 1);
+        cell.set(2);
+        assert_eq!(cell.update(|value| value + 3), 5);
+        assert_eq!(cell.replace(7), 5);
+        assert_eq!(cell.get(), 7);
+        assert!(interrupts_enabled());
+    }
+}
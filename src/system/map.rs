@@ -84,6 +84,26 @@ impl <K : PartialOrd + PartialEq, V> MapNode<K, V> {
         return result;
     }
 
+    /// Visit this node and every node beneath it,
+    /// in order of their keys.
+    pub fn walk<F: FnMut(&K, &V)>(&self, func: &mut F) {
+        match self.left {
+            None => {},
+            Some(node) => {
+                unsafe { node.as_ref().unwrap() }.walk(func);
+            }
+        }
+
+        func(&self.key, &self.item);
+
+        match self.right {
+            None => {},
+            Some(node) => {
+                unsafe { node.as_ref().unwrap() }.walk(func);
+            }
+        }
+    }
+
     pub fn put_left(&mut self, left: Option<*mut MapNode<K, V>>) {
         self.left = left;
     }
@@ -274,6 +294,14 @@ impl <K : PartialOrd + PartialEq, V> BTreeMap<K, V> {
             Some(node) => node.size()
         };
     }
+
+    /// Visit every entry in the map, in order of their keys.
+    pub fn for_each<F: FnMut(&K, &V)>(&self, mut func: F) {
+        match &self.root {
+            None => {},
+            Some(node) => node.walk(&mut func),
+        }
+    }
 }
 
 impl <K : PartialOrd + PartialEq, V> Map<K, V> for BTreeMap<K, V> {
@@ -355,6 +383,24 @@ mod test {
         assert_eq!(map.get(&15), Some(&2));
     }
 
+    #[test]
+    fn test_btree_for_each() {
+        let mut map = BTreeMap::<u8, u8>::new();
+        map.insert(10, 1);
+        map.insert(4, 2);
+        map.insert(17, 3);
+        map.insert(8, 4);
+
+        let mut keys = [0u8; 4];
+        let mut count = 0;
+        map.for_each(|key, _| {
+            keys[count] = *key;
+            count += 1;
+        });
+
+        assert_eq!(keys, [4, 8, 10, 17]);
+    }
+
     #[test]
     fn test_btree_remove() {
         let mut map = BTreeMap::new();
@@ -0,0 +1,242 @@
+//! A counting semaphore for handing work from
+//! interrupt handlers to the rest of the system.
+//!
+//! Every `give` adds one to the count, and every successful
+//! `take` removes one. Nothing ever blocks, so both sides
+//! are safe to use from an interrupt handler.
+//!
+//! ```no_run
+//! use teensycore::executor::*;
+//! use teensycore::gate::*;
+//! use teensycore::prelude::*;
+//! use teensycore::system::semaphore::*;
+//! # static mut GATES: BTreeMap<u32, u32> = BTreeMap { root: None };
+//!
+//! static SAMPLES: Semaphore = Semaphore::new(0, 16);
+//!
+//! fn handle_irq() {
+//!     SAMPLES.give_from_isr();
+//! }
+//!
+//! // A gate stage takes one count every time it runs
+//! gate_open!()
+//!     .when_ready(&SAMPLES, process_sample)
+//!     .compile();
+//!
+//! // ... or a task can wait for one
+//! spawn(async {
+//!     loop {
+//!         SAMPLES.take().await;
+//!     }
+//! });
+//!
+//! fn process_sample() {}
+//! ```
+use crate::executor::IrqWakers;
+use crate::gate::Signal;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll};
+
+pub struct Semaphore {
+    count: AtomicU32,
+    max: u32,
+    overflows: AtomicU32,
+    wakers: IrqWakers,
+}
+
+impl Semaphore {
+    /// Create a semaphore which starts at `initial`
+    /// and never counts higher than `max`.
+    pub const fn new(initial: u32, max: u32) -> Self {
+        return Semaphore {
+            count: AtomicU32::new(initial),
+            max: max,
+            overflows: AtomicU32::new(0),
+            wakers: IrqWakers::new(),
+        };
+    }
+
+    /// Add one to the count. Returns false, and counts an
+    /// overflow, if the semaphore is already at its maximum.
+    pub fn give(&self) -> bool {
+        let result = self
+            .count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                if count < self.max {
+                    return Some(count + 1);
+                } else {
+                    return None;
+                }
+            });
+
+        if result.is_err() {
+            self.overflows.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        self.wakers.wake_all();
+        return true;
+    }
+
+    /// The same as `give`. The name is a reminder
+    /// that this is what interrupt handlers should call.
+    pub fn give_from_isr(&self) -> bool {
+        return self.give();
+    }
+
+    /// Take one from the count, if there is one to take.
+    pub fn try_take(&self) -> bool {
+        return self
+            .count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                if count > 0 {
+                    return Some(count - 1);
+                } else {
+                    return None;
+                }
+            })
+            .is_ok();
+    }
+
+    /// Returns a future which completes once a count has been
+    /// taken. Every waiting task is woken by a `give`, and
+    /// whichever of them misses out waits again.
+    pub fn take(&self) -> SemaphoreTake<'_> {
+        return SemaphoreTake { semaphore: self };
+    }
+
+    pub fn count(&self) -> u32 {
+        return self.count.load(Ordering::Acquire);
+    }
+
+    /// How many times `give` was refused because
+    /// the semaphore was full.
+    pub fn overflows(&self) -> u32 {
+        return self.overflows.load(Ordering::Relaxed);
+    }
+}
+
+impl Signal for Semaphore {
+    fn is_ready(&self) -> bool {
+        return self.count() > 0;
+    }
+
+    fn try_acquire(&self) -> bool {
+        return self.try_take();
+    }
+}
+
+/// A future which waits to take a count from a `Semaphore`.
+pub struct SemaphoreTake<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Future for SemaphoreTake<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.semaphore.try_take() {
+            return Poll::Ready(());
+        }
+
+        self.semaphore.wakers.register(cx.waker());
+
+        // A count may have been given while registering
+        if self.semaphore.try_take() {
+            return Poll::Ready(());
+        }
+
+        return Poll::Pending;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::executor::*;
+    use std::thread;
+
+    #[test]
+    fn test_give_and_take() {
+        let semaphore = Semaphore::new(1, 2);
+        assert_eq!(semaphore.try_take(), true);
+        assert_eq!(semaphore.try_take(), false);
+
+        assert_eq!(semaphore.give_from_isr(), true);
+        assert_eq!(semaphore.give_from_isr(), true);
+        assert_eq!(semaphore.give_from_isr(), false);
+        assert_eq!(semaphore.count(), 2);
+        assert_eq!(semaphore.overflows(), 1);
+
+        assert_eq!(semaphore.try_acquire(), true);
+        assert_eq!(semaphore.count(), 1);
+    }
+
+    #[test]
+    fn test_take_async() {
+        static SEMAPHORE: Semaphore = Semaphore::new(0, 4);
+        static mut TAKEN: u32 = 0;
+
+        spawn(async {
+            loop {
+                SEMAPHORE.take().await;
+                unsafe { TAKEN += 1 };
+            }
+        })
+        .unwrap();
+
+        executor_poll();
+        assert_eq!(unsafe { TAKEN }, 0);
+
+        SEMAPHORE.give_from_isr();
+        SEMAPHORE.give_from_isr();
+        executor_poll();
+        executor_poll();
+        assert_eq!(unsafe { TAKEN }, 2);
+        assert_eq!(SEMAPHORE.count(), 0);
+    }
+
+    #[test]
+    fn test_several_waiters() {
+        static SEMAPHORE: Semaphore = Semaphore::new(0, 4);
+        let first = spawn(async { SEMAPHORE.take().await }).unwrap();
+        let second = spawn(async { SEMAPHORE.take().await }).unwrap();
+        executor_poll();
+
+        // Both were waiting, so both get a count
+        SEMAPHORE.give();
+        SEMAPHORE.give();
+        executor_poll();
+        assert!(!is_running(first));
+        assert!(!is_running(second));
+        assert_eq!(SEMAPHORE.count(), 0);
+    }
+
+    #[test]
+    fn test_threads() {
+        static SEMAPHORE: Semaphore = Semaphore::new(0, 1000);
+        const COUNT: u32 = 10_000;
+
+        let giver = thread::spawn(|| {
+            for _ in 0..COUNT {
+                while !SEMAPHORE.give_from_isr() {
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut taken = 0;
+        while taken < COUNT {
+            if SEMAPHORE.try_take() {
+                taken += 1;
+            } else {
+                thread::yield_now();
+            }
+        }
+
+        giver.join().unwrap();
+        assert_eq!(SEMAPHORE.count(), 0);
+    }
+}
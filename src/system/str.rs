@@ -72,6 +72,7 @@ pub struct Str {
     capacity: Option<usize>,
     index: usize,
     blocks: usize,
+    arena: Option<Arena>,
 }
 
 // This device is only 1 thread so... everyone gets a sync!
@@ -111,6 +112,20 @@ impl Str {
             head: None,
             tail: None,
             index: 0,
+            arena: None,
+        };
+    }
+
+    /// Create a new string whose blocks are all
+    /// allocated from a particular arena.
+    pub const fn with_arena(arena: Arena) -> Self {
+        return Str {
+            blocks: 0,
+            capacity: None,
+            head: None,
+            tail: None,
+            index: 0,
+            arena: Some(arena),
         };
     }
 
@@ -139,6 +154,7 @@ impl Str {
             head: None,
             tail: None,
             index: 0,
+            arena: None,
         };
 
         return result;
@@ -369,7 +385,10 @@ impl Str {
             return;
         }
 
-        let block = alloc();
+        let block = match self.arena {
+            None => alloc(),
+            Some(arena) => arena.alloc(),
+        };
         self.blocks += 1;
 
         unsafe {
@@ -631,6 +650,40 @@ mod test_string_builder {
         assert_eq!(sb.index, 0);
     }
 
+    #[test]
+    fn test_drop_releases_memory() {
+        let mut sb = Str::new();
+        sb.append(b"this is more than a whole page of data, several times over");
+        assert!(crate::mem::ref_count() > 1);
+
+        sb.drop();
+        assert_eq!(crate::mem::ref_count(), 0);
+        assert_eq!(crate::mem::heap_verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_with_arena() {
+        let arena = Arena::new();
+        let mut sb = Str::with_arena(arena);
+        sb.append(b"this is more than a whole page of data, several times over");
+        let plain = str!(b"not in the arena");
+        assert_eq!(arena.ref_count(), sb.blocks);
+
+        arena.release();
+        assert_eq!(crate::mem::ref_count(), plain.blocks);
+    }
+
+    #[test]
+    fn test_using_releases_memory() {
+        crate::using!({
+            let mut sb = str!(b"hello, ");
+            sb.append(b"world");
+            assert!(crate::mem::ref_count() > 0);
+        });
+
+        assert_eq!(crate::mem::ref_count(), 0);
+    }
+
     #[test]
     fn test_iterator() {
         let mut sb = Str::new();
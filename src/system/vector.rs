@@ -7,7 +7,7 @@
 #![allow(dead_code)]
 use crate::{
     math::rand,
-    mem::{alloc, free},
+    mem::{alloc, free, Arena},
 };
 use core::iter::Iterator;
 
@@ -64,6 +64,7 @@ pub struct Node<T: Clone + Copy> {
 pub struct Vector<T: Clone + Copy> {
     pub head: Option<*mut Node<T>>,
     pub size: usize,
+    arena: Option<Arena>,
 }
 
 pub struct NodeIter<T: Clone + Copy> {
@@ -110,6 +111,7 @@ impl<T: Clone + Copy> Clone for Vector<T> {
         }
 
         let mut result = Vector::new();
+        result.arena = self.arena;
         let mut ptr = self.head.unwrap();
 
         loop {
@@ -174,7 +176,10 @@ impl<T: Clone + Copy> Array<T> for Vector<T> {
 impl<T: Clone + Copy> Queue<T> for Vector<T> {
     fn enqueue(&mut self, item: T) {
         // Add it to the end of the stack
-        let ptr = alloc();
+        let ptr = match self.arena {
+            None => alloc(),
+            Some(arena) => arena.alloc(),
+        };
         unsafe {
             (*ptr) = Node {
                 item: item,
@@ -203,8 +208,9 @@ impl<T: Clone + Copy> Queue<T> for Vector<T> {
                 return None;
             }
             Some(node) => {
-                // Copy the reference
-                let node_item = unsafe { node.as_mut().unwrap() };
+                // Copy the node out before it is released, since
+                // freed memory may be reused straight away.
+                let node_item = unsafe { *node };
 
                 // Free the actual node.
                 free(node);
@@ -261,6 +267,17 @@ impl<T: Clone + Copy> Vector<T> {
         return Vector {
             head: None,
             size: 0,
+            arena: None,
+        };
+    }
+
+    /// Create an empty vector whose nodes are all
+    /// allocated from a particular arena.
+    pub const fn with_arena(arena: Arena) -> Self {
+        return Vector {
+            head: None,
+            size: 0,
+            arena: Some(arena),
         };
     }
 
@@ -529,4 +546,19 @@ mod test {
         assert_eq!(next_vec.get(4).unwrap(), 2);
         assert_eq!(next_vec.get(5).unwrap(), 5);
     }
+
+    #[test]
+    fn test_with_arena() {
+        let arena = Arena::new();
+        let mut vec = Vector::with_arena(arena);
+        vec.push(1u32);
+        vec.push(2);
+        vec.push(3);
+        // The copy's nodes come from the arena as well
+        let _copy = vec.clone();
+        assert_eq!(arena.ref_count(), 6);
+
+        arena.release();
+        assert_eq!(crate::mem::ref_count(), 0);
+    }
 }
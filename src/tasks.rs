@@ -0,0 +1,338 @@
+//! Runs a fixed list of `Task`s from the main loop.
+//!
+//! Most firmware ends up as a handful of independent pieces, each
+//! with some setup and some work to do every time around the main
+//! loop. Implementing `Task` for each of them and handing the list
+//! to `main!` saves writing that loop by hand, and keeps track of
+//! how long each task takes.
+//!
+//! ```ignore
+//! use teensycore::*;
+//!
+//! struct Blinker {
+//!     on: bool,
+//! }
+//!
+//! impl Task for Blinker {
+//!     fn new() -> Self {
+//!         return Blinker { on: false };
+//!     }
+//!
+//!     fn init(&mut self) {
+//!         pin_mode(13, Mode::Output);
+//!     }
+//!
+//!     fn system_loop(&mut self) {
+//!         self.on = !self.on;
+//!         pin_out(13, if self.on { Power::High } else { Power::Low });
+//!     }
+//! }
+//!
+//! # struct Sensor;
+//! # impl Task for Sensor {
+//! #     fn new() -> Self { return Sensor; }
+//! #     fn init(&mut self) {}
+//! #     fn system_loop(&mut self) {}
+//! # }
+//! // Blink every 500ms, and read the sensor as often as possible
+//! main!(tasks: [Blinker => 500, Sensor]);
+//! ```
+//!
+//! Tasks are constructed and initialized in the order they are
+//! listed, and then run in that order, over and over. A task with a
+//! rate limit is skipped until its period has passed. If it falls
+//! behind, missed periods are dropped rather than run back to back.
+use crate::clock::*;
+use crate::mem::write_num;
+use crate::Task;
+use crate::MS_TO_NANO;
+
+/// How a single task has been behaving.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TaskStats {
+    /// How many times `system_loop` has run.
+    pub runs: u32,
+    /// The total time spent in `system_loop`.
+    pub total_nanos: uNano,
+    /// The longest `system_loop` has ever taken.
+    pub worst_nanos: uNano,
+    /// For rate limited tasks, the furthest past
+    /// its due time the task has ever run.
+    pub worst_late_nanos: uNano,
+}
+
+impl TaskStats {
+    pub const fn new() -> Self {
+        return TaskStats {
+            runs: 0,
+            total_nanos: 0,
+            worst_nanos: 0,
+            worst_late_nanos: 0,
+        };
+    }
+
+    /// The average time `system_loop` takes to run.
+    pub fn average_nanos(&self) -> uNano {
+        if self.runs == 0 {
+            return 0;
+        }
+
+        return self.total_nanos / self.runs as uNano;
+    }
+}
+
+/// Everything the runner knows about one task.
+#[derive(Copy, Clone)]
+pub struct TaskEntry {
+    pub name: &'static str,
+    pub period: Option<uNano>,
+    pub next_due: uNano,
+    pub stats: TaskStats,
+}
+
+impl TaskEntry {
+    pub const fn new(name: &'static str) -> Self {
+        return TaskEntry {
+            name: name,
+            period: None,
+            next_due: 0,
+            stats: TaskStats::new(),
+        };
+    }
+
+    /// Run the task at most once every `ms` milliseconds.
+    pub const fn every_ms(self, ms: u32) -> Self {
+        return self.every_nano(ms as uNano * MS_TO_NANO);
+    }
+
+    /// Run the task at most once every `ns` nanoseconds.
+    /// A period of zero is treated as one nanosecond.
+    pub const fn every_nano(mut self, ns: uNano) -> Self {
+        self.period = Some(ns);
+        return self;
+    }
+}
+
+pub struct TaskRunner<const N: usize> {
+    entries: [TaskEntry; N],
+}
+
+impl<const N: usize> TaskRunner<N> {
+    pub const fn new(entries: [TaskEntry; N]) -> Self {
+        return TaskRunner { entries: entries };
+    }
+
+    /// Initialize every task, in order. Rate limited
+    /// tasks are first due as soon as this returns.
+    pub fn init(&mut self, tasks: &mut [&mut dyn Task; N]) {
+        for task in tasks.iter_mut() {
+            task.init();
+        }
+
+        let now = nanos();
+        for entry in self.entries.iter_mut() {
+            entry.next_due = now;
+        }
+    }
+
+    /// Go around the list once, running every task which is due.
+    pub fn step(&mut self, tasks: &mut [&mut dyn Task; N]) {
+        for index in 0..N {
+            let entry = &mut self.entries[index];
+            let start = nanos();
+
+            match entry.period {
+                None => {}
+                Some(period) => {
+                    let period = crate::math::max(period, 1);
+                    if start < entry.next_due {
+                        continue;
+                    }
+
+                    let late = start - entry.next_due;
+                    entry.stats.worst_late_nanos =
+                        crate::math::max(entry.stats.worst_late_nanos, late);
+                    entry.next_due += period * (late / period + 1);
+                }
+            }
+
+            tasks[index].system_loop();
+
+            let elapsed = nanos() - start;
+            entry.stats.runs = entry.stats.runs.wrapping_add(1);
+            entry.stats.total_nanos += elapsed;
+            entry.stats.worst_nanos = crate::math::max(entry.stats.worst_nanos, elapsed);
+        }
+    }
+
+    /// When the next rate limited task is due, or None if
+    /// some task runs every time around the loop.
+    pub fn next_due(&self) -> Option<uNano> {
+        let mut result: Option<uNano> = None;
+        for entry in self.entries.iter() {
+            match entry.period {
+                None => {
+                    return None;
+                }
+                Some(_) => {
+                    result = match result {
+                        None => Some(entry.next_due),
+                        Some(due) => Some(crate::math::min(due, entry.next_due)),
+                    };
+                }
+            }
+        }
+
+        return result;
+    }
+
+    pub fn entry(&self, index: usize) -> &TaskEntry {
+        return &self.entries[index];
+    }
+
+    pub fn clear_stats(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.stats = TaskStats::new();
+        }
+    }
+}
+
+/// Print one line for every task.
+pub fn task_report<const N: usize, F: FnMut(&[u8])>(runner: &TaskRunner<N>, mut write: F) {
+    for entry in runner.entries.iter() {
+        let stats = entry.stats;
+        write(b"task ");
+        write(entry.name.as_bytes());
+        write(b" runs=");
+        write_num(&mut write, stats.runs as u64, 10);
+        write(b" avg_ns=");
+        write_num(&mut write, stats.average_nanos() as u64, 10);
+        write(b" worst_ns=");
+        write_num(&mut write, stats.worst_nanos as u64, 10);
+        write(b" late_ns=");
+        write_num(&mut write, stats.worst_late_nanos as u64, 10);
+        write(b"\n");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::system::map::BTreeMap;
+
+    struct Counter {
+        inits: u32,
+        runs: u32,
+        cost: uNano,
+    }
+
+    impl Task for Counter {
+        fn new() -> Self {
+            return Counter {
+                inits: 0,
+                runs: 0,
+                cost: 0,
+            };
+        }
+
+        fn init(&mut self) {
+            self.inits += 1;
+        }
+
+        fn system_loop(&mut self) {
+            self.runs += 1;
+            clock_advance(self.cost);
+        }
+    }
+
+    #[test]
+    fn test_runner() {
+        clock_set(0);
+        let mut fast = Counter::new();
+        let mut slow = Counter::new();
+        fast.cost = 1000;
+
+        let mut runner =
+            TaskRunner::new([TaskEntry::new("fast"), TaskEntry::new("slow").every_ms(10)]);
+        {
+            let mut tasks: [&mut dyn Task; 2] = [&mut fast, &mut slow];
+            runner.init(&mut tasks);
+            for _ in 0..5 {
+                runner.step(&mut tasks);
+            }
+        }
+
+        assert_eq!(fast.inits, 1);
+        assert_eq!(slow.inits, 1);
+        assert_eq!(fast.runs, 5);
+        assert_eq!(slow.runs, 1);
+        assert_eq!(runner.entry(0).stats.runs, 5);
+        assert_eq!(runner.entry(0).stats.average_nanos(), 1000);
+        assert_eq!(runner.next_due(), None);
+
+        // Falling far behind only runs the slow task once
+        clock_advance(35 * MS_TO_NANO);
+        {
+            let mut tasks: [&mut dyn Task; 2] = [&mut fast, &mut slow];
+            runner.step(&mut tasks);
+            runner.step(&mut tasks);
+        }
+
+        assert_eq!(slow.runs, 2);
+        assert_eq!(runner.entry(1).next_due, 40 * MS_TO_NANO);
+        assert_eq!(
+            runner.entry(1).stats.worst_late_nanos,
+            25 * MS_TO_NANO + 6000
+        );
+    }
+
+    #[test]
+    fn test_runner_zero_period() {
+        clock_set(0);
+        let mut task = Counter::new();
+        task.cost = 1000;
+
+        let mut runner = TaskRunner::new([TaskEntry::new("zero").every_ms(0)]);
+        {
+            let mut tasks: [&mut dyn Task; 1] = [&mut task];
+            runner.init(&mut tasks);
+            for _ in 0..3 {
+                runner.step(&mut tasks);
+            }
+        }
+
+        assert_eq!(task.runs, 3);
+        assert_eq!(runner.entry(0).next_due, 2001);
+    }
+
+    #[test]
+    fn test_task_list() {
+        // The same expansion `main!(tasks: [...])` uses
+        clock_set(0);
+        let (mut tasks, mut runner) = crate::task_list!(Counter => 5, Counter);
+        runner.init(&mut tasks);
+        runner.step(&mut tasks);
+        runner.step(&mut tasks);
+
+        assert_eq!(runner.entry(0).name, "Counter");
+        assert_eq!(runner.entry(0).stats.runs, 1);
+        assert_eq!(runner.entry(1).stats.runs, 2);
+        assert_eq!(runner.next_due(), None);
+
+        let gates: BTreeMap<u32, u32> = BTreeMap { root: None };
+        assert!(!crate::idle::idle_tasks(&runner, &gates));
+    }
+
+    #[test]
+    fn test_report() {
+        let runner = TaskRunner::new([TaskEntry::new("blinker").every_ms(500)]);
+        assert_eq!(runner.next_due(), Some(0));
+
+        let mut output = std::vec::Vec::new();
+        task_report(&runner, |bytes| output.extend_from_slice(bytes));
+        assert_eq!(
+            output.as_slice(),
+            b"task blinker runs=0 avg_ns=0 worst_ns=0 late_ns=0\n".as_slice()
+        );
+    }
+}
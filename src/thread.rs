@@ -0,0 +1,676 @@
+//! Preemptive threads.
+//!
+//! Gates and the executor are cooperative, so one slow stage can
+//! hold up everything else. Threads are not. Every thread has its
+//! own stack allocated from `mem`, and a priority. The SysTick
+//! exception fires every millisecond, and if a more important
+//! thread is ready (or the current one has used up its time
+//! slice) the PendSV exception switches to it.
+//!
+//! ```no_run
+//! use teensycore::thread::*;
+//! use teensycore::*;
+//!
+//! thread_init();
+//!
+//! let motor = thread_spawn(5, DEFAULT_STACK_BYTES, || loop {
+//!     // Hard real-time work
+//!     sleep(MS_TO_NANO);
+//! })
+//! .unwrap();
+//!
+//! loop {
+//!     // The main thread keeps running at DEFAULT_PRIORITY
+//!     yield_now();
+//! }
+//! ```
+//!
+//! The higher the priority, the more important the thread.
+//! Threads which share a priority take turns every
+//! `TIME_SLICE_TICKS` milliseconds. A thread with nothing to
+//! do should `sleep()` so less important threads get to run.
+//!
+//! The floating point registers are saved along with everything
+//! else, but only for threads which have actually used them.
+//!
+//! The scheduler takes over SysTick, and checks the main stack's
+//! guard words on every tick in place of `mem::stack_watch()`.
+use crate::clock::*;
+use crate::mem::{Mempage, ScopeUnit, DEFAULT_SCOPE, GLOBAL_ALLOC_SCOPE, MEMORY_SCOPE};
+use crate::phys::irq::{disable_interrupts, enable_interrupts};
+use core::mem::{align_of, size_of};
+
+#[cfg(not(feature = "testing"))]
+use core::arch::{asm, global_asm};
+
+/// The most threads which can exist at once, including
+/// the main thread and the idle thread.
+pub const MAX_THREADS: usize = 8;
+
+/// The priority of the main thread.
+pub const DEFAULT_PRIORITY: u8 = 1;
+
+/// The idle thread only runs when nothing else can.
+pub const IDLE_PRIORITY: u8 = 0;
+
+/// A reasonable amount of stack for a thread which
+/// doesn't do anything unusual.
+pub const DEFAULT_STACK_BYTES: usize = 4096;
+
+/// How many SysTick milliseconds a thread gets before another
+/// thread with the same priority gets a turn.
+pub const TIME_SLICE_TICKS: u32 = 10;
+
+const MAIN_THREAD: usize = 0;
+const IDLE_THREAD: usize = 1;
+const IDLE_STACK_BYTES: usize = 512;
+
+// Written to the bottom word of every thread stack
+const STACK_CANARY: u32 = 0x7EAD_5AFE;
+
+// Hardware pushes r0-r3, r12, lr, pc and xPSR. The PendSV
+// handler pushes r4-r11, the EXC_RETURN value and a word
+// of padding to keep the stack 8 byte aligned.
+const HARDWARE_FRAME_WORDS: usize = 8;
+const SOFTWARE_FRAME_WORDS: usize = 10;
+
+// Return to thread mode on the process stack, without FPU state
+const EXC_RETURN_THREAD_PSP: u32 = 0xFFFF_FFFD;
+
+/// Identifies a thread.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ThreadId(usize);
+
+/// What a thread is doing.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ThreadState {
+    /// The slot is not in use.
+    Free,
+    /// Running, or ready to run.
+    Ready,
+    /// Waiting until `clock::nanos()` reaches some uptime.
+    Sleeping(uNano),
+    /// Waiting for another thread to finish.
+    Joining(usize),
+    /// Finished, but not yet joined.
+    Finished,
+}
+
+#[derive(Copy, Clone)]
+struct Thread {
+    state: ThreadState,
+    priority: u8,
+    sp: usize,
+    stack: usize,
+    scope: ScopeUnit, // MEMORY_SCOPE while the thread isn't running
+}
+
+impl Thread {
+    const fn new() -> Self {
+        return Thread {
+            state: ThreadState::Free,
+            priority: 0,
+            sp: 0,
+            stack: 0,
+            scope: DEFAULT_SCOPE,
+        };
+    }
+}
+
+/// Decides which thread runs next. This holds no hardware
+/// state at all, so it can be exercised on the host.
+pub struct Scheduler {
+    threads: [Thread; MAX_THREADS],
+    current: usize,
+    slice: u32,
+}
+
+impl Scheduler {
+    pub const fn new() -> Self {
+        return Scheduler {
+            threads: [Thread::new(); MAX_THREADS],
+            current: MAIN_THREAD,
+            slice: 0,
+        };
+    }
+
+    /// Claim a free slot for a thread which is ready to run.
+    fn add(&mut self, priority: u8, sp: usize, stack: usize) -> Option<usize> {
+        for index in 0..MAX_THREADS {
+            if self.threads[index].state == ThreadState::Free {
+                self.threads[index] = Thread {
+                    state: ThreadState::Ready,
+                    priority: priority,
+                    sp: sp,
+                    stack: stack,
+                    scope: DEFAULT_SCOPE,
+                };
+                return Some(index);
+            }
+        }
+
+        return None;
+    }
+
+    /// Returns the state of a thread.
+    pub fn state(&self, index: usize) -> ThreadState {
+        return self.threads[index].state;
+    }
+
+    /// Returns true if the current thread can wait for a thread
+    /// to finish. The main and idle threads never finish, a thread
+    /// can't wait for itself, and only one thread can wait for
+    /// another, since joining releases it.
+    fn joinable(&self, index: usize) -> bool {
+        if index == self.current
+            || index == MAIN_THREAD
+            || index == IDLE_THREAD
+            || self.threads[index].state == ThreadState::Free
+        {
+            return false;
+        }
+
+        for thread in self.threads.iter() {
+            if thread.state == ThreadState::Joining(index) {
+                return false;
+            }
+        }
+
+        return true;
+    }
+
+    /// Wake every thread whose sleep has elapsed, or
+    /// whose join target has finished.
+    fn wake(&mut self, now: uNano) {
+        for index in 0..MAX_THREADS {
+            match self.threads[index].state {
+                ThreadState::Sleeping(until) if until <= now => {
+                    self.threads[index].state = ThreadState::Ready;
+                }
+                ThreadState::Joining(target)
+                    if self.threads[target].state == ThreadState::Finished =>
+                {
+                    self.threads[index].state = ThreadState::Ready;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the most important priority of any ready thread.
+    fn best_priority(&self) -> u8 {
+        let mut best = 0;
+        for thread in self.threads.iter() {
+            if thread.state == ThreadState::Ready && thread.priority > best {
+                best = thread.priority;
+            }
+        }
+        return best;
+    }
+
+    /// Pick the next thread to run. Of the most important ready
+    /// threads, the first one after the current thread wins, so
+    /// threads of equal priority take turns.
+    pub fn next(&mut self, now: uNano) -> usize {
+        self.wake(now);
+        let best = self.best_priority();
+
+        for offset in 1..=MAX_THREADS {
+            let index = (self.current + offset) % MAX_THREADS;
+            let thread = &self.threads[index];
+            if thread.state == ThreadState::Ready && thread.priority == best {
+                return index;
+            }
+        }
+
+        // The idle thread is always ready, so this is unreachable
+        return self.current;
+    }
+
+    /// Called every millisecond. Returns true if another
+    /// thread should be switched to.
+    pub fn tick(&mut self, now: uNano) -> bool {
+        self.slice += 1;
+        self.wake(now);
+
+        let current = &self.threads[self.current];
+        if current.state != ThreadState::Ready {
+            return true;
+        }
+
+        let best = self.best_priority();
+        if best > current.priority {
+            return true;
+        } else if self.slice < TIME_SLICE_TICKS {
+            return false;
+        }
+
+        // Only switch if someone else is waiting for a turn
+        for index in 0..MAX_THREADS {
+            let thread = &self.threads[index];
+            if index != self.current
+                && thread.state == ThreadState::Ready
+                && thread.priority == current.priority
+            {
+                return true;
+            }
+        }
+
+        return false;
+    }
+
+    /// Record where the current thread left off and return the
+    /// stack pointer of the thread which should run next. Each
+    /// thread allocates against its own `MEMORY_SCOPE`, so that
+    /// is swapped over as well.
+    pub fn switch(&mut self, sp: usize, now: uNano) -> usize {
+        self.threads[self.current].sp = sp;
+        let next = self.next(now);
+        if next != self.current {
+            unsafe {
+                self.threads[self.current].scope = MEMORY_SCOPE;
+                MEMORY_SCOPE = self.threads[next].scope;
+            }
+            self.slice = 0;
+            self.current = next;
+        }
+        return self.threads[next].sp;
+    }
+}
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut SCHEDULER: Scheduler = Scheduler::new();
+#[cfg_attr(feature = "testing", thread_local)]
+static mut STARTED: bool = false;
+
+fn scheduler() -> &'static mut Scheduler {
+    return unsafe { &mut *core::ptr::addr_of_mut!(SCHEDULER) };
+}
+
+/// Returns true once `thread_init()` has been called,
+/// and the scheduler owns the SysTick exception.
+pub fn thread_running() -> bool {
+    return unsafe { STARTED };
+}
+
+/// Turn the code which is running right now into the main
+/// thread, and start switching between threads.
+pub fn thread_init() {
+    unsafe {
+        if STARTED {
+            return;
+        }
+
+        disable_interrupts();
+        *scheduler() = Scheduler::new();
+
+        // The main thread keeps the stack it already has. It
+        // gets a stack pointer the first time it is switched away from.
+        scheduler().add(DEFAULT_PRIORITY, 0, 0);
+        let idle = new_stack(IDLE_STACK_BYTES, idle_entry as *const () as usize, 0);
+        scheduler().add(IDLE_PRIORITY, idle.0, idle.1);
+        STARTED = true;
+        start_switching();
+        enable_interrupts();
+    }
+}
+
+/// Start a new thread running `func`. Returns None if there
+/// are already `MAX_THREADS` threads.
+///
+/// The thread's resources are released once it has finished
+/// and another thread has called `join()` on it.
+pub fn thread_spawn<F: FnOnce() + 'static>(
+    priority: u8,
+    stack_bytes: usize,
+    func: F,
+) -> Option<ThreadId> {
+    let closure = Mempage::add_page_in::<F>(size_of::<F>(), align_of::<F>(), GLOBAL_ALLOC_SCOPE);
+    unsafe { closure.write(func) };
+
+    let (sp, stack) = new_stack(
+        stack_bytes,
+        thread_entry::<F> as *const () as usize,
+        closure as usize,
+    );
+
+    disable_interrupts();
+    let index = scheduler().add(priority, sp, stack);
+    enable_interrupts();
+
+    return match index {
+        Some(index) => {
+            // A more important thread should start straight away
+            crate::pendsv();
+            Some(ThreadId(index))
+        }
+        None => {
+            Mempage::free(stack);
+            Mempage::free(closure as usize);
+            None
+        }
+    };
+}
+
+/// Returns the thread which is running right now.
+pub fn thread_current() -> ThreadId {
+    return ThreadId(scheduler().current);
+}
+
+/// Stop running the current thread for some amount of
+/// nanoseconds, letting less important threads run.
+pub fn sleep(duration: uNano) {
+    disable_interrupts();
+    let scheduler = scheduler();
+    scheduler.threads[scheduler.current].state = ThreadState::Sleeping(nanos() + duration);
+    crate::pendsv();
+    enable_interrupts();
+}
+
+/// Let any other ready thread with the same priority run.
+pub fn yield_now() {
+    crate::pendsv();
+}
+
+/// Wait for a thread to finish, then release its stack. Returns
+/// false straight away if the thread could never be joined: the
+/// current thread, the main or idle thread, one which has already been
+/// joined, or one which another thread is already waiting for.
+pub fn join(thread: ThreadId) -> bool {
+    let scheduler = scheduler();
+    disable_interrupts();
+    if !scheduler.joinable(thread.0) {
+        enable_interrupts();
+        return false;
+    }
+
+    if scheduler.threads[thread.0].state != ThreadState::Finished {
+        scheduler.threads[scheduler.current].state = ThreadState::Joining(thread.0);
+        crate::pendsv();
+    }
+    enable_interrupts();
+
+    // By the time we are running again, it has finished
+    disable_interrupts();
+    let stack = scheduler.threads[thread.0].stack;
+    scheduler.threads[thread.0] = Thread::new();
+    enable_interrupts();
+
+    if stack != 0 {
+        Mempage::free(stack);
+    }
+
+    return true;
+}
+
+/// Allocate a stack and lay out a frame on it which the PendSV
+/// handler will "return" into. Returns the initial stack pointer
+/// and the allocation.
+fn new_stack(bytes: usize, entry: usize, arg: usize) -> (usize, usize) {
+    let bytes = crate::math::max(bytes, (HARDWARE_FRAME_WORDS + SOFTWARE_FRAME_WORDS + 2) * 4);
+    let stack = Mempage::add_page_in::<u32>(bytes, 8, GLOBAL_ALLOC_SCOPE) as usize;
+    unsafe { *(stack as *mut u32) = STACK_CANARY };
+    return (prepare_stack(stack + bytes, entry, arg), stack);
+}
+
+/// Lay out an exception frame at the top of a stack, so that
+/// switching to it starts running `entry(arg)`.
+fn prepare_stack(top: usize, entry: usize, arg: usize) -> usize {
+    let top = top & !0x7;
+    let hardware = top - HARDWARE_FRAME_WORDS * 4;
+    let software = hardware - SOFTWARE_FRAME_WORDS * 4;
+
+    unsafe {
+        let frame = hardware as *mut u32;
+        *frame.add(0) = arg as u32; // r0
+        *frame.add(1) = 0; // r1
+        *frame.add(2) = 0; // r2
+        *frame.add(3) = 0; // r3
+        *frame.add(4) = 0; // r12
+        *frame.add(5) = 0xFFFF_FFFF; // lr, entry never returns
+        *frame.add(6) = (entry as u32) & !0x1; // pc
+        *frame.add(7) = 0x0100_0000; // xPSR, thumb state
+
+        let context = software as *mut u32;
+        for word in 0..(SOFTWARE_FRAME_WORDS - 1) {
+            *context.add(word) = 0; // padding, r4-r11
+        }
+        *context.add(SOFTWARE_FRAME_WORDS - 1) = EXC_RETURN_THREAD_PSP;
+    }
+
+    return software;
+}
+
+/// The first code every spawned thread runs.
+extern "C" fn thread_entry<F: FnOnce()>(closure: *mut F) -> ! {
+    let func = unsafe { closure.read() };
+    Mempage::free(closure as usize);
+    func();
+    thread_exit();
+}
+
+fn thread_exit() -> ! {
+    disable_interrupts();
+    let scheduler = scheduler();
+    scheduler.threads[scheduler.current].state = ThreadState::Finished;
+    crate::pendsv();
+    enable_interrupts();
+
+    // Never scheduled again
+    loop {}
+}
+
+extern "C" fn idle_entry(_arg: usize) -> ! {
+    loop {
+        crate::assembly!("wfi");
+    }
+}
+
+/// Called from the PendSV handler with the stack pointer of
+/// the thread being switched away from.
+#[no_mangle]
+extern "C" fn thread_switch(sp: usize) -> usize {
+    let scheduler = scheduler();
+    let stack = scheduler.threads[scheduler.current].stack;
+    if stack != 0 && unsafe { *(stack as *const u32) } != STACK_CANARY {
+        crate::err(crate::PanicType::StackOverflow);
+    }
+
+    return scheduler.switch(sp, nanos());
+}
+
+/// The scheduler owns SysTick, so it checks the main
+/// stack's guard words on `mem::stack_watch()`'s behalf.
+#[cfg(not(feature = "testing"))]
+fn thread_tick() {
+    crate::mem::stack_check();
+    if scheduler().tick(nanos()) {
+        crate::pendsv();
+    }
+}
+
+#[cfg(not(feature = "testing"))]
+fn start_switching() {
+    use crate::phys::irq::*;
+
+    extern "C" {
+        fn thread_pendsv_handler();
+    }
+
+    // Context switches must never interrupt another handler
+    system_priority(0xFF, 0xF0);
+    pendsv_attach(unsafe {
+        core::mem::transmute::<unsafe extern "C" fn(), fn()>(thread_pendsv_handler)
+    });
+    systick_attach(thread_tick);
+    systick_start(1);
+}
+
+#[cfg(feature = "testing")]
+fn start_switching() {}
+
+// Save the outgoing thread onto whichever stack it was using,
+// swap stacks, and restore the incoming thread. The main thread
+// runs on the main stack, every other thread on the process stack,
+// and bit 2 of EXC_RETURN says which one a thread was using.
+// Bit 4 is clear when the thread has floating point state.
+#[cfg(not(feature = "testing"))]
+global_asm!(
+    "
+    .thumb_func
+    .global thread_pendsv_handler
+    thread_pendsv_handler:
+        tst lr, #4
+        ite eq
+        mrseq r0, msp
+        mrsne r0, psp
+
+        tst lr, #0x10
+        it eq
+        vstmdbeq r0!, {{s16-s31}}
+        stmdb r0!, {{r2, r4-r11, lr}}
+
+        tst lr, #4
+        it eq
+        moveq sp, r0
+
+        bl thread_switch
+
+        ldmia r0!, {{r2, r4-r11, lr}}
+        tst lr, #0x10
+        it eq
+        vldmiaeq r0!, {{s16-s31}}
+
+        tst lr, #4
+        ite eq
+        moveq sp, r0
+        msrne psp, r0
+        bx lr
+"
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_scheduler() -> Scheduler {
+        let mut scheduler = Scheduler::new();
+        scheduler.add(DEFAULT_PRIORITY, 0, 0);
+        scheduler.add(IDLE_PRIORITY, 0x100, 0);
+        return scheduler;
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut scheduler = new_scheduler();
+        let other = scheduler.add(DEFAULT_PRIORITY, 0x200, 0).unwrap();
+
+        // Nothing happens until the slice runs out
+        for _ in 0..(TIME_SLICE_TICKS - 1) {
+            assert_eq!(scheduler.tick(0), false);
+        }
+        assert_eq!(scheduler.tick(0), true);
+
+        assert_eq!(scheduler.switch(0x1000, 0), 0x200);
+        assert_eq!(scheduler.current, other);
+        assert_eq!(scheduler.switch(0x2000, 0), 0x1000);
+        assert_eq!(scheduler.current, MAIN_THREAD);
+    }
+
+    #[test]
+    fn test_priority_preempts() {
+        let mut scheduler = new_scheduler();
+        assert_eq!(scheduler.tick(0), false);
+
+        let urgent = scheduler.add(5, 0x300, 0).unwrap();
+        assert_eq!(scheduler.tick(0), true);
+        assert_eq!(scheduler.switch(0x1000, 0), 0x300);
+
+        // A less important thread never takes a turn
+        for _ in 0..(TIME_SLICE_TICKS * 2) {
+            assert_eq!(scheduler.tick(0), false);
+        }
+        assert_eq!(scheduler.next(0), urgent);
+    }
+
+    #[test]
+    fn test_sleep_falls_back_to_idle() {
+        let mut scheduler = new_scheduler();
+        scheduler.threads[MAIN_THREAD].state = ThreadState::Sleeping(500);
+        assert_eq!(scheduler.tick(100), true);
+        assert_eq!(scheduler.switch(0x1000, 100), 0x100);
+        assert_eq!(scheduler.current, IDLE_THREAD);
+
+        assert_eq!(scheduler.tick(499), false);
+        assert_eq!(scheduler.tick(500), true);
+        assert_eq!(scheduler.switch(0x100, 500), 0x1000);
+        assert_eq!(scheduler.state(MAIN_THREAD), ThreadState::Ready);
+    }
+
+    #[test]
+    fn test_join_waits_for_finish() {
+        let mut scheduler = new_scheduler();
+        let worker = scheduler.add(DEFAULT_PRIORITY, 0x200, 0).unwrap();
+        scheduler.threads[MAIN_THREAD].state = ThreadState::Joining(worker);
+
+        assert_eq!(scheduler.switch(0x1000, 0), 0x200);
+        assert_eq!(scheduler.tick(0), false);
+
+        scheduler.threads[worker].state = ThreadState::Finished;
+        assert_eq!(scheduler.switch(0x2000, 0), 0x1000);
+        assert_eq!(scheduler.state(MAIN_THREAD), ThreadState::Ready);
+    }
+
+    #[test]
+    fn test_joinable() {
+        let mut scheduler = new_scheduler();
+        let worker = scheduler.add(DEFAULT_PRIORITY, 0x200, 0).unwrap();
+        let other = scheduler.add(DEFAULT_PRIORITY, 0x300, 0).unwrap();
+
+        assert!(!scheduler.joinable(MAIN_THREAD));
+        assert!(!scheduler.joinable(IDLE_THREAD));
+        assert!(!scheduler.joinable(MAX_THREADS - 1));
+        assert!(scheduler.joinable(worker));
+
+        // A thread can't wait for itself
+        scheduler.current = worker;
+        assert!(!scheduler.joinable(worker));
+
+        // Only one thread gets to wait for another
+        scheduler.threads[other].state = ThreadState::Joining(worker);
+        scheduler.current = MAIN_THREAD;
+        assert!(!scheduler.joinable(worker));
+    }
+
+    #[test]
+    fn test_memory_scope_per_thread() {
+        let mut scheduler = new_scheduler();
+        scheduler.add(DEFAULT_PRIORITY, 0x200, 0).unwrap();
+
+        // The main thread is partway through a using! block
+        unsafe { MEMORY_SCOPE = 0xBEEF };
+        scheduler.switch(0x1000, 0);
+        assert_eq!(unsafe { MEMORY_SCOPE }, DEFAULT_SCOPE);
+
+        unsafe { MEMORY_SCOPE = 0xCAFE };
+        scheduler.switch(0x2000, 0);
+        assert_eq!(unsafe { MEMORY_SCOPE }, 0xBEEF);
+        assert_eq!(scheduler.threads[2].scope, 0xCAFE);
+        unsafe { MEMORY_SCOPE = DEFAULT_SCOPE };
+    }
+
+    #[test]
+    fn test_prepare_stack() {
+        let mut stack = [0u32; 64];
+        let top = stack.as_mut_ptr() as usize + 64 * 4;
+        let sp = prepare_stack(top, 0x6000_1235, 0xABCD);
+
+        let words = (top - sp) / 4;
+        assert_eq!(words, HARDWARE_FRAME_WORDS + SOFTWARE_FRAME_WORDS);
+        assert_eq!(sp % 8, 0);
+        assert_eq!(stack[64 - 8], 0xABCD);
+        assert_eq!(stack[64 - 2], 0x6000_1234);
+        assert_eq!(stack[64 - 1], 0x0100_0000);
+        assert_eq!(stack[64 - 9], EXC_RETURN_THREAD_PSP);
+    }
+}
@@ -0,0 +1,452 @@
+//! Software timers.
+//!
+//! The kernel keeps periodic timers 0 and 1 chained together for
+//! `clock::nanos()`. This module uses periodic timer 2 to run
+//! callbacks at some point in the future, either once or over
+//! and over again.
+//!
+//! ```no_run
+//! use teensycore::timers::*;
+//!
+//! timers_init();
+//!
+//! let heartbeat = every(500, toggle_led);
+//! after(10_000, stop_heartbeat);
+//!
+//! fn toggle_led() {
+//!     // ...
+//! }
+//!
+//! fn stop_heartbeat() {
+//!     // ...
+//! }
+//! ```
+//!
+//! Every pending timer lives in a list sorted by when it is due.
+//! Periodic timer 2 is only ever loaded with the time until the
+//! first entry, so nothing runs unless a callback is due.
+//!
+//! Callbacks run inside the periodic timer interrupt. Keep them
+//! short, and hand anything slow to a gate, a task or a thread.
+//!
+//! Timers 2 and 3 share one interrupt line with the uptime
+//! clock, so once `timers_init()` has been called this module
+//! owns `Irq::PeriodicTimer`.
+use crate::clock::*;
+use crate::phys::irq::{disable_interrupts, enable_interrupts};
+use crate::MS_TO_NANO;
+
+/// The most timers which can be pending at once.
+pub const MAX_TIMERS: usize = 16;
+
+/// The function a timer calls when it is due.
+pub type TimerFn = fn();
+
+/// Returned by `after` and `every`, and used to cancel the timer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimerHandle(u32);
+
+#[derive(Copy, Clone)]
+struct Timer {
+    id: u32,
+    due: uNano,
+    period: Option<uNano>,
+    func: TimerFn,
+}
+
+fn timer_noop() {}
+
+impl Timer {
+    const fn new() -> Self {
+        return Timer {
+            id: 0,
+            due: 0,
+            period: None,
+            func: timer_noop,
+        };
+    }
+}
+
+/// Pending timers, kept sorted by when they are due. This holds
+/// no hardware state at all, so it can be driven on the host.
+pub struct TimerQueue {
+    timers: [Timer; MAX_TIMERS],
+    len: usize,
+    next_id: u32,
+}
+
+impl TimerQueue {
+    pub const fn new() -> Self {
+        return TimerQueue {
+            timers: [Timer::new(); MAX_TIMERS],
+            len: 0,
+            next_id: 1,
+        };
+    }
+
+    /// The number of pending timers.
+    pub fn len(&self) -> usize {
+        return self.len;
+    }
+
+    /// Add a timer which is due `delay` nanoseconds after `now`.
+    /// Periodic timers are then due every `period` nanoseconds.
+    /// Returns None if there are already `MAX_TIMERS` timers.
+    pub fn schedule(
+        &mut self,
+        now: uNano,
+        delay: uNano,
+        period: Option<uNano>,
+        func: TimerFn,
+    ) -> Option<TimerHandle> {
+        if self.len == MAX_TIMERS {
+            return None;
+        }
+
+        // Handles are never reused, so a stale one can't
+        // cancel somebody else's timer.
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+
+        self.insert(Timer {
+            id: id,
+            due: now + delay,
+            period: period,
+            func: func,
+        });
+
+        return Some(TimerHandle(id));
+    }
+
+    /// Remove a pending timer. Returns false if it had already
+    /// fired (for a one-shot timer) or been cancelled.
+    pub fn cancel(&mut self, handle: TimerHandle) -> bool {
+        for index in 0..self.len {
+            if self.timers[index].id == handle.0 {
+                self.remove(index);
+                return true;
+            }
+        }
+
+        return false;
+    }
+
+    /// Returns true if the timer is still pending.
+    pub fn is_pending(&self, handle: TimerHandle) -> bool {
+        return self.timers[0..self.len]
+            .iter()
+            .any(|timer| timer.id == handle.0);
+    }
+
+    /// When the next timer is due, if there is one.
+    pub fn next_due(&self) -> Option<uNano> {
+        if self.len == 0 {
+            return None;
+        }
+
+        return Some(self.timers[0].due);
+    }
+
+    /// Take the first timer if it is due. Periodic timers are put
+    /// back into the list for their next period. A periodic timer
+    /// which has fallen behind skips the periods it missed rather
+    /// than firing several times in a row.
+    pub fn pop_due(&mut self, now: uNano) -> Option<TimerFn> {
+        if self.len == 0 || self.timers[0].due > now {
+            return None;
+        }
+
+        let mut timer = self.timers[0];
+        self.remove(0);
+
+        match timer.period {
+            None => {}
+            Some(period) => {
+                let period = crate::math::max(period, 1);
+                let missed = (now - timer.due) / period;
+                timer.due += (missed + 1) * period;
+                self.insert(timer);
+            }
+        }
+
+        return Some(timer.func);
+    }
+
+    fn insert(&mut self, timer: Timer) {
+        // Timers due at the same time fire in the order
+        // they were scheduled.
+        let mut index = self.len;
+        while index > 0 && self.timers[index - 1].due > timer.due {
+            self.timers[index] = self.timers[index - 1];
+            index -= 1;
+        }
+
+        self.timers[index] = timer;
+        self.len += 1;
+    }
+
+    fn remove(&mut self, index: usize) {
+        for next in index..(self.len - 1) {
+            self.timers[next] = self.timers[next + 1];
+        }
+        self.len -= 1;
+    }
+}
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut TIMERS: TimerQueue = TimerQueue::new();
+
+fn queue() -> &'static mut TimerQueue {
+    return unsafe { &mut *core::ptr::addr_of_mut!(TIMERS) };
+}
+
+#[cfg_attr(feature = "testing", thread_local)]
+static mut STARTED: bool = false;
+
+/// Returns true once `timers_init()` has been called, and
+/// timers will fire without anything having to poll them.
+pub fn timers_ready() -> bool {
+    return unsafe { STARTED };
+}
+
+/// Run `func` once, `ms` milliseconds from now.
+pub fn after(ms: u32, func: TimerFn) -> Option<TimerHandle> {
+    return schedule(ms as uNano * MS_TO_NANO, None, func);
+}
+
+/// Run `func` every `ms` milliseconds, starting
+/// `ms` milliseconds from now.
+pub fn every(ms: u32, func: TimerFn) -> Option<TimerHandle> {
+    let period = ms as uNano * MS_TO_NANO;
+    return schedule(period, Some(period), func);
+}
+
+/// Run `func` once, `ns` nanoseconds from now.
+pub fn after_nano(ns: uNano, func: TimerFn) -> Option<TimerHandle> {
+    return schedule(ns, None, func);
+}
+
+/// Stop a timer from firing. Returns false if it
+/// was not pending.
+pub fn cancel(handle: TimerHandle) -> bool {
+    disable_interrupts();
+    let result = queue().cancel(handle);
+    enable_interrupts();
+    return result;
+}
+
+/// Returns true if the timer has yet to fire. Periodic
+/// timers are pending until they are cancelled.
+pub fn is_pending(handle: TimerHandle) -> bool {
+    return queue().is_pending(handle);
+}
+
+/// When the next timer is due, as a `clock::nanos()` uptime.
+pub fn timers_next_due() -> Option<uNano> {
+    return queue().next_due();
+}
+
+fn schedule(delay: uNano, period: Option<uNano>, func: TimerFn) -> Option<TimerHandle> {
+    disable_interrupts();
+    let handle = queue().schedule(nanos(), delay, period, func);
+    timers_arm();
+    enable_interrupts();
+    return handle;
+}
+
+/// Run every timer which is due right now. This is what the
+/// periodic timer interrupt does, and is public so that
+/// tests and polling loops can drive timers without it.
+pub fn timers_service() {
+    loop {
+        match queue().pop_due(nanos()) {
+            None => {
+                break;
+            }
+            Some(func) => {
+                func();
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "testing"))]
+mod hardware {
+    use super::*;
+    use crate::phys::irq::*;
+    use crate::phys::periodic_timers::*;
+    use crate::S_TO_NANO;
+
+    /// Start servicing timers from periodic timer 2.
+    pub fn timers_init() {
+        pit_configure(
+            &PeriodicTimerSource::Timer2,
+            PITConfig {
+                chained: false,
+                irq_en: true,
+                en: false,
+            },
+        );
+
+        irq_attach(Irq::PeriodicTimer, timers_handle_irq);
+        irq_enable(Irq::PeriodicTimer);
+        timers_arm();
+
+        unsafe {
+            STARTED = true;
+        }
+    }
+
+    /// Load periodic timer 2 with the time until the next timer
+    /// is due. Anything further out than the 32 bit counter can
+    /// reach just wakes up early and arms again.
+    pub fn timers_arm() {
+        pit_configure(
+            &PeriodicTimerSource::Timer2,
+            PITConfig {
+                chained: false,
+                irq_en: true,
+                en: false,
+            },
+        );
+
+        match queue().next_due() {
+            None => {}
+            Some(due) => {
+                let now = nanos();
+                let delay = if due > now { due - now } else { 0 };
+                // Periodic timers count the IPG clock
+                let ticks = crate::math::max(delay * pit_hz() as uNano / S_TO_NANO, 1);
+                let ticks = crate::math::min(ticks, 0xFFFF_FFFF) as u32;
+
+                pit_load_value(&PeriodicTimerSource::Timer2, ticks - 1);
+                pit_restart(&PeriodicTimerSource::Timer2);
+            }
+        }
+    }
+
+    fn timers_handle_irq() {
+        if pit_interrupt_pending(&PeriodicTimerSource::Timer2) {
+            pit_clear_interrupts(&PeriodicTimerSource::Timer2);
+            timers_service();
+            timers_arm();
+        }
+    }
+}
+
+#[cfg(not(feature = "testing"))]
+use hardware::timers_arm;
+#[cfg(not(feature = "testing"))]
+pub use hardware::timers_init;
+
+/// On the host there is no periodic timer, so timers only
+/// fire when `timers_service()` is called.
+#[cfg(feature = "testing")]
+pub fn timers_init() {
+    unsafe {
+        STARTED = true;
+    }
+}
+
+#[cfg(feature = "testing")]
+fn timers_arm() {}
+
+/// Load periodic timer 2 again after the IPG clock changes
+/// speed, so that the next timer still fires on time.
+pub fn timers_recalibrate() {
+    disable_interrupts();
+    timers_arm();
+    enable_interrupts();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg_attr(feature = "testing", thread_local)]
+    static mut FIRED: u32 = 0;
+
+    fn fire() {
+        unsafe { FIRED += 1 };
+    }
+
+    fn fired() -> u32 {
+        return unsafe { FIRED };
+    }
+
+    #[test]
+    fn test_queue_order() {
+        let mut queue = TimerQueue::new();
+        queue.schedule(0, 300, None, timer_noop).unwrap();
+        let first = queue.schedule(0, 100, None, timer_noop).unwrap();
+        queue.schedule(0, 200, None, timer_noop).unwrap();
+
+        assert_eq!(queue.next_due(), Some(100));
+        assert!(queue.pop_due(99).is_none());
+        assert!(queue.pop_due(100).is_some());
+        assert_eq!(queue.is_pending(first), false);
+        assert_eq!(queue.next_due(), Some(200));
+        assert!(queue.pop_due(1000).is_some());
+        assert!(queue.pop_due(1000).is_some());
+        assert!(queue.pop_due(1000).is_none());
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_queue_periodic() {
+        let mut queue = TimerQueue::new();
+        let handle = queue.schedule(0, 100, Some(100), timer_noop).unwrap();
+
+        assert!(queue.pop_due(100).is_some());
+        assert_eq!(queue.next_due(), Some(200));
+
+        // Fell behind by a few periods, only fire once
+        assert!(queue.pop_due(450).is_some());
+        assert_eq!(queue.next_due(), Some(500));
+        assert!(queue.pop_due(450).is_none());
+
+        assert_eq!(queue.cancel(handle), true);
+        assert_eq!(queue.cancel(handle), false);
+        assert_eq!(queue.next_due(), None);
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let mut queue = TimerQueue::new();
+        for _ in 0..MAX_TIMERS {
+            assert!(queue.schedule(0, 100, None, timer_noop).is_some());
+        }
+        assert!(queue.schedule(0, 100, None, timer_noop).is_none());
+    }
+
+    #[test]
+    fn test_after_and_every() {
+        clock_set(0);
+        let once = after(10, fire).unwrap();
+        let repeat = every(4, fire).unwrap();
+
+        clock_set(3 * MS_TO_NANO);
+        timers_service();
+        assert_eq!(fired(), 0);
+
+        clock_set(4 * MS_TO_NANO);
+        timers_service();
+        assert_eq!(fired(), 1);
+
+        clock_set(8 * MS_TO_NANO);
+        timers_service();
+        assert_eq!(fired(), 2);
+
+        clock_set(10 * MS_TO_NANO);
+        timers_service();
+        assert_eq!(fired(), 3);
+        assert_eq!(is_pending(once), false);
+        assert_eq!(timers_next_due(), Some(12 * MS_TO_NANO));
+
+        assert_eq!(cancel(repeat), true);
+        clock_set(100 * MS_TO_NANO);
+        timers_service();
+        assert_eq!(fired(), 3);
+        assert_eq!(timers_next_due(), None);
+    }
+}
@@ -1,30 +1,37 @@
 use crate::{
-    arm_dcache_delete, mem,
+    arm_dcache_delete,
+    executor::IrqWaker,
+    mem,
     phys::{addrs::USB, usb::models::*, usb::registers::*},
     phys::{assign, read_word, usb::descriptors::*, usb::*},
     system::{
         buffer::*,
+        irq_mutex::*,
         vector::{Queue, Stack},
     },
 };
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
 
 // How many pages of data we support
 const RX_BUFFER_SIZE: usize = 512;
 const TX_BUFFER_SIZE: usize = 512;
 
-static mut BUFFER: Buffer<512, u8> = Buffer::new(0);
+static BUFFER: IrqMutex<Buffer<RX_BUFFER_SIZE, u8>> = IrqMutex::new(Buffer::new(0));
 
 #[link_section = ".descriptors"]
 static mut TX_DTD: UsbEndpointTransferDescriptor = UsbEndpointTransferDescriptor::new();
 #[link_section = ".descriptors"]
 static mut RX_DTD: UsbEndpointTransferDescriptor = UsbEndpointTransferDescriptor::new();
-static mut TX_BUFFER_TRANSIENT: Buffer<TX_BUFFER_SIZE, u8> = Buffer::new(0);
+static TX_BUFFER_TRANSIENT: IrqMutex<Buffer<TX_BUFFER_SIZE, u8>> = IrqMutex::new(Buffer::new(0));
 #[link_section = ".dmabuffers"]
 static mut RX_BUFFER: BufferPage = BufferPage::new();
 
 #[link_section = ".dmabuffers"]
 static mut TX_BUFFER: BufferPage = BufferPage::new();
-static mut CONFIGURED: bool = false;
+static CONFIGURED: IrqCell<bool> = IrqCell::new(false);
+static RX_WAKER: IrqWaker = IrqWaker::new();
 
 const CDC_STATUS_INTERFACE: u8 = 0;
 const CDC_DATA_INTERFACE: u8 = 1;
@@ -114,9 +121,7 @@ fn usb_serial_configure(packet: SetupPacket) {
             assign(USB + 0x84, (1 << 31) | (1 << 30));
 
             // Open the flood gates.
-            unsafe {
-                CONFIGURED = true;
-            }
+            CONFIGURED.set(true);
         }
         _ => {
             // Do nothing
@@ -135,7 +140,7 @@ fn rx_queue_transfer() {
 }
 
 fn rx_callback(packet: &UsbEndpointTransferDescriptor) {
-    if unsafe { CONFIGURED } == false {
+    if CONFIGURED.get() == false {
         return;
     }
 
@@ -143,10 +148,14 @@ fn rx_callback(packet: &UsbEndpointTransferDescriptor) {
     let len = (RX_BUFFER_SIZE as u32) - (packet.status >> 16) & 0x7FFF;
 
     // // Read the bytes
-    for index in 0..len {
-        unsafe {
-            BUFFER.enqueue(RX_BUFFER.bytes[index as usize]);
+    BUFFER.lock(|buffer| {
+        for index in 0..len {
+            buffer.enqueue(unsafe { RX_BUFFER.bytes[index as usize] });
         }
+    });
+
+    if len > 0 {
+        RX_WAKER.wake();
     }
 
     // If the queueheads have been reset, let's
@@ -165,27 +174,68 @@ fn rx_callback(packet: &UsbEndpointTransferDescriptor) {
 /// Returns how many bytes are available to read from
 /// the buffer.
 pub fn usb_serial_available() -> usize {
-    return unsafe { BUFFER.size() };
+    return BUFFER.lock(|buffer| buffer.size());
 }
 
 /// Returns the next available byte in the buffer
 /// and consumes it. If there are no bytes available,
 /// this method will return None.
 pub fn usb_serial_read() -> Option<u8> {
-    return unsafe { BUFFER.dequeue() };
+    return BUFFER.lock(|buffer| buffer.dequeue());
+}
+
+/// The same as `usb_serial_read` but waits for a
+/// byte to arrive if there isn't one already.
+///
+/// ```no_run
+/// use teensycore::executor::*;
+/// use teensycore::usb_serial::*;
+///
+/// spawn(async {
+///     let byte = usb_serial_read_async().await;
+///     usb_serial_putchar(byte);
+/// });
+/// ```
+pub fn usb_serial_read_async() -> UsbSerialRead {
+    return UsbSerialRead {};
+}
+
+/// A future which waits for a byte from the USB host.
+pub struct UsbSerialRead {}
+
+impl Future for UsbSerialRead {
+    type Output = u8;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u8> {
+        match usb_serial_read() {
+            Some(byte) => {
+                return Poll::Ready(byte);
+            }
+            None => {}
+        }
+
+        RX_WAKER.register(cx.waker());
+        RX_WAKER.take();
+
+        // A byte may have arrived while registering
+        return match usb_serial_read() {
+            Some(byte) => Poll::Ready(byte),
+            None => Poll::Pending,
+        };
+    }
 }
 
 /// Return's the next available byte in the buffer
 /// without consuming it. If there are no bytes available,
 /// this method will return None.
 pub fn usb_serial_peek() -> Option<u8> {
-    unsafe {
-        if BUFFER.size() > 0 {
-            return Some(BUFFER.data[0]);
+    return BUFFER.lock(|buffer| {
+        if buffer.size() > 0 {
+            return Some(buffer.data[0]);
         } else {
             return None;
         }
-    }
+    });
 }
 fn tx_callback(packet: &UsbEndpointTransferDescriptor) {
     if (packet.status & 0x80) != 0 {
@@ -210,11 +260,11 @@ pub fn usb_serial_putchar(byte: u8) {
 /// usb_serial_write(b"Hello, world!");
 /// ```
 pub fn usb_serial_write(bytes: &[u8]) {
-    unsafe {
+    TX_BUFFER_TRANSIENT.lock(|buffer| {
         for byte in bytes {
-            TX_BUFFER_TRANSIENT.push(*byte);
+            buffer.push(*byte);
         }
-    }
+    });
 
     usb_timer_oneshot();
 }
@@ -229,12 +279,16 @@ pub fn usb_serial_write(bytes: &[u8]) {
 /// Returns how many bytes were written.
 pub fn usb_serial_flush() -> u32 {
     // Verify we are in a good, configured state.
-    if unsafe { CONFIGURED == false } {
+    if CONFIGURED.get() == false {
         return 0;
     }
 
     // Prepare
-    if unsafe { TX_BUFFER_TRANSIENT.size() } > 0 {
+    return TX_BUFFER_TRANSIENT.lock(|buffer| {
+        if buffer.size() == 0 {
+            return 0;
+        }
+
         let dtd = unsafe { &mut TX_DTD };
 
         if (dtd.status & 0x80) > 0 {
@@ -256,23 +310,19 @@ pub fn usb_serial_flush() -> u32 {
         }
 
         // Copy the data.
-        let len = unsafe { TX_BUFFER_TRANSIENT.size() } as u32;
-        let src_ptr = unsafe { TX_BUFFER_TRANSIENT.data.as_ptr() } as u32;
+        let len = buffer.size() as u32;
+        let src_ptr = buffer.data.as_ptr() as u32;
         let dst_ptr = unsafe { TX_BUFFER.as_ptr() } as u32;
 
         mem::copy(src_ptr, dst_ptr, len);
 
-        unsafe {
-            // Clear buffer
-            TX_BUFFER_TRANSIENT.clear();
-        }
+        // Clear buffer
+        buffer.clear();
 
         usb_prepare_transfer(dtd, dst_ptr, len, true);
         usb_transmit(CDC_TX_ENDPOINT as usize, dtd);
         return len;
-    }
-
-    return 0;
+    });
 }
 
 fn setup_cdc_descriptors() {